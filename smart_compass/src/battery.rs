@@ -4,7 +4,7 @@
 use crate::timers::{ElapsedMs, EveryNMillis};
 use embedded_hal::digital::v2::InputPin;
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum BatteryStatus {
     Low,
     Ok,
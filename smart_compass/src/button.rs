@@ -0,0 +1,103 @@
+//! Buttons for things that shouldn't happen by accident.
+//! A short press does something small. Two quick presses do something a little bigger. Holding the button down for a
+//! while does something big. A bump in a pocket won't be held long enough for that
+use crate::timers::ElapsedMs;
+use embedded_hal::digital::v2::InputPin;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Press {
+    /// let go before `hold_ms` and not pressed again within `double_ms`
+    Short,
+    /// two short presses with less than `double_ms` between letting go and pressing again
+    Double,
+    /// held for `hold_ms`. letting go after this doesn't count as another press
+    Long,
+}
+
+/// TODO: debounce? a bounce looks like a very short press
+pub struct Button<ButtonPin> {
+    pin: ButtonPin,
+    hold_ms: u32,
+    double_ms: u32,
+    /// ElapsedMs::now when the button went down. None while it's up
+    pressed_at: Option<u32>,
+    /// true once this press has been counted as long. the button has to be let go before it counts again
+    fired: bool,
+    /// ElapsedMs::now when a short press was let go. it isn't a Short until we know it isn't the start of a Double
+    released_at: Option<u32>,
+    /// true if the button is down for the second half of a Double
+    second: bool,
+}
+
+impl<ButtonPin: InputPin> Button<ButtonPin> {
+    /// The pin is high while the button is pressed
+    pub fn new(pin: ButtonPin, hold_ms: u32, double_ms: u32) -> Self {
+        Self {
+            pin,
+            hold_ms,
+            double_ms,
+            pressed_at: None,
+            fired: false,
+            released_at: None,
+            second: false,
+        }
+    }
+
+    /// Long presses show up as soon as the button has been held for `hold_ms`. Short presses show up `double_ms` after
+    /// the button is let go. Double presses show up when the button is let go the second time. Call this often
+    pub fn check(&mut self, elapsed_ms: &ElapsedMs) -> Option<Press> {
+        let now = elapsed_ms.now();
+
+        // TODO: what should we do if the pin errors?
+        if !self.pin.is_high().unwrap_or(false) {
+            let pressed_at = self.pressed_at.take();
+            let fired = self.fired;
+            let second = self.second;
+
+            self.fired = false;
+            self.second = false;
+
+            if pressed_at.is_some() && !fired {
+                if second {
+                    return Some(Press::Double);
+                }
+
+                self.released_at = Some(now);
+                return None;
+            }
+
+            return match self.released_at {
+                Some(released_at) if now.wrapping_sub(released_at) >= self.double_ms => {
+                    self.released_at = None;
+                    Some(Press::Short)
+                }
+                _ => None,
+            };
+        }
+
+        if self.pressed_at.is_none() {
+            self.pressed_at = Some(now);
+
+            if let Some(released_at) = self.released_at.take() {
+                if now.wrapping_sub(released_at) >= self.double_ms {
+                    // we weren't called in time to see the gap. this is a new press
+                    return Some(Press::Short);
+                }
+
+                self.second = true;
+            }
+        }
+
+        let pressed_at = self.pressed_at.unwrap_or(now);
+
+        if self.fired || now.wrapping_sub(pressed_at) < self.hold_ms {
+            return None;
+        }
+
+        // a quick tap right before holding is part of the hold
+        self.fired = true;
+        self.second = false;
+
+        Some(Press::Long)
+    }
+}
@@ -1 +1,241 @@
+//! Everything a compass needs to know about its group. Everyone in a group gets (almost) the same file on their SD card.
+//!
+//! The group file is "key = value" lines. Blank lines and lines starting with '#' are skipped:
+//!
+//! ```text
+//! # our group for the weekend
+//! network_secret = 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f
+//! num_peers = 5
+//! hue = 160
+//! saturation = 255
+//! # leave this out to join with a free id. everyone else's file can be exactly the same
+//! peer_id = 2
+//! # anything that `RadioSettings::set` understands
+//! region = US915
+//! spreading_factor = 8
+//! ```
+use crate::network::{NetworkSecret, RadioSettings, SettingsError, NETWORK_SECRET_LEN};
+use crate::MAX_PEERS;
 
+/// The group file in the root of the SD card
+pub const GROUP_FILE: &str = "GROUP.TXT";
+
+/// Group files longer than this are rejected instead of cut off. That leaves plenty of room for comments
+pub const MAX_GROUP_FILE_LEN: usize = 1024;
+
+/// How many peers a group has if the group file doesn't say
+pub const DEFAULT_NUM_PEERS: usize = 5;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// a line that isn't "key = value" or a key that we don't know
+    Parse,
+    /// the secret is missing or isn't 64 hex characters. all zeros counts as missing
+    NetworkSecret,
+    /// 1 to MAX_PEERS
+    NumPeers,
+    /// has to be less than num_peers
+    PeerId,
+    /// a bad (or illegal) radio setting
+    Radio(SettingsError),
+    /// the file is longer than MAX_GROUP_FILE_LEN
+    TooLong,
+}
+
+impl From<SettingsError> for ConfigError {
+    fn from(err: SettingsError) -> Self {
+        match err {
+            // the radio didn't know the key either
+            SettingsError::Parse => ConfigError::Parse,
+            err => ConfigError::Radio(err),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DeviceConfig {
+    pub network_secret: NetworkSecret,
+    pub num_peers: usize,
+    /// None means we haven't joined the group yet (or the file left it out so everyone can share it)
+    pub peer_id: Option<usize>,
+    pub hue: u8,
+    pub saturation: u8,
+    pub radio_settings: RadioSettings,
+}
+
+impl DeviceConfig {
+    /// Read a group file. Anything that isn't in it keeps its default. Except the secret. That has to be there
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+
+        for line in text.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+
+            let key = parts.next().ok_or(ConfigError::Parse)?.trim();
+            let value = parts.next().ok_or(ConfigError::Parse)?.trim();
+
+            match key {
+                "network_secret" => {
+                    config.network_secret = parse_secret(value).ok_or(ConfigError::NetworkSecret)?
+                }
+                "num_peers" => config.num_peers = parse(value)?,
+                "peer_id" => config.peer_id = Some(parse(value)?),
+                "hue" => config.hue = parse(value)?,
+                "saturation" => config.saturation = parse(value)?,
+                // everything else is for the radio
+                _ => config.radio_settings.set(key, value)?,
+            }
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        // a group without a secret would be anyone's group
+        if self.network_secret == [0; NETWORK_SECRET_LEN] {
+            return Err(ConfigError::NetworkSecret);
+        }
+
+        if self.num_peers == 0 || self.num_peers > MAX_PEERS {
+            return Err(ConfigError::NumPeers);
+        }
+
+        if let Some(peer_id) = self.peer_id {
+            if peer_id >= self.num_peers {
+                return Err(ConfigError::PeerId);
+            }
+        }
+
+        self.radio_settings.validate()?;
+
+        Ok(())
+    }
+}
+
+/// Everything that a group file can leave out. There is no secret, so this doesn't pass `validate` on its own. Don't
+/// turn on the radio with it
+/// TODO: pick a random hue?
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            network_secret: [0; NETWORK_SECRET_LEN],
+            num_peers: DEFAULT_NUM_PEERS,
+            peer_id: None,
+            hue: 0,
+            saturation: 0,
+            radio_settings: RadioSettings::default(),
+        }
+    }
+}
+
+fn parse<T: core::str::FromStr>(value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::Parse)
+}
+
+/// 64 hex characters. None if it is the wrong length or has anything else in it
+fn parse_secret(value: &str) -> Option<NetworkSecret> {
+    let value = value.as_bytes();
+
+    if value.len() != NETWORK_SECRET_LEN * 2 {
+        return None;
+    }
+
+    let mut secret = [0; NETWORK_SECRET_LEN];
+
+    for (b, hex) in secret.iter_mut().zip(value.chunks(2)) {
+        let hex = core::str::from_utf8(hex).ok()?;
+
+        *b = u8::from_str_radix(hex, 16).ok()?;
+    }
+
+    Some(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::Region;
+
+    #[test]
+    fn test_parse() {
+        let text = "# a group\nnetwork_secret = 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f\n\nnum_peers = 8\npeer_id=3\nhue = 160\nsaturation = 255\nregion = EU868\nspreading_factor = 9\n";
+
+        let config = DeviceConfig::parse(text).unwrap();
+
+        assert_eq!(config.network_secret[0], 0x00);
+        assert_eq!(config.network_secret[31], 0x1f);
+        assert_eq!(config.num_peers, 8);
+        assert_eq!(config.peer_id, Some(3));
+        assert_eq!(config.hue, 160);
+        assert_eq!(config.saturation, 255);
+        assert_eq!(config.radio_settings.region, Region::Eu868);
+        assert_eq!(config.radio_settings.spreading_factor, 9);
+
+        // everything but the secret is optional
+        let config = DeviceConfig::parse(
+            "network_secret = 000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        )
+        .unwrap();
+
+        assert_eq!(config.num_peers, DEFAULT_NUM_PEERS);
+        assert_eq!(config.peer_id, None);
+        assert_eq!(config.radio_settings, RadioSettings::default());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            DeviceConfig::default().validate(),
+            Err(ConfigError::NetworkSecret)
+        );
+
+        assert_eq!(
+            DeviceConfig::parse("hue = 1"),
+            Err(ConfigError::NetworkSecret)
+        );
+        assert_eq!(
+            DeviceConfig::parse("network_secret = 0001"),
+            Err(ConfigError::NetworkSecret)
+        );
+        assert_eq!(DeviceConfig::parse("hue = 300"), Err(ConfigError::Parse));
+        assert_eq!(DeviceConfig::parse("volume = 11"), Err(ConfigError::Parse));
+
+        let valid = DeviceConfig {
+            network_secret: [1; NETWORK_SECRET_LEN],
+            ..DeviceConfig::default()
+        };
+        assert_eq!(valid.validate(), Ok(()));
+
+        let config = DeviceConfig {
+            peer_id: Some(DEFAULT_NUM_PEERS),
+            ..valid
+        };
+        assert_eq!(config.validate(), Err(ConfigError::PeerId));
+
+        let config = DeviceConfig {
+            num_peers: MAX_PEERS + 1,
+            ..valid
+        };
+        assert_eq!(config.validate(), Err(ConfigError::NumPeers));
+
+        let config = DeviceConfig {
+            radio_settings: RadioSettings {
+                sync_word: 0x34,
+                ..RadioSettings::default()
+            },
+            ..valid
+        };
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::Radio(SettingsError::SyncWord))
+        );
+    }
+}
@@ -4,8 +4,9 @@ extern crate alloc;
 
 pub mod arduino;
 pub mod battery;
+pub mod button;
 // pub mod compass;
-// pub mod config;
+pub mod config;
 pub mod lights;
 pub mod location;
 pub mod network;
@@ -15,6 +16,10 @@ pub mod timers;
 pub use accelerometer;
 pub use time;
 
-// TODO: i'd like this to be configurable at runtime,
-pub const MAX_PEERS: usize = 5;
+/// The most peers that any group can have. Each group picks its actual size at boot (`NetworkData::num_peers`).
+/// This only sets how much memory is reserved for the peer tables.
+/// Don't raise this without looking at the schedule. A cycle is `num_peers * num_peers` time segments, so a full group
+/// of 16 only talks about itself once every 512 seconds. Relays fill in the gaps (see `network::MAX_RELAY_AGE_S`)
+pub const MAX_PEERS: usize = 16;
+pub const MAX_PINS: usize = 8;
 pub const NUM_LEDS: usize = 256;
@@ -2,7 +2,7 @@ mod focalintent;
 mod patterns;
 
 use self::patterns::Pattern;
-use crate::location::GpsData;
+use crate::location::{time_of_day, GpsData};
 use crate::network::NetworkData;
 use crate::timers::{ElapsedMs, EveryNMillis};
 use crate::NUM_LEDS;
@@ -10,6 +10,20 @@ use accelerometer::Orientation;
 use embedded_hal::digital::v2::OutputPin;
 use smart_leds::{brightness, gamma, SmartLedsWrite, RGB8};
 
+/// How long each blink of `draw_error` is on (and then off) for
+pub const ERROR_BLINK_MS: u32 = 300;
+
+/// Something is wrong and the compass can't work without it. Each one blinks a different number of times
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorPattern {
+    /// the radio stopped answering and resetting it didn't help
+    Radio = 2,
+    /// the group file is missing or has bad settings. we are in the default group
+    Config = 3,
+    /// no SD card (or it isn't answering). we are in the default group and can't remember our peer id
+    SdCard = 4,
+}
+
 /// TODO: better trait bounds?
 pub struct Lights<SmartLeds: SmartLedsWrite> {
     pub brightness: u8,
@@ -30,6 +44,7 @@ pub struct Lights<SmartLeds: SmartLedsWrite> {
     pattern_test_map: patterns::TestMap,
     pattern_waves: patterns::Waves,
     pattern_compass: patterns::Compass,
+    pattern_sos: patterns::Sos,
 }
 
 impl<SmartLeds: SmartLedsWrite> Lights<SmartLeds>
@@ -51,11 +66,12 @@ where
 
         let last_orientation = Orientation::Unknown;
 
-        let pattern_compass = patterns::Compass::new(3, 3000.0, 400);
+        let pattern_compass = patterns::Compass::new(3, 3000.0, 400, 500);
         let pattern_clock = patterns::Clock::new(240);
         let pattern_lines = patterns::Lines::new(100);
         let pattern_pacman = patterns::PacMan::new();
         let pattern_pride = patterns::Pride::new();
+        let pattern_sos = patterns::Sos::new(3000.0, 250);
         let pattern_sunflower = patterns::Sunflower::new();
         let pattern_test_map = patterns::TestMap::new();
         let pattern_waves = patterns::Waves::new();
@@ -71,6 +87,7 @@ where
             pattern_lines,
             pattern_pacman,
             pattern_pride,
+            pattern_sos,
             pattern_sunflower,
             pattern_test_map,
             pattern_waves,
@@ -96,6 +113,23 @@ where
         // TODO: match or something to pick between a bunch of different patterns
         let orientation_changed = self.last_orientation == *orientation;
 
+        // someone needs help. that matters more than whichever way we are holding the compass
+        if let Some(network_data) = network_data {
+            let now = elapsed_ms.now();
+
+            // without the time we can't tell if it's too old. we can't receive one without the time anyway
+            let sos = network_data
+                .clock
+                .now(now)
+                .and_then(|(epoch_seconds, _)| network_data.active_sos(epoch_seconds));
+
+            if let Some(sos) = sos {
+                return self
+                    .pattern_sos
+                    .buffer(now, &mut self.led_buffer, network_data, sos);
+            }
+        }
+
         // TODO: have a Pattern state machine that handles orientation and transitionary animations
         let result = match orientation {
             Orientation::FaceDown => {
@@ -107,7 +141,8 @@ where
 
                 let network_data = network_data?;
 
-                if network_data.my_peer_id == 0 {
+                if network_data.my_peer_id.is_none() {
+                    // still joining
                     return None;
                 }
 
@@ -120,10 +155,14 @@ where
             }
             Orientation::PortraitDown => {
                 // clock
-                let gps_time = &gps_data?.time?;
+                // the network clock has milliseconds (from the PPS pin or peers). the GPS sentences only have seconds
+                let time = match network_data.and_then(|x| x.clock.now(elapsed_ms.now())) {
+                    Some((epoch_seconds, ms)) => time_of_day(epoch_seconds, ms)?,
+                    None => gps_data?.time?,
+                };
 
                 self.pattern_clock
-                    .buffer(elapsed_ms, &mut self.led_buffer, gps_time)
+                    .buffer(elapsed_ms, &mut self.led_buffer, &time)
             }
             Orientation::LandscapeUp | Orientation::LandscapeDown | Orientation::PortraitUp | Orientation::Unknown => {
                 // render pretty lights
@@ -253,6 +292,31 @@ where
         self._draw(elapsed_ms);
     }
 
+    /// Blink red a few times and then pause. Count the blinks to see what is wrong
+    pub fn draw_error(&mut self, elapsed_ms: &ElapsedMs, error: ErrorPattern) {
+        let now = match self.framerate.ready(elapsed_ms) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        let blinks = error as u32;
+
+        // on, off, on, off, ... and then a pause as long as 2 blinks
+        let step = (now / ERROR_BLINK_MS) % (blinks * 2 + 4);
+
+        let color = if step < blinks * 2 && step % 2 == 0 {
+            RGB8::new(0xFF, 0, 0)
+        } else {
+            RGB8::default()
+        };
+
+        for led in self.led_buffer.iter_mut() {
+            *led = color;
+        }
+
+        self._draw(elapsed_ms);
+    }
+
     pub fn draw(
         &mut self,
         elapsed_ms: &ElapsedMs,
@@ -260,12 +324,24 @@ where
         network: Option<&NetworkData>,
         orientation: &Orientation,
     ) -> Option<(u32, u32, u32)> {
+        let start = self.buffer(elapsed_ms, gps, network, orientation)?;
+
+        Some(self.draw_buffer(elapsed_ms, start))
+    }
+
+    /// Fill the light buffer without displaying it. Returns when the frame started. None if it isn't time for the next
+    /// frame yet. Displaying is slow, so this lets the caller give back the network data before `draw_buffer`
+    pub fn buffer(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+        gps: Option<&GpsData>,
+        network: Option<&NetworkData>,
+        orientation: &Orientation,
+    ) -> Option<u32> {
         let start = self.framerate.ready(elapsed_ms).ok()?;
 
         // TODO: warn if framerate is too fast for us to keep up. will need to keep track of the last time we drew
 
-        // fill the light buffer
-        // TODO: make it possible to call buffer seperate from draw
         if self
             ._try_buffer(elapsed_ms, orientation, gps, network)
             .is_none()
@@ -273,7 +349,11 @@ where
             self._buffer_loading(elapsed_ms)
         }
 
-        // display
+        Some(start)
+    }
+
+    /// Display what `buffer` filled in. `start` is what `buffer` returned
+    pub fn draw_buffer(&mut self, elapsed_ms: &ElapsedMs, start: u32) -> (u32, u32, u32) {
         // TODO! some drivers disable interrupts while they draw! this means we won't have an accurate ELAPSED_MS!
         let draw_time = self._draw(elapsed_ms);
 
@@ -281,6 +361,6 @@ where
 
         // TODO: calculate actual framerate
 
-        Some((start, draw_time, total_time))
+        (start, draw_time, total_time)
     }
 }
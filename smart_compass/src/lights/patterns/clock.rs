@@ -30,7 +30,8 @@ impl Clock {
         leds: &mut [RGB8],
         time: &time::Time,
     ) -> Option<()> {
-        let second = time.second() as f32;
+        // milliseconds keep the second hand moving smoothly
+        let second = time.second() as f32 + (time.millisecond() as f32 / 1000.0);
 
         // float minute = timeClient.getMinutes() + (second / 60.0);
         let minute = (time.minute() as f32) + (second / 60.0);
@@ -1,7 +1,7 @@
 use super::{ANGLES, PHYSICAL_TO_FIBONACCI, RGB8};
 use crate::arduino::*;
 use crate::lights::focalintent::fade_to_black_by;
-use crate::network::{NetworkData, PeerLocation};
+use crate::network::{Coordinates, NetworkData, PeerAge, Status};
 use crate::NUM_LEDS;
 use derive_more::Constructor;
 use heapless::consts::*;
@@ -9,12 +9,29 @@ use heapless::FnvIndexMap;
 use micromath::F32Ext;
 use smart_leds::hsv::{hsv2rgb, Hsv};
 
+/// peers that we haven't heard directly in a while (or only hear through relays) are drawn this bright
+const DIM_VAL: u8 = 64;
+
+/// peers with weak links flicker this fast
+const FLICKER_MS: u32 = 150;
+
+/// peers with stale positions slowly pulse between dim and full brightness
+const PULSE_MS: u32 = 2_000;
+
+/// peers that we lost contact with are barely there
+const FAINT_VAL: u8 = 16;
+
+/// status glyphs go just past their peer. this is how much farther as a fraction of `max_distance`
+const STATUS_OFFSET: f32 = 1.0 / 16.0;
+
 #[derive(Constructor)]
 pub struct Compass {
     pub background_fade: u8,
     pub max_distance: f32,
     /// if two peers are next to eachother, we cycle between their colors
     pub ms_per_color: u32,
+    /// pins blink so that they don't get confused with peers
+    pub ms_per_blink: u32,
 }
 
 impl Compass {
@@ -24,11 +41,17 @@ impl Compass {
         leds: &mut [RGB8],
         network_data: &NetworkData,
     ) -> Option<()> {
-        let my_peer_id = &network_data.my_peer_id;
+        let my_peer_id = network_data.my_peer_id?;
 
         fade_to_black_by(leds, self.background_fade);
 
-        if let Some((my_location, _)) = network_data.peer_locations[*my_peer_id].as_ref() {
+        // without the time, we can't tell how old anything is
+        let now_epoch_seconds = network_data
+            .clock
+            .now(now)
+            .map(|(epoch_seconds, _)| epoch_seconds);
+
+        if let Some((my_location, _)) = network_data.peer_locations[my_peer_id].as_ref() {
             // store locations in a hashmap of vecs because multiple items might be on the same led
             // TODO: use MAX_PEERS for the size of this map
             let mut locations = FnvIndexMap::<_, _, U16>::new();
@@ -40,14 +63,11 @@ impl Compass {
 
             for peer_location in network_data.peer_locations.iter() {
                 if let Some((peer_location, _)) = peer_location {
-                    if peer_location.peer_id == *my_peer_id {
+                    if peer_location.peer_id == my_peer_id {
                         // we already drew ourselves at the center
                         continue;
                     }
 
-                    // TODO: check last_updated_at to make sure it isn't too old
-                    // if peer_location.last_updated_at;
-
                     let bearing = get_bearing(my_location, peer_location);
 
                     let distance = get_haversine_distance(my_location, peer_location);
@@ -75,30 +95,189 @@ impl Compass {
                     peer_ids[color_id]
                 };
 
+                let age = match now_epoch_seconds {
+                    Some(now_epoch_seconds) => network_data
+                        .peer_age_limits
+                        .age(drawn_peer_id.last_updated_at, now_epoch_seconds),
+                    None => PeerAge::Stale,
+                };
+
+                let val = if drawn_peer_id.peer_id == my_peer_id {
+                    255
+                } else {
+                    match age {
+                        PeerAge::Fresh => match network_data.links[drawn_peer_id.peer_id] {
+                            Some(link) if link.is_stale(now) => DIM_VAL,
+                            Some(link) if link.is_weak() => {
+                                // we might lose them soon
+                                if (now / FLICKER_MS) % 3 == 0 {
+                                    DIM_VAL
+                                } else {
+                                    255
+                                }
+                            }
+                            Some(_) => 255,
+                            None => DIM_VAL,
+                        },
+                        // they have probably moved since then
+                        PeerAge::Stale => pulse(now),
+                        // don't walk toward a position that is this old
+                        PeerAge::Lost => FAINT_VAL,
+                    }
+                };
+
                 // TODO: save the color in the peer_location instead of doing this conversion every time
                 let color = hsv2rgb(Hsv {
                     hue: drawn_peer_id.hue,
                     sat: drawn_peer_id.sat,
-                    val: 255,
+                    val,
                 });
 
                 // TODO: nblend
                 leds[*led_id] = color;
             }
+
+            for (peer_location, _) in network_data.peer_locations.iter().flatten() {
+                let (hue, blinks) = match network_data
+                    .peer_status(peer_location.peer_id)
+                    .and_then(status_glyph)
+                {
+                    Some(x) => x,
+                    None => continue,
+                };
+
+                if blinks && (now / self.ms_per_blink) % 2 == 1 {
+                    continue;
+                }
+
+                // we are in the middle. our glyph goes just above us
+                let (bearing, distance) = if peer_location.peer_id == my_peer_id {
+                    (0.0, 0.0)
+                } else {
+                    (
+                        get_bearing(my_location, peer_location),
+                        get_haversine_distance(my_location, peer_location),
+                    )
+                };
+
+                let i = bearing_and_distance_to_id(
+                    bearing,
+                    distance + self.max_distance * STATUS_OFFSET,
+                    self.max_distance,
+                );
+
+                leds[i] = hsv2rgb(Hsv {
+                    hue,
+                    sat: 255,
+                    val: 255,
+                });
+            }
+
+            if (now / self.ms_per_blink) % 2 == 0 {
+                for (pin, _) in network_data.pin_locations.iter().flatten() {
+                    let bearing = get_bearing(my_location, pin);
+
+                    let distance = get_haversine_distance(my_location, pin);
+
+                    let i = bearing_and_distance_to_id(bearing, distance, self.max_distance);
+
+                    // pins are drawn on top of peers
+                    leds[i] = hsv2rgb(Hsv {
+                        hue: pin.hue,
+                        sat: pin.sat,
+                        val: 255,
+                    });
+                }
+            }
+        }
+
+        if (now / self.ms_per_blink) % 2 == 1 {
+            // peers that are on and in range, but that we can't point to. each one gets its own spot on the edge.
+            // they blink opposite the pins so that nobody walks toward them
+            // TODO: their color is in their location. beacons from peers that never had a fix are always red
+            for (peer_id, beacon) in network_data.beacons.iter().enumerate() {
+                if beacon.is_none() || peer_id == my_peer_id {
+                    continue;
+                }
+
+                match network_data.links[peer_id] {
+                    Some(link) if !link.is_stale(now) => {}
+                    _ => continue,
+                }
+
+                let location = network_data.peer_locations[peer_id].as_ref();
+
+                let can_point = match (location, now_epoch_seconds) {
+                    (Some((location, _)), Some(now_epoch_seconds)) => {
+                        network_data
+                            .peer_age_limits
+                            .age(location.last_updated_at, now_epoch_seconds)
+                            != PeerAge::Lost
+                    }
+                    _ => false,
+                };
+
+                if can_point {
+                    continue;
+                }
+
+                let (hue, sat) =
+                    location.map_or((0, 255), |(location, _)| (location.hue, location.sat));
+
+                let bearing = peer_id as f32 * 360.0 / network_data.num_peers as f32;
+
+                let i = bearing_and_distance_to_id(bearing, self.max_distance, self.max_distance);
+
+                leds[i] = hsv2rgb(Hsv {
+                    hue,
+                    sat,
+                    val: DIM_VAL,
+                });
+            }
         }
 
         Some(())
     }
 }
 
-fn get_bearing(my_location: &PeerLocation, other_location: &PeerLocation) -> f32 {
-    let d_lon = other_location.lon - my_location.lon;
+/// What each status looks like next to its peer. The hue and whether it blinks. None for nothing
+fn status_glyph(status: Status) -> Option<(u8, bool)> {
+    match status {
+        Status::Clear => None,
+        // green means go
+        Status::HeadingToCamp => Some((96, false)),
+        // blue like water. blinking because it's urgent
+        Status::NeedWater => Some((160, true)),
+        Status::AtStage => Some((208, false)),
+        Status::Hungry => Some((32, true)),
+        Status::Resting => Some((64, false)),
+        // from newer firmware. at least show that they said something
+        Status::Other(_) => Some((0, false)),
+    }
+}
+
+/// Up from dim to full brightness and back down again every `PULSE_MS`
+fn pulse(now: u32) -> u8 {
+    let half = PULSE_MS / 2;
+    let phase = now % PULSE_MS;
+
+    let up = if phase < half {
+        phase
+    } else {
+        PULSE_MS - phase
+    };
+
+    (DIM_VAL as u32 + (255 - DIM_VAL as u32) * up / half) as u8
+}
+
+pub fn get_bearing<A: Coordinates, B: Coordinates>(my_location: &A, other_location: &B) -> f32 {
+    let d_lon = other_location.lon() - my_location.lon();
 
     // y = math.sin(dLon) * math.cos(lat2)
-    let y = d_lon.sin() * other_location.lat;
+    let y = d_lon.sin() * other_location.lat();
     // x = math.cos(lat1) * math.sin(lat2) - math.sin(lat1) * math.cos(lat2) * math.cos(dLon)
-    let x = my_location.lat.cos() * other_location.lat.sin()
-        - my_location.lat.sin() * other_location.lat.cos() * d_lon.cos();
+    let x = my_location.lat().cos() * other_location.lat().sin()
+        - my_location.lat().sin() * other_location.lat().cos() * d_lon.cos();
 
     // brng = math.atan2(y, x)
     let bearing = y.atan2(x);
@@ -115,14 +294,17 @@ fn get_bearing(my_location: &PeerLocation, other_location: &PeerLocation) -> f32
     bearing
 }
 
-fn get_haversine_distance(my_location: &PeerLocation, other_location: &PeerLocation) -> f32 {
+pub fn get_haversine_distance<A: Coordinates, B: Coordinates>(
+    my_location: &A,
+    other_location: &B,
+) -> f32 {
     // kilometer radius of Earth
     const R: f32 = 6371.0;
 
-    let d_lat: f32 = (other_location.lat - my_location.lat).to_radians();
-    let d_lon: f32 = (other_location.lon - my_location.lon).to_radians();
-    let lat1: f32 = (my_location.lat).to_radians();
-    let lat2: f32 = (other_location.lat).to_radians();
+    let d_lat: f32 = (other_location.lat() - my_location.lat()).to_radians();
+    let d_lon: f32 = (other_location.lon() - my_location.lon()).to_radians();
+    let lat1: f32 = (my_location.lat()).to_radians();
+    let lat2: f32 = (other_location.lat()).to_radians();
 
     let a: f32 = ((d_lat / 2.0).sin()) * ((d_lat / 2.0).sin())
         + ((d_lon / 2.0).sin()) * ((d_lon / 2.0).sin()) * (lat1.cos()) * (lat2.cos());
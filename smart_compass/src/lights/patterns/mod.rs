@@ -3,6 +3,7 @@ mod compass;
 mod lines;
 mod pacman;
 mod pride;
+mod sos;
 mod sunflower;
 mod tests;
 mod waves;
@@ -12,6 +13,7 @@ pub use self::compass::Compass;
 pub use self::lines::Lines;
 pub use self::pacman::PacMan;
 pub use self::pride::Pride;
+pub use self::sos::Sos;
 pub use self::sunflower::Sunflower;
 pub use self::tests::TestMap;
 pub use self::waves::Waves;
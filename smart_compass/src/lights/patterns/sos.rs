@@ -0,0 +1,74 @@
+use super::compass::{bearing_and_distance_to_id, get_bearing, get_haversine_distance};
+use super::RGB8;
+use crate::network::{self, NetworkData};
+use derive_more::Constructor;
+use smart_leds::colors;
+
+/// how many leds to light between the center and the peer that needs help
+const LINE_STEPS: usize = 16;
+
+/// Someone needs help. Everything flashes red and a white line points at them. The longer the line, the farther away
+/// they are. If we don't know where we are (or they never had a fix), it just flashes
+#[derive(Constructor)]
+pub struct Sos {
+    pub max_distance: f32,
+    pub ms_per_flash: u32,
+}
+
+impl Sos {
+    pub fn buffer(
+        &mut self,
+        now: u32,
+        leds: &mut [RGB8],
+        network_data: &NetworkData,
+        sos: &network::Sos,
+    ) -> Option<()> {
+        let flash_on = (now / self.ms_per_flash) % 2 == 0;
+
+        // bright enough to notice even if the compass is in a pocket
+        let background = if flash_on {
+            colors::RED
+        } else {
+            RGB8::new(0x40, 0, 0)
+        };
+
+        for led in leds.iter_mut() {
+            *led = background;
+        }
+
+        let my_location = network_data.my_peer_id.and_then(|my_peer_id| {
+            network_data.peer_locations[my_peer_id]
+                .as_ref()
+                .map(|(location, _)| location)
+        });
+
+        let (my_location, their_position) = match (my_location, sos.position) {
+            (Some(my_location), Some(their_position)) => (my_location, their_position),
+            _ => return Some(()),
+        };
+
+        let bearing = get_bearing(my_location, &their_position);
+
+        let distance = get_haversine_distance(my_location, &their_position);
+
+        // a line from us out toward them
+        for step in 0..LINE_STEPS {
+            let step_distance = distance * step as f32 / LINE_STEPS as f32;
+
+            let i = bearing_and_distance_to_id(bearing, step_distance, self.max_distance);
+
+            leds[i] = colors::WHITE;
+        }
+
+        // and them at the end of it. blinking opposite the background so they stand out
+        let i = bearing_and_distance_to_id(bearing, distance, self.max_distance);
+
+        leds[i] = if flash_on {
+            RGB8::default()
+        } else {
+            colors::WHITE
+        };
+
+        Some(())
+    }
+}
@@ -6,6 +6,7 @@
 // TODO: i'd really like to use someone else's code here
 // use adafruit_gps::gps::{Gps, GpsSentence};
 // use adafruit_gps::send_pmtk::NmeaOutput;
+use core::sync::atomic::{AtomicU32, Ordering};
 use embedded_hal::digital::v2::OutputPin;
 use heapless::consts::U256;
 use heapless::spsc::{Consumer, Producer, Queue};
@@ -32,12 +33,14 @@ pub struct UltimateGps<SerialTx: embedded_hal::serial::Write<u8>, EnablePin: Out
     // TODO: what size? what's the longest sentence?
     sentence_buffer: [u8; 82],
 
+    pub clock: &'static GpsClock,
     pub data: GpsData,
 }
 
 pub struct UltimateGpsQueue<SerialRx: embedded_hal::serial::Read<u8>> {
     serial_rx: SerialRx,
     queue_tx: Producer<'static, u8, U256>,
+    clock: &'static GpsClock,
 }
 
 impl<SerialRx: embedded_hal::serial::Read<u8>> UltimateGpsQueue<SerialRx> {
@@ -56,6 +59,135 @@ impl<SerialRx: embedded_hal::serial::Read<u8>> UltimateGpsQueue<SerialRx> {
             }
         }
     }
+
+    /// The PPS pin went high. `now` is `ElapsedMs::now`
+    /// this gets called inside an interrupt, so make this fast!
+    #[inline(always)]
+    pub fn pps_edge(&mut self, now: u32) {
+        self.clock.pps_edge(now);
+    }
+}
+
+/// If the PPS pin has been quiet longer than this, we lost it (or the fix)
+pub const PPS_TIMEOUT_MS: u32 = 1_100;
+
+/// Don't count PPS edges forward from a sentence for longer than this many seconds
+pub const MAX_PPS_WITHOUT_SENTENCE: u32 = 3;
+
+/// Millisecond time from the GPS's PPS pin.
+///
+/// The PPS pin goes high right at the start of every second (but only while the GPS has a fix). The NMEA sentences
+/// for that second show up a few hundred ms later. Put the two together and we know exactly when the second started.
+///
+/// The PPS interrupt writes the edges and the idle loop writes the sentence times, so this is all atomics.
+pub struct GpsClock {
+    /// `ElapsedMs::now` at the latest PPS edge
+    edge_at: AtomicU32,
+    /// how many PPS edges we have seen
+    edges: AtomicU32,
+    /// `edges` when the last sentence was received
+    sentence_edges: AtomicU32,
+    /// seconds since the epoch from the last sentence. 0 means we haven't had one yet
+    sentence_seconds: AtomicU32,
+}
+
+impl GpsClock {
+    pub const fn new() -> Self {
+        Self {
+            edge_at: AtomicU32::new(0),
+            edges: AtomicU32::new(0),
+            sentence_edges: AtomicU32::new(0),
+            sentence_seconds: AtomicU32::new(0),
+        }
+    }
+
+    /// Call this from the PPS interrupt. `now` is `ElapsedMs::now`
+    #[inline(always)]
+    pub fn pps_edge(&self, now: u32) {
+        self.edge_at.store(now, Ordering::Relaxed);
+        // only the interrupt writes this, so load and store is fine (and thumbv6 doesn't have fetch_add)
+        let edges = self.edges.load(Ordering::Relaxed);
+        self.edges.store(edges.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Call this when a sentence gives us the time. The sentence is about the second that the latest edge started
+    /// TODO: if the idle loop is slow and doesn't parse the sentence until after the next edge, we will be off by a
+    /// second. timestamp the start of the sentence in the interrupt?
+    pub fn sentence_time(&self, epoch_seconds: u32) {
+        self.sentence_edges
+            .store(self.edges.load(Ordering::Acquire), Ordering::Relaxed);
+        self.sentence_seconds
+            .store(epoch_seconds, Ordering::Relaxed);
+    }
+
+    /// Milliseconds since the epoch. None if we aren't getting PPS edges or sentences
+    pub fn now_ms(&self, now: u32) -> Option<u64> {
+        let sentence_seconds = self.sentence_seconds.load(Ordering::Relaxed);
+
+        if sentence_seconds == 0 {
+            return None;
+        }
+
+        // the interrupt might fire while we are reading. if it does, read again
+        let (edges, edge_at) = loop {
+            let edges = self.edges.load(Ordering::Acquire);
+            let edge_at = self.edge_at.load(Ordering::Relaxed);
+
+            if self.edges.load(Ordering::Acquire) == edges {
+                break (edges, edge_at);
+            }
+        };
+
+        if edges == 0 {
+            // no PPS pin
+            return None;
+        }
+
+        let since_edge_ms = now.wrapping_sub(edge_at);
+
+        if since_edge_ms > PPS_TIMEOUT_MS {
+            return None;
+        }
+
+        // every edge since the sentence is another second
+        let seconds_since_sentence =
+            edges.wrapping_sub(self.sentence_edges.load(Ordering::Relaxed));
+
+        if seconds_since_sentence > MAX_PPS_WITHOUT_SENTENCE {
+            return None;
+        }
+
+        let epoch_seconds = (sentence_seconds + seconds_since_sentence) as u64;
+
+        Some(epoch_seconds * 1000 + since_edge_ms as u64)
+    }
+
+    /// Seconds since the epoch and milliseconds past that
+    pub fn now(&self, now: u32) -> Option<(u32, u32)> {
+        let now_ms = self.now_ms(now)?;
+
+        Some(((now_ms / 1000) as u32, (now_ms % 1000) as u32))
+    }
+}
+
+impl Default for GpsClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The time of day (UTC) from `GpsClock::now` or `NetworkClock::now`
+pub fn time_of_day(epoch_seconds: u32, ms: u32) -> Option<time::Time> {
+    // the epoch is at midnight, so this is easy
+    let seconds = epoch_seconds % 86_400;
+
+    time::Time::try_from_hms_milli(
+        (seconds / 3_600) as u8,
+        (seconds / 60 % 60) as u8,
+        (seconds % 60) as u8,
+        ms as u16,
+    )
+    .ok()
 }
 
 /// There's a lot more information available, but we don't need it right now
@@ -117,7 +249,7 @@ impl GpsData {
         }
 
         // TODO: i'm sure this could be more efficient
-        // TODO: use pps_pin on an interrupt to increment this
+        // this is only whole seconds. `GpsClock` has the milliseconds
         if let (Some(gps_date), Some(gps_time)) = (self.date, self.time) {
             let now = time::PrimitiveDateTime::new(gps_date, gps_time);
 
@@ -137,12 +269,12 @@ impl<SerialTx: embedded_hal::serial::Write<u8>, EnablePin: OutputPin>
         serial_tx: SerialTx,
         serial_rx: SerialRx,
         enable_pin: EnablePin,
-        // TODO: `pps_pin` on an interrupt
-        // pps_pin:
     ) -> (Self, UltimateGpsQueue<SerialRx>) {
         // `heapless::i` is an "unfortunate implementation detail required to construct heapless types in const context"
         // TODO: do the static outside this?
         static mut Q: Queue<u8, U256> = Queue(heapless::i::Queue::new());
+        // the PPS pin isn't on the serial port. call `UltimateGpsQueue::pps_edge` from its interrupt
+        static CLOCK: GpsClock = GpsClock::new();
 
         let (queue_tx, queue_rx) = unsafe { Q.split() };
 
@@ -164,6 +296,7 @@ impl<SerialTx: embedded_hal::serial::Write<u8>, EnablePin: OutputPin>
             enable_pin,
             sentence_buffer_len,
             sentence_buffer,
+            clock: &CLOCK,
             data,
             epoch,
         };
@@ -171,6 +304,7 @@ impl<SerialTx: embedded_hal::serial::Write<u8>, EnablePin: OutputPin>
         let updater = UltimateGpsQueue {
             serial_rx,
             queue_tx,
+            clock: &CLOCK,
         };
 
         (gps, updater)
@@ -208,6 +342,12 @@ impl<SerialTx: embedded_hal::serial::Write<u8>, EnablePin: OutputPin>
             false
         };
 
+        if updated {
+            if let Some(epoch_seconds) = self.data.epoch_seconds {
+                self.clock.sentence_time(epoch_seconds);
+            }
+        }
+
         // clear the buffer
         self.sentence_buffer_len = 0;
 
@@ -269,40 +409,63 @@ impl<SerialTx: embedded_hal::serial::Write<u8>, EnablePin: OutputPin>
 // TODO: the old code read the gps data on a timer. do we want that still?
 // https://github.com/atsamd-rs/atsamd/blob/master/boards/feather_m0/examples/timers.rs
 
-/*
-// TODO: serde serialize/deserialize on this
-struct SmartCompassLocationMessage {
-    bytes network_hash = 1 [(nanopb).max_size = 16, (nanopb).fixed_length = true];
-    bytes message_hash = 2 [(nanopb).max_size = 16, (nanopb).fixed_length = true];
-    uint32 tx_peer_id = 3;
-
-    uint32 tx_time = 4;
-    uint32 tx_ms = 5;
-
-    uint32 peer_id = 6;
-    uint32 last_updated_at = 7;
-    uint32 hue = 8; // todo: fixed_length and max_size = 8 bits?
-    uint32 saturation = 9; // todo: fixed_length and max_size = 8 bits?
-    int32 latitude = 10;
-    int32 longitude = 11;
-
-    // todo: if there is a mismatch between peers, we need to re-broadcast old pins
-    // todo: this seems naive and fragile
-    uint32 num_pins = 12;
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-// TODO: serde serialize/deserialize on this
-struct SmartCompassPinMessage {
-    bytes network_hash = 1 [(nanopb).max_size = 16, (nanopb).fixed_length = true];
-    bytes message_hash = 2 [(nanopb).max_size = 16, (nanopb).fixed_length = true];
-    uint32 tx_peer_id = 3;
+    const NOW: u32 = 1_600_000;
 
-    uint32 last_updated_at = 5;
+    #[test]
+    fn test_pps() {
+        let clock = GpsClock::new();
 
-    int32 latitude = 6;
-    int32 longitude = 7;
+        // nothing yet
+        assert_eq!(clock.now_ms(0), None);
 
-    uint32 hue = 8; // todo: fixed_length and max_size = 8 bits?
-    uint32 saturation = 9; // todo: fixed_length and max_size = 8 bits?
+        // a sentence without the PPS pin isn't good enough
+        clock.sentence_time(NOW);
+        assert_eq!(clock.now_ms(100), None);
+
+        // the second starts. then its sentence arrives
+        clock.pps_edge(1_000);
+        clock.sentence_time(NOW + 1);
+
+        assert_eq!(clock.now_ms(1_000), Some((NOW as u64 + 1) * 1000));
+        assert_eq!(clock.now(1_250), Some((NOW + 1, 250)));
+
+        // the next edge. its sentence hasn't arrived yet
+        clock.pps_edge(2_001);
+        assert_eq!(clock.now(2_001), Some((NOW + 2, 0)));
+        assert_eq!(clock.now(2_500), Some((NOW + 2, 499)));
+    }
+
+    #[test]
+    fn test_pps_lost() {
+        let clock = GpsClock::new();
+
+        clock.pps_edge(1_000);
+        clock.sentence_time(NOW);
+
+        assert!(clock.now_ms(1_000 + PPS_TIMEOUT_MS).is_some());
+        assert_eq!(clock.now_ms(1_000 + PPS_TIMEOUT_MS + 1), None);
+
+        // edges without sentences
+        for second in 1..=MAX_PPS_WITHOUT_SENTENCE {
+            clock.pps_edge(1_000 + second * 1_000);
+        }
+        assert_eq!(clock.now(4_000), Some((NOW + MAX_PPS_WITHOUT_SENTENCE, 0)));
+
+        clock.pps_edge(5_000);
+        assert_eq!(clock.now_ms(5_000), None);
+    }
+
+    #[test]
+    fn test_time_of_day() {
+        let time = time_of_day(86_400 * 10 + 3_600 * 13 + 60 * 2 + 3, 456).unwrap();
+
+        assert_eq!(time.hour(), 13);
+        assert_eq!(time.minute(), 2);
+        assert_eq!(time.second(), 3);
+        assert_eq!(time.millisecond(), 456);
+    }
 }
-*/
@@ -0,0 +1,72 @@
+//! How long a packet is on the air.
+//!
+//! This is the formula from the SX1276 datasheet (section 4.1.1.7). It assumes an 8 symbol preamble, an explicit header,
+//! and the payload CRC turned on. Those are radio_sx127x's defaults.
+//! TODO: take the preamble length once it is configurable
+
+const PREAMBLE_LEN: u32 = 8;
+
+/// Airtime in microseconds for a packet with `payload_len` bytes. `coding_rate` is the 5 in 4/5
+pub fn airtime_us(
+    payload_len: usize,
+    spreading_factor: u8,
+    bandwidth_hz: u32,
+    coding_rate: u8,
+) -> u32 {
+    let sf = spreading_factor as i64;
+
+    // low data rate optimization is required when a symbol takes 16ms or more (SF11 and SF12 at 125kHz)
+    let symbol_us = (1u64 << sf) * 1_000_000 / bandwidth_hz as u64;
+    let low_data_rate = if symbol_us >= 16_000 { 1 } else { 0 };
+
+    // explicit header (0) and crc on (1)
+    let numerator = 8 * payload_len as i64 - 4 * sf + 28 + 16;
+    let denominator = 4 * (sf - 2 * low_data_rate);
+
+    // ceil, but never less than 0
+    let blocks = if numerator > 0 {
+        (numerator + denominator - 1) / denominator
+    } else {
+        0
+    };
+
+    let payload_symbols = 8 + blocks as u64 * coding_rate as u64;
+
+    // the preamble is 4.25 symbols longer than PREAMBLE_LEN. count in quarter symbols to keep this in integers
+    let quarter_symbols = (PREAMBLE_LEN as u64 * 4 + 17) + payload_symbols * 4;
+
+    let us = quarter_symbols * (1u64 << sf) * 1_000_000 / (4 * bandwidth_hz as u64);
+
+    us as u32
+}
+
+/// Airtime in milliseconds, rounded up
+pub fn airtime_ms(
+    payload_len: usize,
+    spreading_factor: u8,
+    bandwidth_hz: u32,
+    coding_rate: u8,
+) -> u32 {
+    (airtime_us(payload_len, spreading_factor, bandwidth_hz, coding_rate) + 999) / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_airtimes() {
+        // these match Semtech's LoRa calculator
+        assert_eq!(airtime_us(10, 7, 125_000, 5), 41_216);
+        assert_eq!(airtime_us(255, 7, 125_000, 5), 399_616);
+        // low data rate optimization kicks in here
+        assert_eq!(airtime_us(10, 12, 125_000, 5), 991_232);
+    }
+
+    #[test]
+    fn test_wider_is_faster() {
+        assert!(airtime_us(40, 7, 250_000, 5) < airtime_us(40, 7, 125_000, 5));
+        assert!(airtime_us(40, 8, 125_000, 5) > airtime_us(40, 7, 125_000, 5));
+        assert!(airtime_us(40, 7, 125_000, 8) > airtime_us(40, 7, 125_000, 5));
+    }
+}
@@ -0,0 +1,348 @@
+//! What time is it on the network?
+//!
+//! The schedule only works if everyone agrees on the time. GPS time is the best we have, so use it when we have a fix.
+//! With the PPS pin, the GPS time is good to the millisecond.
+//! Without a fix, use the time from peers' `TimeSync` messages. Peers with a fix are trusted over peers without one.
+//!
+//! All of this is tracked as an offset from `ElapsedMs`, so the time keeps ticking between syncs.
+use super::TimeSync;
+
+/// If we haven't synced in this long, take the time from anyone that has it
+pub const SYNC_TIMEOUT_MS: u32 = 10_000;
+
+/// Drift is measured over at least this long. Shorter than this, the jitter from our idle loop is bigger than the drift
+pub const DRIFT_WINDOW_MS: u32 = 60_000;
+
+/// Where our time came from. Later variants are trusted less
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum TimeSource {
+    /// our own GPS
+    Gps,
+    /// a peer with a GPS fix
+    PeerGps,
+    /// a peer that got its time from another peer
+    Peer,
+}
+
+#[derive(Default)]
+pub struct NetworkClock {
+    /// epoch ms minus elapsed ms. None until we have synced
+    offset_ms: Option<i64>,
+    source: Option<TimeSource>,
+    /// elapsed ms at the last sync
+    synced_at: u32,
+    /// the last second the GPS told us about. it changing means a new second just started
+    last_gps_second: Option<u32>,
+    /// elapsed ms and offset at the start of the drift window
+    drift_anchor: Option<(u32, i64)>,
+    /// how fast our ElapsedMs runs compared to the source's clock. positive means ours is slow
+    drift_ppm: i32,
+}
+
+impl NetworkClock {
+    /// Call this every time the GPS gives us a time. `now` is `ElapsedMs::now`
+    /// The NMEA sentence shows up a while after the second starts, so this is only good to a few hundred ms. Use
+    /// `gps_time_ms` if the PPS pin is hooked up
+    pub fn gps_time(&mut self, epoch_seconds: u32, now: u32) {
+        let last_gps_second = self.last_gps_second.replace(epoch_seconds);
+
+        if last_gps_second.is_none() || last_gps_second == Some(epoch_seconds) {
+            // we don't know where in the second we are. wait for the next one to start
+            return;
+        }
+
+        let offset_ms = epoch_seconds as i64 * 1000 - now as i64;
+
+        self.sync(TimeSource::Gps, offset_ms, now);
+    }
+
+    /// Call this with the time from `GpsClock::now_ms`
+    pub fn gps_time_ms(&mut self, epoch_ms: u64, now: u32) {
+        // if we lose the PPS pin, `gps_time` needs to wait for a new second again
+        self.last_gps_second = None;
+
+        let offset_ms = epoch_ms as i64 - now as i64;
+
+        self.sync(TimeSource::Gps, offset_ms, now);
+    }
+
+    /// Call this with a peer's `TimeSync`. `delay_ms` is how long the packet was in the air
+    pub fn peer_time(&mut self, time_sync: &TimeSync, delay_ms: u32, now: u32) {
+        let source = if time_sync.from_gps {
+            TimeSource::PeerGps
+        } else {
+            TimeSource::Peer
+        };
+
+        if let Some(current) = self.source {
+            if current < source && !self.is_stale(now) {
+                // we already have something better
+                return;
+            }
+        }
+
+        let their_ms =
+            time_sync.epoch_seconds as i64 * 1000 + time_sync.ms as i64 + delay_ms as i64;
+        let offset_ms = their_ms - now as i64;
+
+        let offset_ms = match (self.source, self.offset_ms) {
+            (Some(current), Some(old_offset_ms)) if current == source && !self.is_stale(now) => {
+                // everyone's loops add some jitter. don't jump around for it
+                old_offset_ms + (offset_ms - old_offset_ms) / 4
+            }
+            _ => offset_ms,
+        };
+
+        self.sync(source, offset_ms, now);
+    }
+
+    fn sync(&mut self, source: TimeSource, offset_ms: i64, now: u32) {
+        match self.drift_anchor {
+            Some((anchor_at, anchor_offset_ms)) if self.source == Some(source) => {
+                let window_ms = now.wrapping_sub(anchor_at);
+
+                if window_ms >= DRIFT_WINDOW_MS {
+                    self.drift_ppm =
+                        ((offset_ms - anchor_offset_ms) * 1_000_000 / window_ms as i64) as i32;
+                    self.drift_anchor = Some((now, offset_ms));
+                }
+            }
+            _ => {
+                // a different clock drifts differently. start over
+                self.drift_anchor = Some((now, offset_ms));
+            }
+        }
+
+        self.offset_ms = Some(offset_ms);
+        self.source = Some(source);
+        self.synced_at = now;
+    }
+
+    /// True if we haven't synced recently. We can keep going on our own for a while, but we should listen to anyone
+    pub fn is_stale(&self, now: u32) -> bool {
+        self.offset_ms.is_none() || now.wrapping_sub(self.synced_at) > SYNC_TIMEOUT_MS
+    }
+
+    /// Milliseconds since the epoch. None if we have never synced
+    pub fn now_ms(&self, now: u32) -> Option<u64> {
+        let offset_ms = self.offset_ms?;
+
+        // keep correcting for drift until the next sync
+        let since_sync_ms = now.wrapping_sub(self.synced_at) as i64;
+        let drift_ms = since_sync_ms * self.drift_ppm as i64 / 1_000_000;
+
+        Some((now as i64 + offset_ms + drift_ms) as u64)
+    }
+
+    /// Seconds since the epoch and milliseconds past that. This is what `Schedule::slot` wants
+    pub fn now(&self, now: u32) -> Option<(u32, u32)> {
+        let now_ms = self.now_ms(now)?;
+
+        Some(((now_ms / 1000) as u32, (now_ms % 1000) as u32))
+    }
+
+    /// Our time for broadcasting. None if we don't have a time worth sharing
+    pub fn time_sync(&self, now: u32) -> Option<TimeSync> {
+        if self.is_stale(now) {
+            return None;
+        }
+
+        let (epoch_seconds, ms) = self.now(now)?;
+
+        Some(TimeSync {
+            epoch_seconds,
+            ms: ms as u16,
+            from_gps: self.source == Some(TimeSource::Gps),
+        })
+    }
+
+    /// For debugging
+    pub fn source(&self) -> Option<TimeSource> {
+        self.source
+    }
+
+    /// For debugging. How far the network time is from our ElapsedMs
+    pub fn offset_ms(&self) -> Option<i64> {
+        self.offset_ms
+    }
+
+    /// For debugging. How fast our ElapsedMs runs compared to the network time
+    pub fn drift_ppm(&self) -> i32 {
+        self.drift_ppm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{mock_node, NOW};
+    use super::super::MockAir;
+    use super::*;
+    use crate::timers::ElapsedMs;
+
+    #[test]
+    fn test_gps_waits_for_a_new_second() {
+        let mut clock = NetworkClock::default();
+
+        clock.gps_time(NOW, 1_500);
+        assert_eq!(clock.now(1_500), None);
+
+        clock.gps_time(NOW, 1_900);
+        assert_eq!(clock.now(1_900), None);
+
+        // the second just started
+        clock.gps_time(NOW + 1, 2_100);
+        assert_eq!(clock.now(2_100), Some((NOW + 1, 0)));
+        assert_eq!(clock.now(2_350), Some((NOW + 1, 250)));
+        assert_eq!(clock.source(), Some(TimeSource::Gps));
+    }
+
+    #[test]
+    fn test_gps_time_ms() {
+        let mut clock = NetworkClock::default();
+
+        // no waiting with the PPS pin
+        clock.gps_time_ms(NOW as u64 * 1000 + 123, 5_000);
+        assert_eq!(clock.now(5_000), Some((NOW, 123)));
+        assert_eq!(clock.now(5_900), Some((NOW + 1, 23)));
+        assert_eq!(clock.source(), Some(TimeSource::Gps));
+
+        // the PPS pin went away. the sentences alone have to wait for a new second
+        clock.gps_time(NOW + 2, 6_950);
+        assert_eq!(clock.now(6_950), Some((NOW + 2, 73)));
+    }
+
+    #[test]
+    fn test_peer_time() {
+        let mut clock = NetworkClock::default();
+
+        let time_sync = TimeSync {
+            epoch_seconds: NOW,
+            ms: 900,
+            from_gps: true,
+        };
+
+        clock.peer_time(&time_sync, 50, 10_000);
+
+        assert_eq!(clock.now(10_000), Some((NOW, 950)));
+        assert_eq!(clock.now(10_100), Some((NOW + 1, 50)));
+        assert_eq!(clock.source(), Some(TimeSource::PeerGps));
+    }
+
+    #[test]
+    fn test_gps_beats_peers() {
+        let mut clock = NetworkClock::default();
+
+        clock.gps_time(NOW, 0);
+        clock.gps_time(NOW + 1, 1_000);
+
+        // this peer is way off
+        let time_sync = TimeSync {
+            epoch_seconds: NOW + 100,
+            ms: 0,
+            from_gps: true,
+        };
+
+        clock.peer_time(&time_sync, 0, 1_500);
+        assert_eq!(clock.now(1_500), Some((NOW + 1, 500)));
+
+        // but if our GPS goes quiet, the peer is better than nothing
+        clock.peer_time(&time_sync, 0, 1_000 + SYNC_TIMEOUT_MS + 1);
+        assert_eq!(clock.now(1_000 + SYNC_TIMEOUT_MS + 1), Some((NOW + 100, 0)));
+        assert_eq!(clock.source(), Some(TimeSource::PeerGps));
+    }
+
+    #[test]
+    fn test_peers_without_gps_are_a_last_resort() {
+        let mut clock = NetworkClock::default();
+
+        let from_gps = TimeSync {
+            epoch_seconds: NOW,
+            ms: 0,
+            from_gps: true,
+        };
+        let from_peer = TimeSync {
+            epoch_seconds: NOW + 100,
+            ms: 0,
+            from_gps: false,
+        };
+
+        clock.peer_time(&from_gps, 0, 0);
+        clock.peer_time(&from_peer, 0, 0);
+
+        assert_eq!(clock.now(0), Some((NOW, 0)));
+        // we only heard this second hand
+        assert!(!clock.time_sync(0).unwrap().from_gps);
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut clock = NetworkClock::default();
+
+        clock.gps_time(NOW, 0);
+
+        // our ElapsedMs is 1ms slow every second. that's 1000ppm
+        for second in 1..=120 {
+            clock.gps_time(NOW + second, second * 999);
+        }
+
+        assert!((995..=1_005).contains(&clock.drift_ppm()));
+
+        // the GPS went quiet. 100 seconds later, we are still close
+        let expected_ms = (NOW as i64 + 220) * 1000;
+        let now_ms = clock.now_ms(120 * 999 + 100 * 999).unwrap() as i64;
+
+        assert!((now_ms - expected_ms).abs() <= 2);
+    }
+
+    #[test]
+    fn test_stale() {
+        let mut clock = NetworkClock::default();
+
+        assert!(clock.is_stale(0));
+        assert!(clock.time_sync(0).is_none());
+
+        clock.gps_time(NOW, 0);
+        clock.gps_time(NOW + 1, 1_000);
+
+        assert!(!clock.is_stale(1_000 + SYNC_TIMEOUT_MS));
+        assert!(clock.is_stale(1_000 + SYNC_TIMEOUT_MS + 1));
+        assert!(clock.time_sync(1_000 + SYNC_TIMEOUT_MS + 1).is_none());
+
+        // we still have a guess at the time though
+        assert!(clock.now(1_000 + SYNC_TIMEOUT_MS + 1).is_some());
+    }
+
+    #[test]
+    fn test_time_from_peers() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        elapsed_ms.increment_by(500);
+        a.data.clock.gps_time(NOW, elapsed_ms.now());
+        elapsed_ms.increment_by(500);
+        a.data.clock.gps_time(NOW + 1, elapsed_ms.now());
+
+        // b doesn't have a GPS fix. it can't use a's location without knowing the time
+        b.listen_for_time(&elapsed_ms).unwrap();
+        a.transmit(&elapsed_ms, NOW + 1, 0, 2).unwrap();
+        b.listen_for_time(&elapsed_ms).unwrap();
+
+        assert_eq!(b.data.stats.stale, 1);
+        assert!(b.data.clock.now(elapsed_ms.now()).is_none());
+
+        // a has nothing else to say about itself, so it sends its time
+        a.transmit(&elapsed_ms, NOW + 1, 0, 2).unwrap();
+        b.listen_for_time(&elapsed_ms).unwrap();
+
+        assert_eq!(b.data.stats.accepted, 1);
+        assert_eq!(b.data.clock.source(), Some(TimeSource::PeerGps));
+
+        let (epoch_seconds, ms) = b.data.clock.now(elapsed_ms.now()).unwrap();
+        assert_eq!(epoch_seconds, NOW + 1);
+        // plus however long the packet was in the air
+        assert!(ms > 0 && ms < 100);
+    }
+}
@@ -0,0 +1,149 @@
+//! Keys derived from the shared network secret.
+//!
+//! Everyone in a group has the same secret. Anyone who doesn't have it can't produce a valid tag,
+//! so they can't put fake peers on our compasses even if they sniff our packets.
+//!
+//! Encryption is BLAKE2s in counter mode followed by the tag over the ciphertext (encrypt-then-mac).
+//! TODO: switch to ChaCha20-Poly1305 if flash space allows. This keeps us to the one hash function
+use blake2::digest::{Update, VariableOutput};
+use blake2::VarBlake2s;
+
+pub const NETWORK_SECRET_LEN: usize = 32;
+pub const NETWORK_HASH_LEN: usize = 16;
+/// Tags are truncated to keep packets small. 64 bits is plenty for a group of friends.
+pub const MAC_LEN: usize = 8;
+
+pub type NetworkSecret = [u8; NETWORK_SECRET_LEN];
+pub type NetworkHash = [u8; NETWORK_HASH_LEN];
+pub type Mac = [u8; MAC_LEN];
+
+/// Labels keep the derived keys independent of each other
+const NETWORK_HASH_LABEL: &[u8] = b"smart compass network hash";
+const MAC_KEY_LABEL: &[u8] = b"smart compass mac key";
+const CIPHER_KEY_LABEL: &[u8] = b"smart compass cipher key";
+
+/// BLAKE2s outputs up to 32 bytes at a time
+const KEYSTREAM_BLOCK_LEN: usize = 32;
+
+pub struct NetworkKeys {
+    /// Identifies our group. Sent in the clear so that other groups' packets can be skipped quickly
+    pub network_hash: NetworkHash,
+    mac_key: [u8; 32],
+    cipher_key: [u8; 32],
+}
+
+impl NetworkKeys {
+    pub fn new(network_secret: &NetworkSecret) -> Self {
+        let mut network_hash = [0u8; NETWORK_HASH_LEN];
+        keyed_hash(network_secret, &[NETWORK_HASH_LABEL], &mut network_hash);
+
+        let mut mac_key = [0u8; 32];
+        keyed_hash(network_secret, &[MAC_KEY_LABEL], &mut mac_key);
+
+        let mut cipher_key = [0u8; 32];
+        keyed_hash(network_secret, &[CIPHER_KEY_LABEL], &mut cipher_key);
+
+        Self {
+            network_hash,
+            mac_key,
+            cipher_key,
+        }
+    }
+
+    /// XOR the data with a keystream. Calling this twice with the same nonce gets the original data back.
+    ///
+    /// The nonce MUST be different for every message. Reusing one leaks the XOR of the two plaintexts
+    pub fn apply_keystream(&self, nonce: &[u8], data: &mut [u8]) {
+        let mut block = [0u8; KEYSTREAM_BLOCK_LEN];
+
+        for (counter, chunk) in data.chunks_mut(KEYSTREAM_BLOCK_LEN).enumerate() {
+            let counter = (counter as u32).to_le_bytes();
+
+            keyed_hash(&self.cipher_key, &[nonce, &counter[..]], &mut block);
+
+            for (b, k) in chunk.iter_mut().zip(block.iter()) {
+                *b ^= k;
+            }
+        }
+    }
+
+    /// Calculate the truncated keyed BLAKE2s tag for some bytes
+    pub fn sign(&self, data: &[u8]) -> Mac {
+        let mut mac = [0u8; MAC_LEN];
+
+        keyed_hash(&self.mac_key, &[data], &mut mac);
+
+        mac
+    }
+
+    /// Check the tag in constant time
+    pub fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        constant_time_eq(&self.sign(data), mac)
+    }
+}
+
+/// `new_keyed` panics if the key or the output is longer than 32 bytes
+fn keyed_hash(key: &[u8], parts: &[&[u8]], output: &mut [u8]) {
+    let mut hasher = VarBlake2s::new_keyed(key, output.len());
+
+    for part in parts {
+        hasher.update(*part);
+    }
+
+    hasher.finalize_variable(|result| output.copy_from_slice(result));
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let keys = NetworkKeys::new(&[1u8; NETWORK_SECRET_LEN]);
+
+        let mac = keys.sign(b"hello");
+
+        assert!(keys.verify(b"hello", &mac));
+        assert!(!keys.verify(b"hellp", &mac));
+        assert!(!keys.verify(b"hello", &mac[1..]));
+    }
+
+    #[test]
+    fn test_different_secrets() {
+        let keys_a = NetworkKeys::new(&[1u8; NETWORK_SECRET_LEN]);
+        let keys_b = NetworkKeys::new(&[2u8; NETWORK_SECRET_LEN]);
+
+        assert_ne!(keys_a.network_hash, keys_b.network_hash);
+
+        let mac = keys_a.sign(b"hello");
+
+        assert!(!keys_b.verify(b"hello", &mac));
+    }
+
+    #[test]
+    fn test_keystream() {
+        let keys = NetworkKeys::new(&[1u8; NETWORK_SECRET_LEN]);
+
+        // longer than one block
+        let plaintext = [42u8; 40];
+
+        let mut data = plaintext;
+        keys.apply_keystream(b"nonce", &mut data);
+        assert_ne!(data[..], plaintext[..]);
+
+        let mut other_nonce = plaintext;
+        keys.apply_keystream(b"other", &mut other_nonce);
+        assert_ne!(data[..], other_nonce[..]);
+
+        keys.apply_keystream(b"nonce", &mut data);
+        assert_eq!(data[..], plaintext[..]);
+    }
+}
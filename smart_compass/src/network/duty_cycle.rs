@@ -0,0 +1,159 @@
+//! How much the radio has been on lately.
+//!
+//! Some regions limit how much of the time we can transmit (1% on most of EU868). Receiving uses almost as much power
+//! as transmitting, so that's tracked too.
+//!
+//! Time is counted in buckets. Old buckets drop off as time goes on.
+
+/// Duty cycle limits are over an hour
+pub const DUTY_CYCLE_WINDOW_MS: u32 = 60 * 60 * 1000;
+
+const BUCKET_MS: u32 = 10 * 60 * 1000;
+
+/// The newest bucket is only partly done. One extra makes sure that we always count at least a whole hour
+const BUCKETS: usize = (DUTY_CYCLE_WINDOW_MS / BUCKET_MS) as usize + 1;
+
+#[derive(Default)]
+pub struct RadioTime {
+    transmit_ms: [u32; BUCKETS],
+    receive_ms: [u32; BUCKETS],
+    /// `ElapsedMs::now / BUCKET_MS` for the newest bucket
+    newest_bucket: u32,
+}
+
+impl RadioTime {
+    /// Empty any buckets that are too old
+    fn rotate(&mut self, now: u32) {
+        let bucket = now / BUCKET_MS;
+        let passed = bucket.wrapping_sub(self.newest_bucket).min(BUCKETS as u32);
+
+        for i in 1..=passed {
+            let i = (self.newest_bucket.wrapping_add(i) as usize) % BUCKETS;
+
+            self.transmit_ms[i] = 0;
+            self.receive_ms[i] = 0;
+        }
+
+        self.newest_bucket = bucket;
+    }
+
+    pub fn transmitted(&mut self, ms: u32, now: u32) {
+        self.rotate(now);
+
+        let i = self.newest_bucket as usize % BUCKETS;
+        self.transmit_ms[i] = self.transmit_ms[i].saturating_add(ms);
+    }
+
+    pub fn received(&mut self, ms: u32, now: u32) {
+        self.rotate(now);
+
+        let i = self.newest_bucket as usize % BUCKETS;
+        self.receive_ms[i] = self.receive_ms[i].saturating_add(ms);
+    }
+
+    /// How long we transmitted for in the last hour (or a little more)
+    pub fn transmit_ms(&mut self, now: u32) -> u32 {
+        self.rotate(now);
+
+        self.transmit_ms.iter().sum()
+    }
+
+    /// How long we listened for in the last hour (or a little more)
+    pub fn receive_ms(&mut self, now: u32) -> u32 {
+        self.rotate(now);
+
+        self.receive_ms.iter().sum()
+    }
+
+    /// True if sending a packet this long keeps us under the limit. `max_permille` is the duty cycle limit in 0.1%
+    pub fn can_transmit(&mut self, airtime_ms: u32, max_permille: u32, now: u32) -> bool {
+        let max_ms = DUTY_CYCLE_WINDOW_MS / 1000 * max_permille;
+
+        self.transmit_ms(now) + airtime_ms <= max_ms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::packet::HEADER_LEN;
+    use super::super::test_util::{mock_node, NOW};
+    use super::super::{Message, MockAir, RadioSettings, Region, MAC_LEN};
+    use super::*;
+    use crate::timers::ElapsedMs;
+
+    #[test]
+    fn test_limit() {
+        let mut radio_time = RadioTime::default();
+
+        // 1% of an hour is 36 seconds
+        radio_time.transmitted(35_900, 1_000);
+
+        assert!(radio_time.can_transmit(100, 10, 2_000));
+        assert!(!radio_time.can_transmit(101, 10, 2_000));
+
+        // still too soon
+        assert!(!radio_time.can_transmit(101, 10, DUTY_CYCLE_WINDOW_MS));
+
+        // the old bucket dropped off
+        assert!(radio_time.can_transmit(101, 10, DUTY_CYCLE_WINDOW_MS + BUCKET_MS));
+        assert_eq!(radio_time.transmit_ms(DUTY_CYCLE_WINDOW_MS + BUCKET_MS), 0);
+    }
+
+    #[test]
+    fn test_receive() {
+        let mut radio_time = RadioTime::default();
+
+        radio_time.received(500, 0);
+        radio_time.received(500, BUCKET_MS);
+        radio_time.transmitted(100, BUCKET_MS);
+
+        assert_eq!(radio_time.receive_ms(BUCKET_MS), 1_000);
+        assert_eq!(radio_time.transmit_ms(BUCKET_MS), 100);
+
+        // a long time later
+        assert_eq!(radio_time.receive_ms(BUCKET_MS * 100), 0);
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        let eu = RadioSettings::for_region(Region::Eu868);
+
+        a.set_radio_settings(eu).unwrap();
+        b.set_radio_settings(eu).unwrap();
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        elapsed_ms.increment_by(1_000);
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.radio_time.receive_ms(elapsed_ms.now()), 1_000);
+
+        // way more than 1% of an hour
+        for _ in 0..1_000 {
+            a.transmit_message(&elapsed_ms, NOW, &Message::Ping)
+                .unwrap();
+        }
+
+        let transmit_ms = a.data.radio_time.transmit_ms(elapsed_ms.now());
+        let ping_ms = eu.airtime_ms(HEADER_LEN + MAC_LEN);
+
+        assert!(transmit_ms <= DUTY_CYCLE_WINDOW_MS / 100);
+        assert!(transmit_ms > DUTY_CYCLE_WINDOW_MS / 100 - ping_ms);
+        assert_eq!(air.sent(0), 1_000 - a.data.stats.duty_cycle_limited);
+
+        // the US doesn't limit this
+        let mut c = mock_node(&air, [1; 32], 4);
+
+        for _ in 0..1_000 {
+            c.transmit_message(&elapsed_ms, NOW, &Message::Ping)
+                .unwrap();
+        }
+
+        assert_eq!(c.data.stats.duty_cycle_limited, 0);
+    }
+}
@@ -0,0 +1,78 @@
+//! What can go wrong while talking to the radio.
+//!
+//! A loose wire or some noise on the SPI bus used to panic and reset the whole compass. Now the boards get to decide.
+//! Most errors go away if the same thing is tried again. If they keep happening, `Network::reset_radio` usually fixes
+//! them. If that doesn't work either, the radio is probably gone and all we can do is show an error on the lights.
+use super::SettingsError;
+
+/// `E` is the radio's own error. Each variant says what we were trying to do when it happened
+#[derive(Clone, Debug, PartialEq)]
+pub enum NetworkError<E> {
+    /// the radio didn't answer while starting up. it might not be connected
+    Init(E),
+    /// the settings were fine, but the radio didn't take them
+    Configure(E),
+    /// the settings were bad. nothing was sent to the radio
+    Settings(SettingsError),
+    Transmit(E),
+    Receive(E),
+    Sleep(E),
+    /// resetting didn't help. the radio is probably gone
+    Reset(E),
+    /// the message didn't fit in a packet. nothing was sent. this is a bug, not the radio
+    Encode,
+}
+
+impl<E> From<SettingsError> for NetworkError<E> {
+    fn from(err: SettingsError) -> Self {
+        NetworkError::Settings(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{mock_node, NOW};
+    use super::super::{MockAir, MockError, RadioSettings};
+    use super::*;
+    use crate::timers::ElapsedMs;
+
+    #[test]
+    fn test_radio_errors() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        let quieter_channel = RadioSettings {
+            frequency_hz: 920_000_000,
+            ..RadioSettings::default()
+        };
+
+        a.set_radio_settings(quieter_channel).unwrap();
+        b.set_radio_settings(quieter_channel).unwrap();
+
+        // something went wrong on the SPI bus. nothing panics
+        air.wedge(1);
+
+        assert_eq!(
+            b.try_receive(&elapsed_ms, NOW),
+            Err(NetworkError::Receive(MockError))
+        );
+        assert_eq!(b.sleep(), Err(NetworkError::Sleep(MockError)));
+        assert_eq!(
+            b.transmit(&elapsed_ms, NOW, 1, 3),
+            Err(NetworkError::Transmit(MockError))
+        );
+
+        // a reset fixes it and puts our settings back
+        b.reset_radio().unwrap();
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.stats.accepted, 1);
+        assert_eq!(b.radio_settings(), &quieter_channel);
+    }
+}
@@ -0,0 +1,414 @@
+//! How a fresh compass gets a peer id.
+//!
+//! 1. Listen for a whole schedule cycle. Every peer talks at least once a cycle, so we learn which ids are taken.
+//! 2. Pick a free id and claim it. Claims go out at the very start of time segments, while the segment's owner is
+//!    still waiting out its guard interval.
+//! 3. If someone else claims the same id, the lower nonce wins. The loser picks a different id.
+//! 4. A compass that knows the id is taken answers the claim with that peer's location (or a ping if it's theirs and
+//!    they don't have a location yet). Hearing that, we pick a different id.
+//! 5. If no one objects for `CLAIM_SEGMENTS`, the id is ours.
+//!
+//! TODO: two peers that joined without hearing each other will both keep the id. detect that later
+use super::schedule::{GUARD_MS, TIME_SEGMENT_MS};
+use super::Claim;
+use crate::MAX_PEERS;
+
+/// Compasses that are still joining send this as their tx_peer_id
+pub const UNCONFIGURED_PEER_ID: u8 = 0xFF;
+
+/// How many time segments to keep claiming an id before it is ours
+pub const CLAIM_SEGMENTS: u64 = 10;
+
+/// How long a compass waits to answer a claim for its id. The claimer needs a moment to start listening.
+/// Claims go out in the first half of the guard interval, so the answer still fits in the second half
+pub const DEFEND_DELAY_MS: u32 = 50;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum JoinState {
+    /// Learning which peer ids are taken
+    Listening { until_ms: u64 },
+    /// We want `peer_id`. If no one else wants it by `until_ms`, it is ours
+    Claiming { peer_id: usize, until_ms: u64 },
+}
+
+/// What to do with the radio while joining
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum JoinAction {
+    Receive,
+    Transmit(Claim),
+    Joined(usize),
+}
+
+pub struct Join {
+    pub state: JoinState,
+    /// Breaks ties between compasses that want the same id. Should be random
+    nonce: u32,
+    /// ids that we heard someone else using or claiming
+    taken: [bool; MAX_PEERS],
+    num_peers: usize,
+    /// the last time segment that we considered claiming in
+    last_claim_segment: Option<u64>,
+}
+
+impl Join {
+    pub fn new(num_peers: usize, nonce: u32, now_ms: u64) -> Self {
+        let listen_ms = (num_peers * num_peers) as u64 * TIME_SEGMENT_MS as u64;
+
+        Self {
+            state: JoinState::Listening {
+                until_ms: now_ms + listen_ms,
+            },
+            nonce,
+            taken: [false; MAX_PEERS],
+            num_peers,
+            last_claim_segment: None,
+        }
+    }
+
+    /// We heard a packet from (or a location of) this peer. Someone already has this id
+    pub fn heard_peer(&mut self, peer_id: usize, now_ms: u64) {
+        if peer_id >= self.num_peers {
+            return;
+        }
+
+        self.taken[peer_id] = true;
+
+        if let JoinState::Claiming {
+            peer_id: claiming, ..
+        } = self.state
+        {
+            if claiming == peer_id {
+                self.claim_another(now_ms);
+            }
+        }
+    }
+
+    /// Another compass wants an id
+    pub fn heard_claim(&mut self, claim: &Claim, now_ms: u64) {
+        if claim.peer_id >= self.num_peers {
+            return;
+        }
+
+        if let JoinState::Claiming { peer_id, .. } = self.state {
+            if peer_id == claim.peer_id {
+                if claim.nonce > self.nonce {
+                    // we win. they will hear our claim and back off
+                    return;
+                }
+
+                // they win. on a tie, we both back off and pick again
+                self.taken[peer_id] = true;
+
+                self.claim_another(now_ms);
+                return;
+            }
+        }
+
+        // don't pick an id that someone else is already after
+        self.taken[claim.peer_id] = true;
+    }
+
+    pub fn poll(&mut self, now_ms: u64) -> JoinAction {
+        match self.state {
+            JoinState::Listening { until_ms } => {
+                if now_ms >= until_ms {
+                    self.claim_another(now_ms);
+                }
+
+                JoinAction::Receive
+            }
+            JoinState::Claiming { peer_id, until_ms } => {
+                if now_ms >= until_ms {
+                    return JoinAction::Joined(peer_id);
+                }
+
+                let segment = now_ms / TIME_SEGMENT_MS as u64;
+                let segment_offset_ms = (now_ms % TIME_SEGMENT_MS as u64) as u32;
+
+                // claim in the quiet at the start of a segment. leave room for the packet to finish in the guard
+                if segment_offset_ms >= GUARD_MS / 2 || self.last_claim_segment == Some(segment) {
+                    return JoinAction::Receive;
+                }
+
+                self.last_claim_segment = Some(segment);
+
+                // only claim in about half of the segments. if two of us always transmitted together, we would never
+                // hear each other
+                if mix(self.nonce, segment) & 1 == 0 {
+                    return JoinAction::Receive;
+                }
+
+                JoinAction::Transmit(Claim {
+                    peer_id,
+                    nonce: self.nonce,
+                })
+            }
+        }
+    }
+
+    /// Pick a free id. If there aren't any, listen some more
+    fn claim_another(&mut self, now_ms: u64) {
+        let num_free = self.taken[..self.num_peers]
+            .iter()
+            .filter(|taken| !**taken)
+            .count();
+
+        if num_free == 0 {
+            // the group is full. maybe someone will leave
+            // TODO: show this on the lights
+            *self = Self::new(self.num_peers, self.nonce, now_ms);
+            return;
+        }
+
+        // pick randomly so that two compasses that joined at the same time probably pick different ids
+        let pick = mix(self.nonce, now_ms) as usize % num_free;
+
+        let peer_id = self.taken[..self.num_peers]
+            .iter()
+            .enumerate()
+            .filter(|(_, taken)| !**taken)
+            .nth(pick)
+            .map(|(peer_id, _)| peer_id)
+            .unwrap();
+
+        self.state = JoinState::Claiming {
+            peer_id,
+            until_ms: now_ms + CLAIM_SEGMENTS * TIME_SEGMENT_MS as u64,
+        };
+        self.last_claim_segment = None;
+    }
+}
+
+/// A cheap hash. The nonce is already random. This just spreads it around
+fn mix(nonce: u32, x: u64) -> u32 {
+    let mut h = nonce as u64 ^ x.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    h ^= h >> 29;
+    h = h.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    h ^= h >> 32;
+    h as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{inject, mock_node, new_node, NOW};
+    use super::super::{Header, Message, MessageKind, MockAir, Radio, Schedule, Slot};
+    use super::*;
+    use crate::timers::ElapsedMs;
+
+    fn claiming(join: &Join) -> Option<usize> {
+        match join.state {
+            JoinState::Claiming { peer_id, .. } => Some(peer_id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_listen_then_claim() {
+        let mut join = Join::new(3, 1234, 0);
+
+        join.heard_peer(0, 1_000);
+        join.heard_peer(2, 2_000);
+
+        assert_eq!(join.poll(17_999), JoinAction::Receive);
+        assert_eq!(claiming(&join), None);
+
+        // 3 peers is 9 time segments
+        join.poll(18_000);
+        assert_eq!(claiming(&join), Some(1));
+
+        // no one objected
+        let until_ms = 18_000 + CLAIM_SEGMENTS * TIME_SEGMENT_MS as u64;
+        assert_eq!(join.poll(until_ms), JoinAction::Joined(1));
+    }
+
+    #[test]
+    fn test_claims_sometimes() {
+        let mut join = Join::new(3, 1234, 0);
+        join.poll(18_000);
+
+        let peer_id = claiming(&join).unwrap();
+
+        let mut claims = 0;
+
+        for segment in 9..(9 + CLAIM_SEGMENTS) {
+            let start_ms = segment * TIME_SEGMENT_MS as u64;
+
+            for ms in (0..TIME_SEGMENT_MS as u64).step_by(10) {
+                if let JoinAction::Transmit(claim) = join.poll(start_ms + ms) {
+                    assert_eq!(claim.peer_id, peer_id);
+                    assert!(ms < GUARD_MS as u64);
+                    claims += 1;
+                }
+            }
+        }
+
+        assert!(claims > 0);
+        assert!(claims < CLAIM_SEGMENTS);
+    }
+
+    #[test]
+    fn test_lower_nonce_wins() {
+        let mut join = Join::new(3, 1234, 0);
+        join.heard_peer(0, 0);
+        join.heard_peer(2, 0);
+        join.poll(18_000);
+        assert_eq!(claiming(&join), Some(1));
+
+        // a higher nonce doesn't bother us
+        join.heard_claim(
+            &Claim {
+                peer_id: 1,
+                nonce: 5678,
+            },
+            19_000,
+        );
+        assert_eq!(claiming(&join), Some(1));
+
+        // a lower nonce does. and there are no more free ids
+        join.heard_claim(
+            &Claim {
+                peer_id: 1,
+                nonce: 12,
+            },
+            19_000,
+        );
+        assert_eq!(claiming(&join), None);
+    }
+
+    #[test]
+    fn test_owner_shows_up() {
+        let mut join = Join::new(4, 1234, 0);
+        join.poll(32_000);
+
+        let first = claiming(&join).unwrap();
+
+        // someone was using this id the whole time. we just didn't hear them
+        join.heard_peer(first, 33_000);
+
+        let second = claiming(&join).unwrap();
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_join() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = new_node(&air, [1; 32], None);
+
+        b.start_join(1234, NOW, 0);
+
+        let mut joined = None;
+
+        // a talks during its turns. b listens, then claims a different id
+        let schedule = Schedule::for_peers(5);
+
+        for now_ms in (0..200_000).step_by(10) {
+            let epoch_seconds = NOW + now_ms / 1000;
+            let ms = now_ms % 1000;
+
+            elapsed_ms.increment_by(10);
+
+            match schedule.slot(2, epoch_seconds, ms) {
+                Slot::Transmit {
+                    time_segment_id,
+                    peer_id,
+                } => a.transmit(&elapsed_ms, epoch_seconds, time_segment_id, peer_id),
+                Slot::Receive => a.try_receive(&elapsed_ms, epoch_seconds),
+                Slot::Sleep => a.sleep(),
+            }
+            .unwrap();
+
+            if let Some(peer_id) = b.join(&elapsed_ms, epoch_seconds, ms).unwrap() {
+                joined = Some(peer_id);
+                break;
+            }
+        }
+
+        let joined = joined.unwrap();
+
+        assert_ne!(joined, 2);
+        assert_eq!(b.data.my_peer_id, Some(joined));
+        assert!(b.joining.is_none());
+        // a heard b's claims
+        assert!(a.data.stats.accepted > 0);
+
+        // joining again would give up the id we just got
+        b.start_join(5678, NOW, 0);
+        assert!(b.joining.is_none());
+        assert_eq!(b.join(&elapsed_ms, NOW, 0), Ok(None));
+    }
+
+    #[test]
+    fn test_only_joining_compasses_claim() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+
+        let header = Header {
+            flags: 0,
+            kind: MessageKind::Claim as u8,
+            tx_peer_id: 3,
+            tx_time: NOW,
+            tx_ms: 0,
+            tx_counter: 0,
+        };
+
+        let claim = Message::Claim(Claim {
+            peer_id: 4,
+            nonce: 1,
+        });
+
+        let mut b = air.radio();
+        a.try_receive(&elapsed_ms, NOW).unwrap();
+        inject(&mut b, &[1; 32], &header, &claim);
+        a.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(a.data.stats.malformed, 1);
+    }
+
+    #[test]
+    fn test_defend_peer_id() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        // the delay goes past the point where elapsed ms wraps
+        elapsed_ms.increment_by(u32::MAX - DEFEND_DELAY_MS / 2);
+
+        let mut a = mock_node(&air, [1; 32], 2);
+
+        let header = Header {
+            flags: 0,
+            kind: MessageKind::Claim as u8,
+            tx_peer_id: UNCONFIGURED_PEER_ID,
+            tx_time: NOW,
+            tx_ms: 0,
+            tx_counter: 0,
+        };
+
+        let claim = Message::Claim(Claim {
+            peer_id: 2,
+            nonce: 1,
+        });
+
+        let mut b = air.radio();
+        a.try_receive(&elapsed_ms, NOW).unwrap();
+        inject(&mut b, &[1; 32], &header, &claim);
+        a.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(a.data.stats.accepted, 1);
+
+        // give the joining compass time to start listening
+        a.try_receive(&elapsed_ms, NOW).unwrap();
+        assert_eq!(air.sent(0), 0);
+
+        elapsed_ms.increment_by(DEFEND_DELAY_MS);
+        b.start_receive().unwrap();
+        a.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(air.sent(0), 1);
+        assert_eq!(air.pending(1), 1);
+    }
+}
@@ -0,0 +1,711 @@
+//! Everything that can be sent between compasses.
+//!
+//! The kind goes in the packet header so that the body can be skipped without decoding it.
+//! Firmware that doesn't know about a kind ignores it, so new kinds only bump the minor protocol version.
+//! Extra bytes at the end of a body are ignored, so new fields can be appended the same way.
+use super::packet::RejectReason;
+use super::wire::{pack_hue_sat, unpack_hue_sat, Reader, WireError, Writer};
+use crate::battery::BatteryStatus;
+use core::convert::TryFrom;
+
+/// Never reuse or renumber these! Old firmware will decode the body as the wrong kind
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[repr(u8)]
+pub enum MessageKind {
+    Location = 1,
+    Pin = 2,
+    TimeSync = 3,
+    Config = 4,
+    Ping = 5,
+    Claim = 6,
+    Beacon = 7,
+    Sos = 8,
+    Status = 9,
+}
+
+impl MessageKind {
+    pub fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            1 => Some(Self::Location),
+            2 => Some(Self::Pin),
+            3 => Some(Self::TimeSync),
+            4 => Some(Self::Config),
+            5 => Some(Self::Ping),
+            6 => Some(Self::Claim),
+            7 => Some(Self::Beacon),
+            8 => Some(Self::Sos),
+            9 => Some(Self::Status),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct PeerLocation {
+    pub peer_id: usize,
+    pub last_updated_at: u32,
+    pub hue: u8,
+    pub sat: u8,
+
+    pub lat: f32,
+    pub lon: f32,
+
+    /// how many other compasses relayed this before it got to us. 0 if we heard it from the peer itself
+    pub hops: u8,
+}
+
+/// A spot on the map that everyone should be able to find. Like camp or the car
+#[derive(Copy, Clone)]
+pub struct PinLocation {
+    /// the peer that dropped this pin
+    pub peer_id: usize,
+    /// each peer can have multiple pins
+    pub pin_id: u8,
+    pub last_updated_at: u32,
+    pub hue: u8,
+    pub sat: u8,
+
+    pub lat: f32,
+    pub lon: f32,
+}
+
+/// The transmitter's best guess at the current time
+#[derive(Copy, Clone)]
+pub struct TimeSync {
+    pub epoch_seconds: u32,
+    /// milliseconds past epoch_seconds
+    pub ms: u16,
+    /// true if this time came from a GPS with a fix
+    pub from_gps: bool,
+}
+
+/// Settings that the transmitter wants everyone to know about. They only ever change the transmitter's own entry
+#[derive(Copy, Clone)]
+pub struct PeerConfig {
+    pub hue: u8,
+    pub sat: u8,
+}
+
+/// A compass that is joining wants a peer id. See `join`
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Claim {
+    pub peer_id: usize,
+    /// if two compasses claim the same id, the lower nonce gets it
+    pub nonce: u32,
+}
+
+/// Sent by compasses without a GPS fix so that everyone can at least see that they are on and in range.
+/// Beacons are never relayed. The transmitter is whoever sent the packet
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Beacon {
+    pub battery: BatteryStatus,
+    /// seconds since the transmitter last had a fix. None if it hasn't had one since it booted
+    pub position_age_s: Option<u16>,
+}
+
+/// Someone needs help. Everyone relays these before anything else and points at the sender
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sos {
+    pub peer_id: usize,
+    /// GPS time when this was last updated. the sender keeps updating it with their newest location
+    pub last_updated_at: u32,
+    /// false once they cancel it. cancels are relayed the same way so that everyone stops
+    pub active: bool,
+    /// (lat, lon) where they were. None if they never had a fix
+    pub position: Option<(f32, f32)>,
+    /// how many other compasses relayed this before it got to us. 0 if we heard it from the peer itself
+    pub hops: u8,
+}
+
+/// `Sos` flags
+const SOS_ACTIVE: u8 = 0b0000_0001;
+const SOS_HAS_POSITION: u8 = 0b0000_0010;
+
+/// Predefined so that they fit in a byte and can be picked with one button.
+/// Never reuse or renumber these either! Old firmware will show the wrong status
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Status {
+    /// nothing to say anymore. this is how a status is taken back
+    Clear,
+    HeadingToCamp,
+    NeedWater,
+    AtStage,
+    Hungry,
+    Resting,
+    /// from newer firmware. we don't know what it means, but we can still show that they said something
+    Other(u8),
+}
+
+impl Status {
+    pub fn from_u8(status: u8) -> Self {
+        match status {
+            0 => Self::Clear,
+            1 => Self::HeadingToCamp,
+            2 => Self::NeedWater,
+            3 => Self::AtStage,
+            4 => Self::Hungry,
+            5 => Self::Resting,
+            x => Self::Other(x),
+        }
+    }
+
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Self::Clear => 0,
+            Self::HeadingToCamp => 1,
+            Self::NeedWater => 2,
+            Self::AtStage => 3,
+            Self::Hungry => 4,
+            Self::Resting => 5,
+            Self::Other(x) => x,
+        }
+    }
+
+    /// The one after this. Pressing a button over and over goes through all of them and back to Clear
+    pub fn next(self) -> Self {
+        match self {
+            Self::Clear => Self::HeadingToCamp,
+            Self::HeadingToCamp => Self::NeedWater,
+            Self::NeedWater => Self::AtStage,
+            Self::AtStage => Self::Hungry,
+            Self::Hungry => Self::Resting,
+            Self::Resting | Self::Other(_) => Self::Clear,
+        }
+    }
+
+    /// For logs
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Clear => "clear",
+            Self::HeadingToCamp => "heading to camp",
+            Self::NeedWater => "need water",
+            Self::AtStage => "at stage",
+            Self::Hungry => "hungry",
+            Self::Resting => "resting",
+            Self::Other(_) => "unknown",
+        }
+    }
+}
+
+/// What a peer wants everyone to know. Relayed like their location
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PeerStatus {
+    pub peer_id: usize,
+    /// GPS time when they picked this status
+    pub last_updated_at: u32,
+    pub status: Status,
+    /// how many other compasses relayed this before it got to us. 0 if we heard it from the peer itself
+    pub hops: u8,
+}
+
+/// Anything that can be drawn on the compass
+pub trait Coordinates {
+    fn lat(&self) -> f32;
+    fn lon(&self) -> f32;
+}
+
+impl Coordinates for PeerLocation {
+    fn lat(&self) -> f32 {
+        self.lat
+    }
+
+    fn lon(&self) -> f32 {
+        self.lon
+    }
+}
+
+impl Coordinates for PinLocation {
+    fn lat(&self) -> f32 {
+        self.lat
+    }
+
+    fn lon(&self) -> f32 {
+        self.lon
+    }
+}
+
+/// (lat, lon)
+impl Coordinates for (f32, f32) {
+    fn lat(&self) -> f32 {
+        self.0
+    }
+
+    fn lon(&self) -> f32 {
+        self.1
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum Message {
+    Location(PeerLocation),
+    Pin(PinLocation),
+    TimeSync(TimeSync),
+    Config(PeerConfig),
+    /// Nothing to say. Just letting everyone know we are here
+    Ping,
+    Claim(Claim),
+    Beacon(Beacon),
+    Sos(Sos),
+    Status(PeerStatus),
+}
+
+impl Message {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            Self::Location(_) => MessageKind::Location,
+            Self::Pin(_) => MessageKind::Pin,
+            Self::TimeSync(_) => MessageKind::TimeSync,
+            Self::Config(_) => MessageKind::Config,
+            Self::Ping => MessageKind::Ping,
+            Self::Claim(_) => MessageKind::Claim,
+            Self::Beacon(_) => MessageKind::Beacon,
+            Self::Sos(_) => MessageKind::Sos,
+            Self::Status(_) => MessageKind::Status,
+        }
+    }
+
+    /// Serialize just the body. The kind goes in the header.
+    /// Returns the number of bytes written
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, WireError> {
+        let mut w = Writer::new(buf);
+
+        match self {
+            Self::Location(x) => {
+                w.u8(peer_id_u8(x.peer_id)?)?;
+                w.u32(x.last_updated_at)?;
+                w.u8(pack_hue_sat(x.hue, x.sat))?;
+                w.degrees(x.lat)?;
+                w.degrees(x.lon)?;
+                w.u8(x.hops)?;
+            }
+            Self::Pin(x) => {
+                w.u8(peer_id_u8(x.peer_id)?)?;
+                w.u8(x.pin_id)?;
+                w.u32(x.last_updated_at)?;
+                w.u8(pack_hue_sat(x.hue, x.sat))?;
+                w.degrees(x.lat)?;
+                w.degrees(x.lon)?;
+            }
+            Self::TimeSync(x) => {
+                w.u32(x.epoch_seconds)?;
+                w.u16(x.ms)?;
+                w.u8(x.from_gps as u8)?;
+            }
+            Self::Config(x) => {
+                // full precision here since this is where peers get their real colors
+                w.u8(x.hue)?;
+                w.u8(x.sat)?;
+            }
+            Self::Ping => {}
+            Self::Claim(x) => {
+                w.u8(peer_id_u8(x.peer_id)?)?;
+                w.u32(x.nonce)?;
+            }
+            Self::Beacon(x) => {
+                w.u8((x.battery == BatteryStatus::Low) as u8)?;
+                // u16::MAX means we never had a fix. anything older than that is about as lost as never
+                w.u16(
+                    x.position_age_s
+                        .map_or(u16::MAX, |age| age.min(u16::MAX - 1)),
+                )?;
+            }
+            Self::Sos(x) => {
+                let mut flags = 0;
+
+                if x.active {
+                    flags |= SOS_ACTIVE;
+                }
+
+                if x.position.is_some() {
+                    flags |= SOS_HAS_POSITION;
+                }
+
+                w.u8(peer_id_u8(x.peer_id)?)?;
+                w.u32(x.last_updated_at)?;
+                w.u8(flags)?;
+                w.u8(x.hops)?;
+
+                if let Some((lat, lon)) = x.position {
+                    w.degrees(lat)?;
+                    w.degrees(lon)?;
+                }
+            }
+            Self::Status(x) => {
+                w.u8(peer_id_u8(x.peer_id)?)?;
+                w.u32(x.last_updated_at)?;
+                w.u8(x.status.as_u8())?;
+                w.u8(x.hops)?;
+            }
+        }
+
+        Ok(w.bytes_written())
+    }
+
+    pub fn decode(kind: u8, body: &[u8]) -> Result<Self, RejectReason> {
+        let kind = MessageKind::from_u8(kind).ok_or(RejectReason::UnknownKind)?;
+
+        Self::decode_body(kind, &mut Reader::new(body)).map_err(|_| RejectReason::Malformed)
+    }
+
+    fn decode_body(kind: MessageKind, r: &mut Reader) -> Result<Self, WireError> {
+        let message = match kind {
+            MessageKind::Location => {
+                let peer_id = r.u8()? as usize;
+                let last_updated_at = r.u32()?;
+                let (hue, sat) = unpack_hue_sat(r.u8()?);
+                let lat = r.degrees()?;
+                let lon = r.degrees()?;
+                let hops = r.u8()?;
+
+                Self::Location(PeerLocation {
+                    peer_id,
+                    last_updated_at,
+                    hue,
+                    sat,
+                    lat,
+                    lon,
+                    hops,
+                })
+            }
+            MessageKind::Pin => {
+                let peer_id = r.u8()? as usize;
+                let pin_id = r.u8()?;
+                let last_updated_at = r.u32()?;
+                let (hue, sat) = unpack_hue_sat(r.u8()?);
+                let lat = r.degrees()?;
+                let lon = r.degrees()?;
+
+                Self::Pin(PinLocation {
+                    peer_id,
+                    pin_id,
+                    last_updated_at,
+                    hue,
+                    sat,
+                    lat,
+                    lon,
+                })
+            }
+            MessageKind::TimeSync => Self::TimeSync(TimeSync {
+                epoch_seconds: r.u32()?,
+                ms: r.u16()?,
+                from_gps: r.u8()? != 0,
+            }),
+            MessageKind::Config => Self::Config(PeerConfig {
+                hue: r.u8()?,
+                sat: r.u8()?,
+            }),
+            MessageKind::Ping => Self::Ping,
+            MessageKind::Claim => Self::Claim(Claim {
+                peer_id: r.u8()? as usize,
+                nonce: r.u32()?,
+            }),
+            MessageKind::Beacon => {
+                let battery = if r.u8()? != 0 {
+                    BatteryStatus::Low
+                } else {
+                    BatteryStatus::Ok
+                };
+
+                let position_age_s = match r.u16()? {
+                    u16::MAX => None,
+                    age => Some(age),
+                };
+
+                Self::Beacon(Beacon {
+                    battery,
+                    position_age_s,
+                })
+            }
+            MessageKind::Sos => {
+                let peer_id = r.u8()? as usize;
+                let last_updated_at = r.u32()?;
+                let flags = r.u8()?;
+                let hops = r.u8()?;
+
+                let position = if flags & SOS_HAS_POSITION != 0 {
+                    Some((r.degrees()?, r.degrees()?))
+                } else {
+                    None
+                };
+
+                Self::Sos(Sos {
+                    peer_id,
+                    last_updated_at,
+                    active: flags & SOS_ACTIVE != 0,
+                    position,
+                    hops,
+                })
+            }
+            MessageKind::Status => Self::Status(PeerStatus {
+                peer_id: r.u8()? as usize,
+                last_updated_at: r.u32()?,
+                status: Status::from_u8(r.u8()?),
+                hops: r.u8()?,
+            }),
+        };
+
+        Ok(message)
+    }
+}
+
+/// Peer ids are one byte on the air. A bigger one is a bug. Sending it cut off would put it on some other peer
+fn peer_id_u8(peer_id: usize) -> Result<u8, WireError> {
+    u8::try_from(peer_id).map_err(|_| WireError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::crypto::MAC_LEN;
+    use super::super::packet::HEADER_LEN;
+    use super::*;
+
+    #[test]
+    fn test_location_round_trip() {
+        let message = Message::Location(PeerLocation {
+            peer_id: 2,
+            last_updated_at: 1_000,
+            hue: 100,
+            sat: 255,
+            lat: 37.7749,
+            lon: -122.4194,
+            hops: 2,
+        });
+
+        let mut buf = [0u8; 255];
+        let n = message.encode(&mut buf).unwrap();
+
+        match Message::decode(message.kind() as u8, &buf[..n]) {
+            Ok(Message::Location(location)) => {
+                assert_eq!(location.peer_id, 2);
+                assert_eq!(location.last_updated_at, 1_000);
+                assert_eq!(location.hue, 96);
+                assert_eq!(location.sat, 255);
+                assert!((location.lat - 37.7749).abs() < 0.00001);
+                assert!((location.lon - -122.4194).abs() < 0.00001);
+                assert_eq!(location.hops, 2);
+            }
+            _ => panic!("wrong message"),
+        }
+    }
+
+    #[test]
+    fn test_location_size_budget() {
+        let message = Message::Location(PeerLocation {
+            peer_id: 2,
+            last_updated_at: 1_000,
+            hue: 100,
+            sat: 255,
+            lat: 37.7749,
+            lon: -122.4194,
+            hops: 0,
+        });
+
+        let mut buf = [0u8; 255];
+        let n = message.encode(&mut buf).unwrap();
+
+        // locations are most of our traffic. every byte here costs airtime
+        assert_eq!(n, 15);
+        assert!(HEADER_LEN + n + MAC_LEN <= 44);
+    }
+
+    #[test]
+    fn test_truncated_body() {
+        let message = Message::Pin(PinLocation {
+            peer_id: 2,
+            pin_id: 1,
+            last_updated_at: 1_000,
+            hue: 100,
+            sat: 255,
+            lat: 37.7749,
+            lon: -122.4194,
+        });
+
+        let mut buf = [0u8; 255];
+        let n = message.encode(&mut buf).unwrap();
+
+        assert_eq!(
+            Message::decode(MessageKind::Pin as u8, &buf[..n - 1]).err(),
+            Some(RejectReason::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_peer_id_too_big() {
+        let message = Message::Status(PeerStatus {
+            peer_id: 256,
+            last_updated_at: 1_000,
+            status: Status::NeedWater,
+            hops: 0,
+        });
+
+        let mut buf = [0u8; 255];
+
+        assert_eq!(message.encode(&mut buf), Err(WireError));
+    }
+
+    #[test]
+    fn test_ping_has_no_body() {
+        let mut buf = [0u8; 255];
+
+        assert_eq!(Message::Ping.encode(&mut buf).unwrap(), 0);
+
+        assert!(matches!(
+            Message::decode(MessageKind::Ping as u8, &[]),
+            Ok(Message::Ping)
+        ));
+    }
+
+    #[test]
+    fn test_beacon_round_trip() {
+        let mut buf = [0u8; 255];
+
+        for beacon in [
+            Beacon {
+                battery: BatteryStatus::Low,
+                position_age_s: Some(90),
+            },
+            Beacon {
+                battery: BatteryStatus::Ok,
+                position_age_s: None,
+            },
+        ]
+        .iter()
+        {
+            let n = Message::Beacon(*beacon).encode(&mut buf).unwrap();
+
+            assert_eq!(n, 3);
+
+            match Message::decode(MessageKind::Beacon as u8, &buf[..n]) {
+                Ok(Message::Beacon(decoded)) => assert_eq!(decoded, *beacon),
+                _ => panic!("wrong message"),
+            }
+        }
+
+        // a really old fix is still a fix
+        let n = Message::Beacon(Beacon {
+            battery: BatteryStatus::Ok,
+            position_age_s: Some(u16::MAX),
+        })
+        .encode(&mut buf)
+        .unwrap();
+
+        match Message::decode(MessageKind::Beacon as u8, &buf[..n]) {
+            Ok(Message::Beacon(decoded)) => assert_eq!(decoded.position_age_s, Some(u16::MAX - 1)),
+            _ => panic!("wrong message"),
+        }
+    }
+
+    #[test]
+    fn test_sos_round_trip() {
+        let mut buf = [0u8; 255];
+
+        let message = Message::Sos(Sos {
+            peer_id: 2,
+            last_updated_at: 1_000,
+            active: true,
+            position: Some((37.7749, -122.4194)),
+            hops: 1,
+        });
+
+        let n = message.encode(&mut buf).unwrap();
+
+        match Message::decode(MessageKind::Sos as u8, &buf[..n]) {
+            Ok(Message::Sos(sos)) => {
+                assert_eq!(sos.peer_id, 2);
+                assert_eq!(sos.last_updated_at, 1_000);
+                assert!(sos.active);
+                assert_eq!(sos.hops, 1);
+
+                let (lat, lon) = sos.position.unwrap();
+                assert!((lat - 37.7749).abs() < 0.00001);
+                assert!((lon - -122.4194).abs() < 0.00001);
+            }
+            _ => panic!("wrong message"),
+        }
+
+        // a cancel from someone who never had a fix is even smaller
+        let message = Message::Sos(Sos {
+            peer_id: 2,
+            last_updated_at: 1_001,
+            active: false,
+            position: None,
+            hops: 0,
+        });
+
+        let n = message.encode(&mut buf).unwrap();
+
+        assert_eq!(n, 7);
+
+        match Message::decode(MessageKind::Sos as u8, &buf[..n]) {
+            Ok(Message::Sos(sos)) => {
+                assert!(!sos.active);
+                assert_eq!(sos.position, None);
+            }
+            _ => panic!("wrong message"),
+        }
+    }
+
+    #[test]
+    fn test_status_round_trip() {
+        let mut buf = [0u8; 255];
+
+        for status in [Status::NeedWater, Status::Clear, Status::Other(200)].iter() {
+            let message = Message::Status(PeerStatus {
+                peer_id: 2,
+                last_updated_at: 1_000,
+                status: *status,
+                hops: 1,
+            });
+
+            let n = message.encode(&mut buf).unwrap();
+
+            assert_eq!(n, 7);
+
+            match Message::decode(MessageKind::Status as u8, &buf[..n]) {
+                Ok(Message::Status(peer_status)) => {
+                    assert_eq!(peer_status.peer_id, 2);
+                    assert_eq!(peer_status.last_updated_at, 1_000);
+                    assert_eq!(peer_status.status, *status);
+                    assert_eq!(peer_status.hops, 1);
+                }
+                _ => panic!("wrong message"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_status_cycle() {
+        let mut status = Status::Clear;
+
+        for _ in 0..5 {
+            status = status.next();
+            assert_ne!(status, Status::Clear);
+            assert_eq!(Status::from_u8(status.as_u8()), status);
+        }
+
+        assert_eq!(status.next(), Status::Clear);
+    }
+
+    #[test]
+    fn test_config_round_trip() {
+        let mut buf = [0u8; 255];
+
+        let n = Message::Config(PeerConfig { hue: 160, sat: 7 })
+            .encode(&mut buf)
+            .unwrap();
+
+        match Message::decode(MessageKind::Config as u8, &buf[..n]) {
+            // not packed like locations. this is where peers get their real colors
+            Ok(Message::Config(config)) => assert_eq!((config.hue, config.sat), (160, 7)),
+            _ => panic!("wrong message"),
+        }
+    }
+
+    #[test]
+    fn test_unknown_kind() {
+        assert_eq!(
+            Message::decode(200, &[]).err(),
+            Some(RejectReason::UnknownKind)
+        );
+    }
+}
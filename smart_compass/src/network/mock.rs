@@ -0,0 +1,291 @@
+//! An in-memory radio for running the protocol on the host.
+//!
+//! Every radio made from the same `MockAir` can hear each other. Links can be cut or given a different signal strength.
+//! Transmissions arrive instantly, but only at radios that are receiving at the time (with matching settings). Just
+//! like the real thing. A radio can be wedged to act like a flaky SPI bus.
+use super::{Radio, RadioSettings, RxInfo};
+use alloc::collections::VecDeque;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+/// Reading a packet that isn't there or that doesn't fit in the buffer. Or anything at all on a wedged radio
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MockError;
+
+#[derive(Copy, Clone, PartialEq)]
+enum MockMode {
+    Sleep,
+    Transmit,
+    Receive,
+}
+
+struct MockNode {
+    mode: MockMode,
+    inbox: VecDeque<(Vec<u8>, RxInfo)>,
+    sent: u32,
+    settings: RadioSettings,
+    /// every call fails until the radio is reset
+    wedged: bool,
+}
+
+impl MockNode {
+    /// The coding rate is in the packet's header, so it doesn't have to match
+    fn can_hear(&self, settings: &RadioSettings) -> bool {
+        self.mode == MockMode::Receive
+            && self.settings.frequency_hz == settings.frequency_hz
+            && self.settings.spreading_factor == settings.spreading_factor
+            && self.settings.bandwidth_hz == settings.bandwidth_hz
+            && self.settings.sync_word == settings.sync_word
+    }
+}
+
+#[derive(Default)]
+struct MockAirInner {
+    nodes: Vec<MockNode>,
+    /// links[from][to]. None if `to` can't hear `from`
+    links: Vec<Vec<Option<RxInfo>>>,
+}
+
+/// The shared medium that all of the mock radios transmit over
+#[derive(Clone, Default)]
+pub struct MockAir(Rc<RefCell<MockAirInner>>);
+
+impl MockAir {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a radio. It can hear (and be heard by) every other radio until `disconnect` is called
+    pub fn radio(&self) -> MockRadio {
+        let mut inner = self.0.borrow_mut();
+
+        let id = inner.nodes.len();
+
+        let good_signal = Some(RxInfo {
+            rssi: -60,
+            snr: Some(10),
+        });
+
+        for from in inner.links.iter_mut() {
+            from.push(good_signal);
+        }
+
+        let mut links = Vec::with_capacity(id + 1);
+        links.resize(id, good_signal);
+        // a radio can't hear itself
+        links.push(None);
+
+        inner.links.push(links);
+
+        inner.nodes.push(MockNode {
+            mode: MockMode::Sleep,
+            inbox: VecDeque::new(),
+            sent: 0,
+            settings: RadioSettings::default(),
+            wedged: false,
+        });
+
+        MockRadio {
+            air: self.clone(),
+            id,
+        }
+    }
+
+    /// `to` will hear `from` with this signal. This is one way!
+    pub fn connect(&self, from: usize, to: usize, info: RxInfo) {
+        self.0.borrow_mut().links[from][to] = Some(info);
+    }
+
+    /// `to` will no longer hear `from`. This is one way!
+    pub fn disconnect(&self, from: usize, to: usize) {
+        self.0.borrow_mut().links[from][to] = None;
+    }
+
+    /// How many packets this radio has transmitted
+    pub fn sent(&self, id: usize) -> u32 {
+        self.0.borrow().nodes[id].sent
+    }
+
+    /// Every call to this radio fails until it is reset
+    pub fn wedge(&self, id: usize) {
+        self.0.borrow_mut().nodes[id].wedged = true;
+    }
+
+    /// How many packets are waiting for this radio to read them
+    pub fn pending(&self, id: usize) -> usize {
+        self.0.borrow().nodes[id].inbox.len()
+    }
+}
+
+pub struct MockRadio {
+    air: MockAir,
+    id: usize,
+}
+
+impl MockRadio {
+    /// The index of this radio in its `MockAir`
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    fn set_mode(&mut self, mode: MockMode) {
+        self.air.0.borrow_mut().nodes[self.id].mode = mode;
+    }
+
+    fn check_wedged(&self) -> Result<(), MockError> {
+        if self.air.0.borrow().nodes[self.id].wedged {
+            return Err(MockError);
+        }
+
+        Ok(())
+    }
+}
+
+impl Radio for MockRadio {
+    type Error = MockError;
+
+    fn reset(&mut self) -> Result<(), Self::Error> {
+        let mut inner = self.air.0.borrow_mut();
+        let node = &mut inner.nodes[self.id];
+
+        // a reset forgets everything. it needs to be configured again
+        node.wedged = false;
+        node.mode = MockMode::Sleep;
+        node.inbox.clear();
+        node.settings = RadioSettings::default();
+
+        Ok(())
+    }
+
+    fn configure(&mut self, settings: &RadioSettings) -> Result<(), Self::Error> {
+        self.check_wedged()?;
+
+        self.air.0.borrow_mut().nodes[self.id].settings = *settings;
+
+        Ok(())
+    }
+
+    fn start_transmit(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.check_wedged()?;
+
+        let mut inner = self.air.0.borrow_mut();
+        let inner = &mut *inner;
+
+        inner.nodes[self.id].mode = MockMode::Transmit;
+        inner.nodes[self.id].sent += 1;
+        // the real radio transmits out of the same FIFO that it receives into
+        inner.nodes[self.id].inbox.clear();
+
+        let settings = inner.nodes[self.id].settings;
+
+        for (to, link) in inner.links[self.id].iter().enumerate() {
+            if let Some(info) = link {
+                let node = &mut inner.nodes[to];
+
+                if node.can_hear(&settings) {
+                    node.inbox.push_back((data.to_vec(), *info));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_transmit(&mut self) -> Result<bool, Self::Error> {
+        self.check_wedged()?;
+
+        // transmissions are instant
+        Ok(true)
+    }
+
+    fn start_receive(&mut self) -> Result<(), Self::Error> {
+        self.check_wedged()?;
+
+        self.set_mode(MockMode::Receive);
+
+        Ok(())
+    }
+
+    fn check_receive(&mut self, _restart: bool) -> Result<bool, Self::Error> {
+        self.check_wedged()?;
+
+        Ok(!self.air.0.borrow().nodes[self.id].inbox.is_empty())
+    }
+
+    fn get_received(&mut self, info: &mut RxInfo, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.check_wedged()?;
+
+        let mut inner = self.air.0.borrow_mut();
+
+        let (data, rx_info) = inner.nodes[self.id].inbox.pop_front().ok_or(MockError)?;
+
+        if data.len() > buf.len() {
+            return Err(MockError);
+        }
+
+        buf[..data.len()].copy_from_slice(&data);
+        *info = rx_info;
+
+        Ok(data.len())
+    }
+
+    fn sleep(&mut self) -> Result<(), Self::Error> {
+        self.check_wedged()?;
+
+        let mut inner = self.air.0.borrow_mut();
+
+        // the real radio clears its FIFO when it goes to sleep
+        inner.nodes[self.id].mode = MockMode::Sleep;
+        inner.nodes[self.id].inbox.clear();
+
+        Ok(())
+    }
+
+    fn silicon_version(&mut self) -> Result<u8, Self::Error> {
+        self.check_wedged()?;
+
+        // what a real SX1276 says
+        Ok(0x12)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{mock_node, NOW};
+    use super::*;
+    use crate::timers::ElapsedMs;
+
+    #[test]
+    fn test_sleeping_radio_hears_nothing() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        b.sleep().unwrap();
+
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+
+        assert_eq!(air.pending(1), 0);
+    }
+
+    #[test]
+    fn test_disconnected() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        air.disconnect(0, 1);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.stats.received, 0);
+    }
+}
@@ -0,0 +1,2162 @@
+mod airtime;
+mod clock;
+mod crypto;
+mod duty_cycle;
+mod error;
+mod join;
+mod message;
+#[cfg(any(test, feature = "mock"))]
+mod mock;
+mod packet;
+mod radio;
+mod rx_queue;
+mod schedule;
+mod settings;
+mod sx127x;
+#[cfg(test)]
+mod test_util;
+mod wire;
+
+pub use self::airtime::{airtime_ms, airtime_us};
+pub use self::clock::{NetworkClock, TimeSource, DRIFT_WINDOW_MS, SYNC_TIMEOUT_MS};
+pub use self::crypto::{NetworkHash, NetworkKeys, NetworkSecret, MAC_LEN, NETWORK_SECRET_LEN};
+pub use self::duty_cycle::{RadioTime, DUTY_CYCLE_WINDOW_MS};
+pub use self::error::NetworkError;
+pub use self::join::{
+    Join, JoinAction, JoinState, CLAIM_SEGMENTS, DEFEND_DELAY_MS, UNCONFIGURED_PEER_ID,
+};
+pub use self::message::{
+    Beacon, Claim, Coordinates, Message, MessageKind, PeerConfig, PeerLocation, PeerStatus,
+    PinLocation, Sos, Status, TimeSync,
+};
+#[cfg(any(test, feature = "mock"))]
+pub use self::mock::{MockAir, MockError, MockRadio};
+pub use self::packet::{
+    Header, RejectReason, FLAG_ENCRYPTED, MAX_PACKET_LEN, PROTOCOL_MAJOR, PROTOCOL_MINOR,
+    PROTOCOL_VERSION,
+};
+pub use self::radio::{Radio, RxInfo};
+pub use self::rx_queue::{rx_queue, RxConsumer, RxPacket, RxProducer, RxQueue, RxQueueLen};
+pub use self::schedule::{
+    Schedule, Slot, BEACON_PERIOD_MS, GUARD_MS, LOW_BATTERY_BROADCAST_EVERY, TIME_SEGMENT_MS,
+    TRANSMIT_WINDOW_MS,
+};
+pub use self::settings::{RadioSettings, Region, SettingsError, LORAWAN_SYNC_WORD};
+pub use self::sx127x::{new_sx127x, MyRadio};
+
+use crate::battery::BatteryStatus;
+use crate::config::ConfigError;
+use crate::{MAX_PEERS, MAX_PINS};
+// use cortex_m_semihosting::hprintln;
+use crate::timers::ElapsedMs;
+use yanp::parse::GpsPosition;
+
+/// Packets sent longer ago than this are dropped. This keeps recorded packets from being replayed later
+/// TODO: tune this. GPS time between peers should be very close, but transmitting takes a while
+pub const MAX_PACKET_AGE_S: u32 = 10;
+
+/// Locations that were relayed this many times are not relayed again. This keeps a location from bouncing around forever
+/// TODO: tune this. more hops reach farther, but take more turns to get there
+pub const MAX_RELAY_HOPS: u8 = 3;
+
+/// SOS messages go farther than locations. Someone out of range might be the closest to help
+/// TODO: tune this
+pub const MAX_SOS_RELAY_HOPS: u8 = 8;
+
+/// Other peers' locations older than this are not relayed. Ours are always sent
+/// Each hop can take a whole schedule cycle (`num_peers * num_peers` time segments), so this has to be pretty long
+pub const MAX_RELAY_AGE_S: u32 = 30 * 60;
+
+/// Active SOS messages older than this are not relayed either. Someone who still needs help keeps sending theirs, so
+/// this only stops an SOS from a compass that died or walked away from everyone
+/// TODO: tune this
+pub const MAX_SOS_RELAY_AGE_S: u32 = 2 * 60 * 60;
+
+/// If we haven't heard a peer directly in this long, our link to them is stale. They might still be relayed
+/// Everyone transmits at least once every `num_peers` time segments, so this is a few turns with a full group
+/// TODO: tune this
+pub const LINK_STALE_MS: u32 = 60_000;
+
+/// Links quieter than this are weak. The SX127x can hear down to about -120dBm at our settings
+/// TODO: tune this
+pub const WEAK_LINK_RSSI: i16 = -110;
+
+/// Links noisier than this are weak. LoRa can receive below the noise floor, but not by much more than this
+pub const WEAK_LINK_SNR: i16 = -7;
+
+/// Positions older than this are stale. Big groups wait longer. See `PeerAgeLimits::for_peers`
+/// TODO: tune this
+pub const MIN_STALE_AFTER_S: u32 = 60;
+
+/// Positions older than this are lost. Nobody should walk toward them
+pub const MIN_LOST_AFTER_S: u32 = 5 * 60;
+
+/// Someone could have walked a long way in this time, no matter how big the group is. A full group's cycle would
+/// otherwise keep positions fresh for most of an hour
+/// TODO: tune this
+pub const MAX_STALE_AFTER_S: u32 = 10 * 60;
+pub const MAX_LOST_AFTER_S: u32 = 15 * 60;
+
+/// The tx_time of packets sent by compasses that don't know the time. Only beacons are sent like this
+pub const NO_TX_TIME: u32 = 0;
+
+#[derive(PartialEq)]
+enum Mode {
+    Sleep,
+    Transmit,
+    Receive,
+    /// a radio call failed. we don't know what the radio is doing until we tell it again
+    Unknown,
+}
+
+/// the usize is the broadcasted_at_id that this was last broadcast at (None if it changed since then).
+/// i don't love this pattern, but it keeps us from broadcasting a message multiple times in a short timespan
+pub type PeerLocations = [Option<(PeerLocation, Option<usize>)>; MAX_PEERS];
+
+/// Same as PeerLocations, but not indexed by peer_id
+pub type PinLocations = [Option<(PinLocation, Option<usize>)>; MAX_PINS];
+
+/// Same as PeerLocations. Everyone has at most one SOS (or the cancel for it)
+pub type PeerSos = [Option<(Sos, Option<usize>)>; MAX_PEERS];
+
+/// Same as PeerLocations
+pub type PeerStatuses = [Option<(PeerStatus, Option<usize>)>; MAX_PEERS];
+
+#[derive(Default)]
+pub struct NetworkStats {
+    pub received: u32,
+    pub accepted: u32,
+    pub malformed: u32,
+    pub bad_mac: u32,
+    pub wrong_network: u32,
+    pub stale: u32,
+    pub replayed: u32,
+    pub unknown_version: u32,
+    pub unknown_kind: u32,
+    /// packets that we didn't send because the region's duty cycle limit was used up
+    pub duty_cycle_limited: u32,
+    /// packets that the radio's interrupt dropped because the idle loop didn't empty the queue in time
+    pub rx_queue_full: u32,
+}
+
+impl NetworkStats {
+    pub fn reject(&mut self, reason: RejectReason) {
+        let counter = match reason {
+            RejectReason::Malformed => &mut self.malformed,
+            RejectReason::BadMac => &mut self.bad_mac,
+            RejectReason::WrongNetwork => &mut self.wrong_network,
+            RejectReason::Stale => &mut self.stale,
+            RejectReason::Replayed => &mut self.replayed,
+            RejectReason::UnknownVersion => &mut self.unknown_version,
+            RejectReason::UnknownKind => &mut self.unknown_kind,
+        };
+
+        *counter = counter.saturating_add(1);
+    }
+}
+
+/// How well we hear a peer. Only packets that they transmitted themselves count. Relays don't tell us anything
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct LinkStats {
+    /// from the last packet
+    pub rssi: i16,
+    /// from the last packet
+    pub snr: Option<i16>,
+    /// accepted packets
+    pub received: u32,
+    /// `ElapsedMs::now` when we last heard them
+    pub last_heard_ms: u32,
+}
+
+impl LinkStats {
+    pub fn heard(&mut self, info: &RxInfo, now: u32) {
+        self.rssi = info.rssi;
+        self.snr = info.snr;
+        self.received = self.received.saturating_add(1);
+        self.last_heard_ms = now;
+    }
+
+    /// `now` is `ElapsedMs::now`
+    pub fn is_stale(&self, now: u32) -> bool {
+        now.wrapping_sub(self.last_heard_ms) > LINK_STALE_MS
+    }
+
+    /// True if the last packet barely made it. We will probably miss some of their packets
+    pub fn is_weak(&self) -> bool {
+        self.rssi < WEAK_LINK_RSSI || matches!(self.snr, Some(snr) if snr < WEAK_LINK_SNR)
+    }
+}
+
+/// How old a peer's position is
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PeerAge {
+    Fresh,
+    /// they are probably still close to here, but they might have moved
+    Stale,
+    /// we lost contact. this is just where they were a long time ago
+    Lost,
+}
+
+/// When positions become stale and then lost. Ages are from the GPS time the position was taken at. Not from when we
+/// heard about it. A relayed position might have been old before it got to us
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PeerAgeLimits {
+    pub stale_after_s: u32,
+    pub lost_after_s: u32,
+}
+
+impl PeerAgeLimits {
+    /// Everyone sends their own location once per schedule cycle, and each relay can take another cycle.
+    /// Bigger groups have longer cycles, so their positions get older before they are replaced. Up to a point
+    pub fn for_peers(num_peers: usize) -> Self {
+        let cycle_s = (Schedule::for_peers(num_peers).cycle_ms() / 1000) as u32;
+
+        Self {
+            stale_after_s: MAX_STALE_AFTER_S.min(MIN_STALE_AFTER_S.max(cycle_s * 2)),
+            lost_after_s: MAX_LOST_AFTER_S.min(MIN_LOST_AFTER_S.max(cycle_s * 6)),
+        }
+    }
+
+    pub fn age(&self, last_updated_at: u32, now_epoch_seconds: u32) -> PeerAge {
+        let age_s = now_epoch_seconds.saturating_sub(last_updated_at);
+
+        if age_s > self.lost_after_s {
+            PeerAge::Lost
+        } else if age_s > self.stale_after_s {
+            PeerAge::Stale
+        } else {
+            PeerAge::Fresh
+        }
+    }
+}
+
+impl Default for PeerAgeLimits {
+    fn default() -> Self {
+        Self {
+            stale_after_s: MIN_STALE_AFTER_S,
+            lost_after_s: MIN_LOST_AFTER_S,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct NetworkData {
+    /// how many peers are in our group. peer ids are 0..num_peers
+    pub num_peers: usize,
+    /// None until we have joined the group
+    pub my_peer_id: Option<usize>,
+    pub my_hue: u8,
+    pub my_saturation: u8,
+    pub network_hash: NetworkHash,
+    pub peer_locations: PeerLocations,
+    pub pin_locations: PinLocations,
+    pub sos: PeerSos,
+    pub peer_statuses: PeerStatuses,
+    /// the (tx_time, tx_ms) of the newest packet received from each transmitting peer
+    pub last_received: [Option<(u32, u32)>; MAX_PEERS],
+    /// how well we hear each peer. None if we have never heard them directly
+    pub links: [Option<LinkStats>; MAX_PEERS],
+    /// what time it is on the network
+    pub clock: NetworkClock,
+    /// the time segment that we last broadcast our time in
+    time_sync_broadcasted_at: Option<usize>,
+    /// the last beacon from each peer. `links` has when we heard it
+    pub beacons: [Option<Beacon>; MAX_PEERS],
+    /// the (tx_ms, received_at) of the newest beacon sent without the time by each peer. See `check_beacon_replay`
+    pub last_beacon_received: [Option<(u32, u32)>; MAX_PEERS],
+    /// the time segment that we last broadcast our beacon in
+    beacon_broadcasted_at: Option<usize>,
+    pub stats: NetworkStats,
+    /// how long the radio has been transmitting and receiving
+    pub radio_time: RadioTime,
+    /// when peers' positions are too old to trust
+    pub peer_age_limits: PeerAgeLimits,
+}
+
+impl NetworkData {
+    /// Peer ids come from the radio. Check them before using them as an index
+    pub fn is_peer(&self, peer_id: usize) -> bool {
+        peer_id < self.num_peers
+    }
+
+    /// Where a peer was last. None if we don't know
+    pub fn peer_location(&self, peer_id: usize) -> Option<&PeerLocation> {
+        let (location, _) = self.peer_locations.get(peer_id)?.as_ref()?;
+
+        Some(location)
+    }
+
+    /// How old a peer's position is. None if we don't know where they are
+    pub fn peer_age(&self, peer_id: usize, now_epoch_seconds: u32) -> Option<PeerAge> {
+        let location = self.peer_location(peer_id)?;
+
+        Some(
+            self.peer_age_limits
+                .age(location.last_updated_at, now_epoch_seconds),
+        )
+    }
+
+    /// Make sure a packet is recent and newer than anything else we have received from its transmitter.
+    /// Without this, a recorded packet could be replayed after a reboot (when `peer_locations` is empty)
+    pub fn check_replay(
+        &mut self,
+        tx_peer_id: usize,
+        tx_time: u32,
+        tx_ms: u32,
+        now_epoch_seconds: u32,
+    ) -> Result<(), RejectReason> {
+        if !self.is_peer(tx_peer_id) {
+            return Err(RejectReason::Malformed);
+        }
+
+        check_age(tx_time, now_epoch_seconds)?;
+
+        if let Some(last_received) = self.last_received[tx_peer_id] {
+            // tx_ms resets when the peer reboots, but tx_time will have moved forward
+            if last_received >= (tx_time, tx_ms) {
+                return Err(RejectReason::Replayed);
+            }
+        }
+
+        self.last_received[tx_peer_id] = Some((tx_time, tx_ms));
+
+        Ok(())
+    }
+
+    /// Beacons from compasses that don't know the time can't go through `check_replay`. Their tx_ms has to keep going up
+    /// instead. A lower tx_ms means that the peer rebooted. That's only believable if they haven't been up for longer
+    /// than it has been since we last heard them.
+    ///
+    /// A recording can still be played back once the real compass goes quiet. But only as far as the recording goes.
+    /// Then it has to wait as long as the recorded compass had been up before it can start over.
+    /// `received_at` is `ElapsedMs::now`
+    pub fn check_beacon_replay(
+        &mut self,
+        tx_peer_id: usize,
+        tx_ms: u32,
+        received_at: u32,
+    ) -> Result<(), RejectReason> {
+        if !self.is_peer(tx_peer_id) {
+            return Err(RejectReason::Malformed);
+        }
+
+        if let Some((last_tx_ms, last_received_at)) = self.last_beacon_received[tx_peer_id] {
+            let rebooted = tx_ms <= received_at.wrapping_sub(last_received_at);
+
+            if tx_ms <= last_tx_ms && !rebooted {
+                return Err(RejectReason::Replayed);
+            }
+        }
+
+        self.last_beacon_received[tx_peer_id] = Some((tx_ms, received_at));
+
+        Ok(())
+    }
+
+    /// Save a new or moved pin. If the table is full, the oldest pin is replaced.
+    /// Returns false if we already have this pin (or a newer version of it)
+    pub fn save_pin(&mut self, pin: PinLocation) -> bool {
+        let mut replace_i = None;
+        let mut oldest_updated_at = u32::MAX;
+
+        for (i, old_pin) in self.pin_locations.iter().enumerate() {
+            match old_pin {
+                Some((old_pin, _))
+                    if old_pin.peer_id == pin.peer_id && old_pin.pin_id == pin.pin_id =>
+                {
+                    if old_pin.last_updated_at >= pin.last_updated_at {
+                        // we already have this pin (or a newer one)
+                        return false;
+                    }
+
+                    replace_i = Some(i);
+                    break;
+                }
+                Some((old_pin, _)) => {
+                    if old_pin.last_updated_at < oldest_updated_at {
+                        oldest_updated_at = old_pin.last_updated_at;
+                        replace_i = Some(i);
+                    }
+                }
+                None => {
+                    // an empty slot is better than replacing anything. keep looking for a matching pin though
+                    oldest_updated_at = 0;
+                    replace_i = Some(i);
+                }
+            }
+        }
+
+        if let Some(i) = replace_i {
+            self.pin_locations[i] = Some((pin, None));
+        }
+
+        true
+    }
+
+    /// Get the peer's location if it hasn't already been broadcast during this time segment
+    fn location_to_broadcast(
+        &mut self,
+        time_segment_id: usize,
+        peer_id: usize,
+        now_epoch_seconds: u32,
+    ) -> Option<PeerLocation> {
+        let is_mine = self.my_peer_id == Some(peer_id);
+
+        if let Some(Some((location, broadcasted_at_id))) = self.peer_locations.get_mut(peer_id) {
+            if !is_mine && !should_relay(location, now_epoch_seconds) {
+                return None;
+            }
+
+            if *broadcasted_at_id != Some(time_segment_id) {
+                *broadcasted_at_id = Some(time_segment_id);
+
+                return Some(*location);
+            }
+        }
+
+        None
+    }
+
+    /// Our time, if we have a good one and haven't already broadcast it during this time segment
+    fn time_sync_to_broadcast(&mut self, time_segment_id: usize, now: u32) -> Option<TimeSync> {
+        if self.time_sync_broadcasted_at == Some(time_segment_id) {
+            return None;
+        }
+
+        let time_sync = self.clock.time_sync(now)?;
+
+        self.time_sync_broadcasted_at = Some(time_segment_id);
+
+        Some(time_sync)
+    }
+
+    /// The status that the peer picked. None if they haven't picked one (or cleared it)
+    pub fn peer_status(&self, peer_id: usize) -> Option<Status> {
+        match self.peer_statuses.get(peer_id)? {
+            Some((peer_status, _)) if peer_status.status != Status::Clear => {
+                Some(peer_status.status)
+            }
+            _ => None,
+        }
+    }
+
+    /// Save a new status. Returns false if we already have it (or a newer one)
+    pub fn save_status(&mut self, peer_status: PeerStatus) -> bool {
+        if !self.is_peer(peer_status.peer_id) {
+            // this status is from a bigger group than ours
+            return false;
+        }
+
+        if let Some((old_status, _)) = &mut self.peer_statuses[peer_status.peer_id] {
+            if old_status.last_updated_at == peer_status.last_updated_at {
+                // remember the shortest way it got here
+                old_status.hops = old_status.hops.min(peer_status.hops);
+                return false;
+            }
+
+            if old_status.last_updated_at > peer_status.last_updated_at {
+                return false;
+            }
+        }
+
+        self.peer_statuses[peer_status.peer_id] = Some((peer_status, None));
+
+        true
+    }
+
+    /// Get the peer's status if it hasn't already been broadcast during this time segment. Relayed the same way as
+    /// locations
+    fn status_to_broadcast(
+        &mut self,
+        time_segment_id: usize,
+        peer_id: usize,
+        now_epoch_seconds: u32,
+    ) -> Option<PeerStatus> {
+        let is_mine = self.my_peer_id == Some(peer_id);
+
+        if let Some(Some((peer_status, broadcasted_at_id))) = self.peer_statuses.get_mut(peer_id) {
+            if !is_mine
+                && (peer_status.hops >= MAX_RELAY_HOPS
+                    || peer_status.last_updated_at.saturating_add(MAX_RELAY_AGE_S)
+                        < now_epoch_seconds)
+            {
+                return None;
+            }
+
+            if *broadcasted_at_id != Some(time_segment_id) {
+                *broadcasted_at_id = Some(time_segment_id);
+
+                return Some(*peer_status);
+            }
+        }
+
+        None
+    }
+
+    /// Save a new or updated SOS. Returns false if we already have it (or a newer one)
+    pub fn save_sos(&mut self, sos: Sos) -> bool {
+        if !self.is_peer(sos.peer_id) {
+            // this sos is from a bigger group than ours
+            return false;
+        }
+
+        if let Some((old_sos, _)) = &mut self.sos[sos.peer_id] {
+            if old_sos.last_updated_at == sos.last_updated_at {
+                // remember the shortest way it got here
+                old_sos.hops = old_sos.hops.min(sos.hops);
+                return false;
+            }
+
+            if old_sos.last_updated_at > sos.last_updated_at {
+                return false;
+            }
+        }
+
+        self.sos[sos.peer_id] = Some((sos, None));
+
+        true
+    }
+
+    /// The newest SOS from someone else that hasn't been canceled. This is who we should go help. One that hasn't been
+    /// updated in MAX_SOS_RELAY_AGE_S is from a compass that died or walked away. No one relays it and we don't show it
+    pub fn active_sos(&self, now_epoch_seconds: u32) -> Option<&Sos> {
+        self.sos
+            .iter()
+            .flatten()
+            .map(|(sos, _)| sos)
+            .filter(|sos| {
+                sos.active
+                    && Some(sos.peer_id) != self.my_peer_id
+                    && sos.last_updated_at.saturating_add(MAX_SOS_RELAY_AGE_S) >= now_epoch_seconds
+            })
+            .max_by_key(|sos| sos.last_updated_at)
+    }
+
+    /// Get any SOS (or cancel) that hasn't already been broadcast during this time segment. These go before everything
+    /// else. Active ones are relayed for MAX_SOS_RELAY_AGE_S after the sender last updated them. Cancels are relayed as
+    /// long as locations are
+    fn sos_to_broadcast(&mut self, time_segment_id: usize, now_epoch_seconds: u32) -> Option<Sos> {
+        let my_peer_id = self.my_peer_id;
+
+        for (sos, broadcasted_at_id) in self.sos.iter_mut().flatten() {
+            if *broadcasted_at_id == Some(time_segment_id) {
+                continue;
+            }
+
+            if my_peer_id == Some(sos.peer_id) {
+                if sos.active {
+                    // we still need help. without a fix, save_my_location isn't keeping this new enough to relay
+                    sos.last_updated_at = sos.last_updated_at.max(now_epoch_seconds);
+                }
+            } else {
+                let max_age_s = if sos.active {
+                    MAX_SOS_RELAY_AGE_S
+                } else {
+                    MAX_RELAY_AGE_S
+                };
+
+                if sos.hops >= MAX_SOS_RELAY_HOPS
+                    || sos.last_updated_at.saturating_add(max_age_s) < now_epoch_seconds
+                {
+                    continue;
+                }
+            }
+
+            *broadcasted_at_id = Some(time_segment_id);
+
+            return Some(*sos);
+        }
+
+        None
+    }
+
+    /// True if we should tell everyone that we are here without a good location. Only once per time segment
+    fn should_beacon(&mut self, time_segment_id: usize, now_epoch_seconds: u32) -> bool {
+        let my_peer_id = match self.my_peer_id {
+            Some(x) => x,
+            None => return false,
+        };
+
+        if self.beacon_broadcasted_at == Some(time_segment_id)
+            || self.peer_age(my_peer_id, now_epoch_seconds) == Some(PeerAge::Fresh)
+        {
+            return false;
+        }
+
+        self.beacon_broadcasted_at = Some(time_segment_id);
+
+        true
+    }
+
+    /// Get one of the peer's pins that hasn't already been broadcast during this time segment
+    fn pin_to_broadcast(&mut self, time_segment_id: usize, peer_id: usize) -> Option<PinLocation> {
+        for (pin, broadcasted_at_id) in self.pin_locations.iter_mut().flatten() {
+            if pin.peer_id == peer_id && *broadcasted_at_id != Some(time_segment_id) {
+                *broadcasted_at_id = Some(time_segment_id);
+
+                return Some(*pin);
+            }
+        }
+
+        None
+    }
+}
+
+/// Relay other peers' locations so that peers out of each other's range still find each other
+fn should_relay(location: &PeerLocation, now_epoch_seconds: u32) -> bool {
+    location.hops < MAX_RELAY_HOPS
+        && location.last_updated_at.saturating_add(MAX_RELAY_AGE_S) >= now_epoch_seconds
+}
+
+/// Packets from compasses that are still joining can only be checked for age. They don't have a peer id yet
+pub fn check_age(tx_time: u32, now_epoch_seconds: u32) -> Result<(), RejectReason> {
+    if tx_time.saturating_add(MAX_PACKET_AGE_S) < now_epoch_seconds
+        || tx_time > now_epoch_seconds.saturating_add(MAX_PACKET_AGE_S)
+    {
+        return Err(RejectReason::Stale);
+    }
+
+    Ok(())
+}
+
+pub struct Network<R> {
+    radio: R,
+    current_mode: Mode,
+    keys: NetworkKeys,
+    /// encrypt the bodies of the packets that we send
+    encrypt: bool,
+    radio_settings: RadioSettings,
+    /// elapsed ms the last time we checked for packets. Time spent receiving is counted between checks
+    receive_tick: u32,
+    /// the radio's interrupt reads packets. `receive` only has to keep the radio listening
+    receive_interrupt: bool,
+    /// Some while we are looking for a peer id
+    pub joining: Option<Join>,
+    /// Someone claimed a peer id that we know is taken. The elapsed ms when we heard them. Tell them DEFEND_DELAY_MS
+    /// after that
+    defend_claim: Option<(u32, usize)>,
+    /// goes in our beacons
+    battery: BatteryStatus,
+    /// elapsed ms when we last saved our own location. beacons say how long ago that was
+    my_location_saved_at: Option<u32>,
+    /// the beacon period (from `Schedule::beacon_slot`) that we last beaconed in
+    beacon_sent_at: Option<u32>,
+    /// goes in every header so that we never reuse a nonce. See `Header::tx_counter`
+    tx_counter: u32,
+    pub data: NetworkData,
+}
+
+impl<R: Radio> Network<R> {
+    /// `nonce_seed` should be random. See `Header::tx_counter`
+    /// A bad `num_peers` or `my_peer_id` is the same mistake as in a group file, so it gets the same error
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        radio: R,
+        network_secret: NetworkSecret,
+        encrypt: bool,
+        num_peers: usize,
+        my_peer_id: Option<usize>,
+        my_hue: u8,
+        my_saturation: u8,
+        nonce_seed: u32,
+    ) -> Result<Self, ConfigError> {
+        if num_peers == 0 || num_peers > MAX_PEERS {
+            return Err(ConfigError::NumPeers);
+        }
+
+        if let Some(my_peer_id) = my_peer_id {
+            if my_peer_id >= num_peers {
+                return Err(ConfigError::PeerId);
+            }
+        }
+
+        let current_mode = Mode::Sleep;
+
+        let keys = NetworkKeys::new(&network_secret);
+
+        let data = NetworkData {
+            num_peers,
+            my_peer_id,
+            my_hue,
+            my_saturation,
+            network_hash: keys.network_hash,
+            peer_age_limits: PeerAgeLimits::for_peers(num_peers),
+            ..Default::default()
+        };
+
+        Ok(Self {
+            radio,
+            current_mode,
+            keys,
+            encrypt,
+            // this is what `new_sx127x` starts with. use `set_radio_settings` to change it
+            radio_settings: RadioSettings::default(),
+            receive_tick: 0,
+            receive_interrupt: false,
+            joining: None,
+            defend_claim: None,
+            battery: BatteryStatus::Ok,
+            my_location_saved_at: None,
+            beacon_sent_at: None,
+            // random so that a reboot (or another compass that is still joining) doesn't count through the same nonces
+            tx_counter: nonce_seed,
+            data,
+        })
+    }
+
+    /// Check and apply new radio settings. Everyone in the group has to use the same settings or they won't hear
+    /// each other
+    pub fn set_radio_settings(
+        &mut self,
+        radio_settings: RadioSettings,
+    ) -> Result<(), NetworkError<R::Error>> {
+        radio_settings.validate()?;
+
+        let schedule = Schedule::for_peers(self.data.num_peers);
+
+        if radio_settings.airtime_ms(MAX_PACKET_LEN) >= schedule.transmit_window_ms() {
+            return Err(SettingsError::TooSlow.into());
+        }
+
+        self.current_mode = Mode::Unknown;
+
+        self.radio
+            .configure(&radio_settings)
+            .map_err(NetworkError::Configure)?;
+        self.radio_settings = radio_settings;
+
+        // anything in the radio's FIFO was sent with the old settings
+        self.sleep()
+    }
+
+    /// Start the radio over and give it our settings again. Try this when the radio keeps failing
+    pub fn reset_radio(&mut self) -> Result<(), NetworkError<R::Error>> {
+        self.current_mode = Mode::Unknown;
+
+        self.radio.reset().map_err(NetworkError::Reset)?;
+
+        self.radio
+            .configure(&self.radio_settings)
+            .map_err(NetworkError::Configure)?;
+
+        self.sleep()
+    }
+
+    pub fn radio_settings(&self) -> &RadioSettings {
+        &self.radio_settings
+    }
+
+    /// Stop polling the radio for packets. Call `read_received` from the radio's DIO0 interrupt instead and
+    /// `handle_received` from the idle loop
+    pub fn use_receive_interrupt(&mut self) {
+        self.receive_interrupt = true;
+    }
+
+    /// Our beacons tell everyone when our battery is low
+    pub fn set_battery(&mut self, status: BatteryStatus) {
+        self.battery = status;
+    }
+
+    /// Start looking for a free peer id. Use a random nonce! It decides who gets an id if two compasses want it.
+    /// Does nothing if we already have one
+    pub fn start_join(&mut self, nonce: u32, epoch_seconds: u32, ms: u32) {
+        if self.data.my_peer_id.is_some() {
+            return;
+        }
+
+        let now_ms = epoch_seconds as u64 * 1000 + ms as u64;
+
+        self.joining = Some(Join::new(self.data.num_peers, nonce, now_ms));
+    }
+
+    /// Call this instead of following the schedule until we have a peer id.
+    /// Returns the peer id once we have one. Save it so that we don't have to join again after a reboot
+    pub fn join(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+        epoch_seconds: u32,
+        ms: u32,
+    ) -> Result<Option<usize>, NetworkError<R::Error>> {
+        let now_ms = epoch_seconds as u64 * 1000 + ms as u64;
+
+        let action = match &mut self.joining {
+            Some(joining) => joining.poll(now_ms),
+            None => return Ok(None),
+        };
+
+        match action {
+            JoinAction::Receive => self.try_receive(elapsed_ms, epoch_seconds)?,
+            JoinAction::Transmit(claim) => {
+                self.transmit_message(elapsed_ms, epoch_seconds, &Message::Claim(claim))?
+            }
+            JoinAction::Joined(peer_id) => {
+                self.joining = None;
+                self.data.my_peer_id = Some(peer_id);
+
+                return Ok(Some(peer_id));
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn save_message(&mut self, header: &Header, message: Message) {
+        match message {
+            Message::Location(mut location) => {
+                if header.tx_peer_id as usize != location.peer_id {
+                    // a relay. whoever sent this to us is one more hop
+                    location.hops = location.hops.saturating_add(1);
+                } else {
+                    location.hops = 0;
+                }
+
+                self.save_location(location)
+            }
+            Message::Pin(pin) => {
+                if !self.data.is_peer(pin.peer_id) {
+                    // this pin is from a bigger group than ours
+                    return;
+                }
+
+                self.data.save_pin(pin);
+            }
+            Message::TimeSync(_) => {
+                // try_receive already gave this to the clock. it needs to know when the packet showed up
+            }
+            Message::Config(config) => {
+                // the mac says who sent this. a peer only gets to pick its own color
+                let tx_peer_id = header.tx_peer_id as usize;
+
+                if self.data.my_peer_id == Some(tx_peer_id) {
+                    // another compass that thinks it's us. our color comes from our own config
+                    return;
+                }
+
+                if let Some(Some((location, broadcasted_at))) =
+                    self.data.peer_locations.get_mut(tx_peer_id)
+                {
+                    location.hue = config.hue;
+                    location.sat = config.sat;
+                    // newer so that peers who relay it replace the old color
+                    location.last_updated_at = location.last_updated_at.max(header.tx_time);
+                    *broadcasted_at = None;
+                }
+            }
+            Message::Ping => {
+                // nothing to save. check_replay already kept track of when we heard from them
+            }
+            Message::Claim(claim) => {
+                if let Some(joining) = &mut self.joining {
+                    joining.heard_claim(&claim, header.tx_time as u64 * 1000);
+                }
+            }
+            Message::Sos(mut sos) => {
+                if header.tx_peer_id as usize != sos.peer_id {
+                    sos.hops = sos.hops.saturating_add(1);
+                } else {
+                    sos.hops = 0;
+                }
+
+                // this might be our own. if it's newer than what we have, we rebooted since sending it. keep it so that
+                // it can be canceled
+                self.data.save_sos(sos);
+            }
+            Message::Status(mut peer_status) => {
+                if header.tx_peer_id as usize != peer_status.peer_id {
+                    peer_status.hops = peer_status.hops.saturating_add(1);
+                } else {
+                    peer_status.hops = 0;
+                }
+
+                self.data.save_status(peer_status);
+            }
+            Message::Beacon(beacon) => {
+                let tx_peer_id = header.tx_peer_id as usize;
+
+                if self.data.is_peer(tx_peer_id) {
+                    self.data.beacons[tx_peer_id] = Some(beacon);
+                }
+            }
+        }
+    }
+
+    fn save_location(&mut self, location: PeerLocation) {
+        let peer_id = location.peer_id as usize;
+
+        if !self.data.is_peer(peer_id) {
+            // this location is from a bigger group than ours
+            return;
+        }
+
+        // TODO: set our millis timer to match the leader's timer?
+        // TODO: do this better. we have GPS. we should be able to have super accurate time without this
+
+        if let Some((old_location, _)) = &mut self.data.peer_locations[peer_id] {
+            if old_location.last_updated_at == location.last_updated_at {
+                // we already have this message. but remember the shortest way it got here
+                old_location.hops = old_location.hops.min(location.hops);
+                return;
+            }
+
+            if old_location.last_updated_at > location.last_updated_at {
+                // we already have a newer message
+                return;
+            }
+        }
+
+        self.data.peer_locations[peer_id] = Some((location, None));
+    }
+
+    /// This does nothing until we have joined. There is nowhere to put our location without a peer id
+    pub fn save_my_location(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+        last_updated_at: u32,
+        position: &GpsPosition,
+    ) {
+        let my_peer_id = match self.data.my_peer_id {
+            Some(x) => x,
+            None => return,
+        };
+
+        self.my_location_saved_at = Some(elapsed_ms.now());
+
+        if let Some((sos, broadcast_at)) = &mut self.data.sos[my_peer_id] {
+            if sos.active {
+                // lead them to where we are now
+                sos.last_updated_at = last_updated_at;
+                sos.position = Some((position.lat, position.lon));
+
+                *broadcast_at = None;
+            }
+        }
+
+        match &mut self.data.peer_locations[my_peer_id] {
+            Some((compass_location, broadcast_at)) => {
+                compass_location.last_updated_at = last_updated_at;
+                compass_location.lat = position.lat;
+                compass_location.lon = position.lon;
+
+                *broadcast_at = None;
+            }
+            None => {
+                let location = PeerLocation {
+                    peer_id: my_peer_id,
+                    last_updated_at,
+                    hue: self.data.my_hue,
+                    sat: self.data.my_saturation,
+                    lat: position.lat,
+                    lon: position.lon,
+                    hops: 0,
+                };
+
+                self.data.peer_locations[my_peer_id] = Some((location, None));
+            }
+        }
+    }
+
+    /// Ask everyone for help. Our SOS goes out first in every one of our turns until `cancel_sos`. Peers relay it first
+    /// in all of theirs
+    pub fn start_sos(&mut self, epoch_seconds: u32) {
+        self.set_sos(epoch_seconds, true);
+    }
+
+    /// We are fine. Everyone stops pointing at us once they hear this
+    pub fn cancel_sos(&mut self, epoch_seconds: u32) {
+        self.set_sos(epoch_seconds, false);
+    }
+
+    /// True if we asked for help and haven't canceled it
+    pub fn sos_active(&self) -> bool {
+        match self.data.my_peer_id {
+            Some(my_peer_id) => matches!(self.data.sos[my_peer_id], Some((sos, _)) if sos.active),
+            None => false,
+        }
+    }
+
+    fn set_sos(&mut self, epoch_seconds: u32, active: bool) {
+        let my_peer_id = match self.data.my_peer_id {
+            Some(x) => x,
+            None => return,
+        };
+
+        let position = self.data.peer_locations[my_peer_id]
+            .as_ref()
+            .map(|(location, _)| (location.lat, location.lon));
+
+        let last_updated_at = match self.data.sos[my_peer_id] {
+            // an sos and its cancel can happen in the same second. the newer one has to win
+            Some((old_sos, _)) => epoch_seconds.max(old_sos.last_updated_at.saturating_add(1)),
+            None => epoch_seconds,
+        };
+
+        self.data.sos[my_peer_id] = Some((
+            Sos {
+                peer_id: my_peer_id,
+                last_updated_at,
+                active,
+                position,
+                hops: 0,
+            },
+            None,
+        ));
+    }
+
+    /// Tell everyone what we are up to. `Status::Clear` takes it back
+    pub fn set_my_status(&mut self, epoch_seconds: u32, status: Status) {
+        let my_peer_id = match self.data.my_peer_id {
+            Some(x) => x,
+            None => return,
+        };
+
+        let last_updated_at = match self.data.peer_statuses[my_peer_id] {
+            // someone pressing a button over and over changes it a few times a second. the last one has to win
+            Some((old_status, _)) => {
+                epoch_seconds.max(old_status.last_updated_at.saturating_add(1))
+            }
+            None => epoch_seconds,
+        };
+
+        self.data.save_status(PeerStatus {
+            peer_id: my_peer_id,
+            last_updated_at,
+            status,
+            hops: 0,
+        });
+    }
+
+    /// Drop a pin at our current location
+    pub fn save_my_pin(
+        &mut self,
+        pin_id: u8,
+        last_updated_at: u32,
+        position: &GpsPosition,
+        hue: u8,
+        sat: u8,
+    ) {
+        let my_peer_id = match self.data.my_peer_id {
+            Some(x) => x,
+            None => return,
+        };
+
+        let pin = PinLocation {
+            peer_id: my_peer_id,
+            pin_id,
+            last_updated_at,
+            hue,
+            sat,
+            lat: position.lat,
+            lon: position.lon,
+        };
+
+        self.data.save_pin(pin);
+    }
+
+    /// Broadcast any SOS first. Then the peer's location and status. If those were already sent during this time segment,
+    /// broadcast one of their pins. After that, broadcast our time. During our own turn without a fresh location, finish
+    /// with a beacon
+    pub fn transmit(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+        epoch_seconds: u32,
+        time_segment_id: usize,
+        peer_id: usize,
+    ) -> Result<(), NetworkError<R::Error>> {
+        if self.current_mode == Mode::Transmit {
+            let done = self.radio.check_transmit().map_err(|err| {
+                self.current_mode = Mode::Unknown;
+                NetworkError::Transmit(err)
+            })?;
+
+            if !done {
+                // another transmission is in process. skip
+                return Ok(());
+            }
+        }
+
+        let message = if let Some(sos) = self.data.sos_to_broadcast(time_segment_id, epoch_seconds)
+        {
+            // someone needs help. this can't wait for their turn
+            Message::Sos(sos)
+        } else if let Some(location) =
+            self.data
+                .location_to_broadcast(time_segment_id, peer_id, epoch_seconds)
+        {
+            Message::Location(location)
+        } else if let Some(peer_status) =
+            self.data
+                .status_to_broadcast(time_segment_id, peer_id, epoch_seconds)
+        {
+            Message::Status(peer_status)
+        } else if let Some(pin) = self.data.pin_to_broadcast(time_segment_id, peer_id) {
+            Message::Pin(pin)
+        } else if let Some(time_sync) = self
+            .data
+            .time_sync_to_broadcast(time_segment_id, elapsed_ms.now())
+        {
+            // peers without a GPS fix can still keep up with the schedule
+            Message::TimeSync(time_sync)
+        } else if self.data.my_peer_id == Some(peer_id)
+            && self.data.should_beacon(time_segment_id, epoch_seconds)
+        {
+            // we know the time from our peers, but not where we are
+            Message::Beacon(self.my_beacon(elapsed_ms.now()))
+        } else {
+            // we've already broadcast everything we have for this peer. no one else talks during our turn
+            return self.sleep();
+        };
+
+        self.transmit_message(elapsed_ms, epoch_seconds, &message)
+    }
+
+    /// Use this instead of following the schedule when we don't know the time. It sends a beacon if `beacon_id` (from
+    /// `Schedule::beacon_slot`) is new and listens otherwise
+    pub fn beacon(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+        beacon_id: Option<u32>,
+    ) -> Result<(), NetworkError<R::Error>> {
+        if self.current_mode == Mode::Transmit {
+            let done = self.radio.check_transmit().map_err(|err| {
+                self.current_mode = Mode::Unknown;
+                NetworkError::Transmit(err)
+            })?;
+
+            if !done {
+                return Ok(());
+            }
+        }
+
+        match (self.data.my_peer_id, beacon_id) {
+            (Some(_), Some(beacon_id)) if self.beacon_sent_at != Some(beacon_id) => {
+                self.beacon_sent_at = Some(beacon_id);
+
+                let message = Message::Beacon(self.my_beacon(elapsed_ms.now()));
+
+                self.transmit_message(elapsed_ms, NO_TX_TIME, &message)
+            }
+            // a peer might tell us the time. and we want to hear other beacons
+            _ => self.listen_for_time(elapsed_ms),
+        }
+    }
+
+    /// `now` is `ElapsedMs::now`
+    fn my_beacon(&self, now: u32) -> Beacon {
+        let position_age_s = self
+            .my_location_saved_at
+            .map(|saved_at| (now.wrapping_sub(saved_at) / 1000).min(u16::MAX as u32) as u16);
+
+        Beacon {
+            battery: self.battery,
+            position_age_s,
+        }
+    }
+
+    /// Send any kind of message. This does not check if the radio is busy
+    pub fn transmit_message(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+        epoch_seconds: u32,
+        message: &Message,
+    ) -> Result<(), NetworkError<R::Error>> {
+        let flags = if self.encrypt { FLAG_ENCRYPTED } else { 0 };
+
+        self.tx_counter = self.tx_counter.wrapping_add(1);
+
+        let header = Header {
+            flags,
+            kind: message.kind() as u8,
+            tx_peer_id: self
+                .data
+                .my_peer_id
+                .map_or(UNCONFIGURED_PEER_ID, |x| x as u8),
+            tx_time: epoch_seconds,
+            tx_ms: elapsed_ms.now(),
+            tx_counter: self.tx_counter,
+        };
+
+        // leave room for the header at the start and for the mac at the end
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        let body_len = message
+            .encode(&mut buf[packet::HEADER_LEN..][..packet::MAX_BODY_LEN])
+            .map_err(|_| NetworkError::Encode)?;
+
+        let n = packet::seal(&self.keys, &header, &mut buf, body_len);
+
+        let now = elapsed_ms.now();
+        let airtime_ms = self.radio_settings.airtime_ms(n);
+
+        if let Some(max_permille) = self.radio_settings.max_duty_cycle_permille() {
+            if !self
+                .data
+                .radio_time
+                .can_transmit(airtime_ms, max_permille, now)
+            {
+                // we used up our airtime. the schedule will give us more turns later
+                self.data.stats.duty_cycle_limited =
+                    self.data.stats.duty_cycle_limited.saturating_add(1);
+                return Ok(());
+            }
+        }
+
+        if let Err(err) = self.radio.start_transmit(&buf[..n]) {
+            self.current_mode = Mode::Unknown;
+            return Err(NetworkError::Transmit(err));
+        }
+
+        self.current_mode = Mode::Transmit;
+        self.data.radio_time.transmitted(airtime_ms, now);
+
+        // TODO: block until transmission is complete?
+
+        Ok(())
+    }
+
+    /// `now_epoch_seconds` is used to drop old packets. Only call this when we have the time from the GPS
+    pub fn try_receive(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+        now_epoch_seconds: u32,
+    ) -> Result<(), NetworkError<R::Error>> {
+        if let Some((claim_heard_at, peer_id)) = self.defend_claim {
+            // elapsed ms wraps after 49 days
+            if elapsed_ms.now().wrapping_sub(claim_heard_at) >= DEFEND_DELAY_MS {
+                self.defend_claim = None;
+
+                // a location for the id tells the joining compass that it's taken. so does any packet from its owner
+                // TODO: if several peers know about this id, their answers will collide
+                let message = match self.data.peer_locations[peer_id] {
+                    Some((location, _)) => Message::Location(location),
+                    None => Message::Ping,
+                };
+
+                return self.transmit_message(elapsed_ms, now_epoch_seconds, &message);
+            }
+        }
+
+        self.receive(elapsed_ms, Some(now_epoch_seconds))
+    }
+
+    /// Use this instead of `try_receive` when we don't know the time. Everything but `TimeSync` and `Beacon` is dropped
+    pub fn listen_for_time(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+    ) -> Result<(), NetworkError<R::Error>> {
+        self.receive(elapsed_ms, None)
+    }
+
+    fn receive(
+        &mut self,
+        elapsed_ms: &ElapsedMs,
+        now_epoch_seconds: Option<u32>,
+    ) -> Result<(), NetworkError<R::Error>> {
+        let now = elapsed_ms.now();
+
+        if self.current_mode != Mode::Receive {
+            self.current_mode = Mode::Unknown;
+            self.radio.start_receive().map_err(NetworkError::Receive)?;
+            self.current_mode = Mode::Receive;
+        } else {
+            self.data
+                .radio_time
+                .received(now.wrapping_sub(self.receive_tick), now);
+        }
+
+        self.receive_tick = now;
+
+        if self.receive_interrupt {
+            // packets are already on their way to `handle_received`
+            return Ok(());
+        }
+
+        if let Some(mut packet) = self.read_packet(now)? {
+            self.handle_received(&mut packet, now_epoch_seconds);
+        }
+
+        Ok(())
+    }
+
+    /// Copy a packet out of the radio if one is waiting
+    fn read_packet(&mut self, now: u32) -> Result<Option<RxPacket>, NetworkError<R::Error>> {
+        // TODO: true or false here?
+        let waiting = self.radio.check_receive(true).map_err(|err| {
+            self.current_mode = Mode::Unknown;
+            NetworkError::Receive(err)
+        })?;
+
+        if !waiting {
+            return Ok(None);
+        }
+
+        let mut packet = RxPacket::new(now);
+
+        packet.len = self
+            .radio
+            .get_received(&mut packet.info, &mut packet.data)
+            .map_err(|err| {
+                self.current_mode = Mode::Unknown;
+                NetworkError::Receive(err)
+            })?;
+
+        Ok(Some(packet))
+    }
+
+    /// Call this from the radio's DIO0 interrupt. It only copies the packet into the queue. `now` is `ElapsedMs::now`
+    pub fn read_received(
+        &mut self,
+        now: u32,
+        queue: &mut RxProducer,
+    ) -> Result<(), NetworkError<R::Error>> {
+        if self.current_mode != Mode::Receive {
+            // DIO0 also goes high when a transmission is done. `transmit` checks on that itself
+            return Ok(());
+        }
+
+        if let Some(packet) = self.read_packet(now)? {
+            if queue.enqueue(packet).is_err() {
+                self.data.stats.rx_queue_full = self.data.stats.rx_queue_full.saturating_add(1);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check, decode, and save a packet from the radio. Returns the message if it was for us.
+    /// `now_epoch_seconds` is used to drop old packets. Use None if we don't know the time. Everything but `TimeSync`
+    /// and `Beacon` is dropped then
+    pub fn handle_received(
+        &mut self,
+        packet: &mut RxPacket,
+        now_epoch_seconds: Option<u32>,
+    ) -> Option<Message> {
+        self.data.stats.received = self.data.stats.received.saturating_add(1);
+
+        let n = packet.len;
+
+        let (header, body) = match packet::open(&self.keys, &mut packet.data[..n]) {
+            Ok(x) => x,
+            Err(reason) => {
+                // this packet is corrupted, forged, or for a different network
+                self.data.stats.reject(reason);
+                return None;
+            }
+        };
+
+        // unknown kinds are from newer firmware. they are counted and skipped
+        let message = match Message::decode(header.kind, body) {
+            Ok(x) => x,
+            Err(reason) => {
+                // hprintln!("Failed parsing the packet!").unwrap();
+                self.data.stats.reject(reason);
+                return None;
+            }
+        };
+
+        let now_epoch_seconds = match (now_epoch_seconds, &message) {
+            (Some(x), _) => x,
+            // we can't tell how old this is. but the mac says it's from someone in our group
+            (None, Message::TimeSync(_)) | (None, Message::Beacon(_)) => header.tx_time,
+            (None, _) => {
+                self.data.stats.reject(RejectReason::Stale);
+                return None;
+            }
+        };
+
+        let checked = match (header.tx_peer_id, &message) {
+            // compasses that are still joining only send claims
+            (UNCONFIGURED_PEER_ID, Message::Claim(_)) => {
+                check_age(header.tx_time, now_epoch_seconds)
+            }
+            (UNCONFIGURED_PEER_ID, _) | (_, Message::Claim(_)) => Err(RejectReason::Malformed),
+            // the transmitter doesn't know the time, so this can't be checked for age
+            (tx_peer_id, Message::Beacon(_)) if header.tx_time == NO_TX_TIME => self
+                .data
+                .check_beacon_replay(tx_peer_id as usize, header.tx_ms, packet.received_at),
+            (tx_peer_id, _) => self.data.check_replay(
+                tx_peer_id as usize,
+                header.tx_time,
+                header.tx_ms,
+                now_epoch_seconds,
+            ),
+        };
+
+        if let Err(reason) = checked {
+            self.data.stats.reject(reason);
+            return None;
+        }
+
+        // this packet is for us
+        self.data.stats.accepted = self.data.stats.accepted.saturating_add(1);
+
+        if header.tx_peer_id != UNCONFIGURED_PEER_ID {
+            // check_replay already made sure this is a peer
+            self.data.links[header.tx_peer_id as usize]
+                .get_or_insert_with(LinkStats::default)
+                .heard(&packet.info, packet.received_at);
+        }
+
+        if let Message::TimeSync(time_sync) = &message {
+            // the time was right when they sent it. it was a little later when it got here
+            let delay_ms = self.radio_settings.airtime_ms(n);
+
+            self.data
+                .clock
+                .peer_time(time_sync, delay_ms, packet.received_at);
+        }
+
+        if let Message::Claim(claim) = &message {
+            // peers out of the claimer's range might have this id. we relay for them, so we answer for them too
+            if self.data.is_peer(claim.peer_id)
+                && (self.data.my_peer_id == Some(claim.peer_id)
+                    || self.data.peer_locations[claim.peer_id].is_some())
+            {
+                self.defend_claim = Some((packet.received_at, claim.peer_id));
+            }
+        }
+
+        if let Some(joining) = &mut self.joining {
+            // anyone we hear from already has their id
+            let now_ms = now_epoch_seconds as u64 * 1000;
+
+            if header.tx_peer_id != UNCONFIGURED_PEER_ID {
+                joining.heard_peer(header.tx_peer_id as usize, now_ms);
+            }
+
+            if let Message::Location(location) = &message {
+                joining.heard_peer(location.peer_id, now_ms);
+            }
+        }
+
+        self.save_message(&header, message);
+
+        Some(message)
+    }
+
+    pub fn sleep(&mut self) -> Result<(), NetworkError<R::Error>> {
+        if self.current_mode != Mode::Sleep {
+            self.current_mode = Mode::Unknown;
+            self.radio.sleep().map_err(NetworkError::Sleep)?;
+            self.current_mode = Mode::Sleep;
+        }
+
+        Ok(())
+    }
+
+    pub fn silicon_version(&mut self) -> Result<u8, NetworkError<R::Error>> {
+        self.radio.silicon_version().map_err(NetworkError::Init)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_util::{mock_node, mock_position, new_node, NOW};
+    use super::*;
+
+    #[test]
+    fn test_location_exchange() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        // b has to be listening to hear anything
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        assert_eq!(air.sent(0), 1);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.stats.accepted, 1);
+        assert!(b.data.peer_location(2).is_some());
+
+        // the location was already sent during this time segment
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        assert_eq!(air.sent(0), 1);
+    }
+
+    #[test]
+    fn test_relay() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+        let mut c = mock_node(&air, [1; 32], 4);
+
+        // a and c are too far apart. b is in the middle
+        air.disconnect(0, 2);
+        air.disconnect(2, 0);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.peer_location(2).unwrap().hops, 0);
+        assert!(c.data.peer_location(2).is_none());
+
+        elapsed_ms.increment_by(10);
+        b.transmit(&elapsed_ms, NOW, 1, 2).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(c.data.peer_location(2).unwrap().hops, 1);
+
+        // the same location straight from a doesn't go anywhere new, but it is fewer hops
+        air.connect(0, 2, RxInfo::default());
+        a.transmit(&elapsed_ms, NOW, 2, 2).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(c.data.peer_location(2).unwrap().hops, 0);
+    }
+
+    #[test]
+    fn test_config() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+
+        let mut header = Header {
+            flags: 0,
+            kind: MessageKind::Location as u8,
+            tx_peer_id: 3,
+            tx_time: NOW,
+            tx_ms: 0,
+            tx_counter: 0,
+        };
+
+        a.save_message(
+            &header,
+            Message::Location(PeerLocation {
+                peer_id: 3,
+                last_updated_at: NOW,
+                hue: 0,
+                sat: 255,
+                lat: 0.0,
+                lon: 0.0,
+                hops: 0,
+            }),
+        );
+        a.transmit(&elapsed_ms, NOW, 0, 3).unwrap();
+        assert_eq!(air.sent(0), 1);
+
+        // peer 4 can't pick peer 3's color
+        header.kind = MessageKind::Config as u8;
+        header.tx_peer_id = 4;
+        a.save_message(&header, Message::Config(PeerConfig { hue: 9, sat: 9 }));
+        assert_eq!(a.data.peer_location(3).unwrap().hue, 0);
+
+        // and no one can pick ours
+        header.tx_peer_id = 2;
+        a.save_message(&header, Message::Config(PeerConfig { hue: 9, sat: 9 }));
+        assert_eq!(a.data.peer_location(2).unwrap().hue, 0);
+
+        header.tx_peer_id = 3;
+        header.tx_time = NOW + 5;
+        a.save_message(&header, Message::Config(PeerConfig { hue: 100, sat: 200 }));
+
+        let location = a.data.peer_location(3).unwrap();
+        assert_eq!((location.hue, location.sat), (100, 200));
+        assert_eq!(location.last_updated_at, NOW + 5);
+
+        // the new color goes out again even though we already relayed their location this time segment
+        elapsed_ms.increment_by(10);
+        a.transmit(&elapsed_ms, NOW + 5, 0, 3).unwrap();
+        assert_eq!(air.sent(0), 2);
+    }
+
+    #[test]
+    fn test_peer_age() {
+        let air = MockAir::new();
+
+        let a = mock_node(&air, [1; 32], 2);
+
+        // 5 peers take 50 seconds to get through the schedule
+        let limits = a.data.peer_age_limits;
+        assert_eq!(limits.stale_after_s, 100);
+        assert_eq!(limits.lost_after_s, MIN_LOST_AFTER_S);
+
+        assert_eq!(a.data.peer_age(2, NOW), Some(PeerAge::Fresh));
+        assert_eq!(
+            a.data.peer_age(2, NOW + limits.stale_after_s),
+            Some(PeerAge::Fresh)
+        );
+        assert_eq!(
+            a.data.peer_age(2, NOW + limits.stale_after_s + 1),
+            Some(PeerAge::Stale)
+        );
+        assert_eq!(
+            a.data.peer_age(2, NOW + limits.lost_after_s + 1),
+            Some(PeerAge::Lost)
+        );
+
+        // a clock that is a little behind doesn't make anything older
+        assert_eq!(a.data.peer_age(2, NOW - 5), Some(PeerAge::Fresh));
+
+        // we don't know where they are at all
+        assert_eq!(a.data.peer_age(3, NOW), None);
+        assert_eq!(a.data.peer_age(MAX_PEERS, NOW), None);
+
+        // 9 peers take 162 seconds. stale waits longer, but lost is already as long as it gets
+        let limits = PeerAgeLimits::for_peers(9);
+        assert_eq!(limits.stale_after_s, 2 * 162);
+        assert_eq!(limits.lost_after_s, MAX_LOST_AFTER_S);
+
+        // a full group's cycle is 512 seconds. that would be almost an hour before they are lost
+        let limits = PeerAgeLimits::for_peers(MAX_PEERS);
+        assert_eq!(limits.stale_after_s, MAX_STALE_AFTER_S);
+        assert_eq!(limits.lost_after_s, MAX_LOST_AFTER_S);
+
+        for num_peers in 1..=MAX_PEERS {
+            let limits = PeerAgeLimits::for_peers(num_peers);
+            assert!(limits.stale_after_s < limits.lost_after_s);
+        }
+    }
+
+    #[test]
+    fn test_link_stats() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+        let mut c = mock_node(&air, [1; 32], 4);
+
+        air.connect(
+            0,
+            1,
+            RxInfo {
+                rssi: -60,
+                snr: Some(9),
+            },
+        );
+        // c can barely hear b and can't hear a at all
+        air.connect(
+            1,
+            2,
+            RxInfo {
+                rssi: -118,
+                snr: Some(-12),
+            },
+        );
+        air.disconnect(0, 2);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        elapsed_ms.increment_by(100);
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        let link = b.data.links[2].unwrap();
+        assert_eq!(link.rssi, -60);
+        assert_eq!(link.snr, Some(9));
+        assert_eq!(link.received, 1);
+        assert_eq!(link.last_heard_ms, 100);
+        assert!(!link.is_weak());
+        assert!(!link.is_stale(100 + LINK_STALE_MS));
+        assert!(link.is_stale(100 + LINK_STALE_MS + 1));
+
+        // b relays a's location. that says nothing about c's link to a
+        elapsed_ms.increment_by(10);
+        b.transmit(&elapsed_ms, NOW, 1, 2).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert!(c.data.peer_location(2).is_some());
+        assert!(c.data.links[2].is_none());
+        assert!(c.data.links[3].unwrap().is_weak());
+    }
+
+    #[test]
+    fn test_relay_limits() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+
+        // peer 4 relayed peer 3's location to us. that's one more hop
+        let header = Header {
+            flags: 0,
+            kind: MessageKind::Location as u8,
+            tx_peer_id: 4,
+            tx_time: NOW,
+            tx_ms: 0,
+            tx_counter: 0,
+        };
+
+        let location = PeerLocation {
+            peer_id: 3,
+            last_updated_at: NOW,
+            hue: 0,
+            sat: 255,
+            lat: 0.0,
+            lon: 0.0,
+            hops: MAX_RELAY_HOPS - 2,
+        };
+
+        a.save_message(&header, Message::Location(location));
+        a.transmit(&elapsed_ms, NOW, 0, 3).unwrap();
+        assert_eq!(air.sent(0), 1);
+
+        // too far
+        a.save_message(
+            &header,
+            Message::Location(PeerLocation {
+                last_updated_at: NOW + 1,
+                hops: MAX_RELAY_HOPS - 1,
+                ..location
+            }),
+        );
+        elapsed_ms.increment_by(10);
+        a.transmit(&elapsed_ms, NOW + 1, 1, 3).unwrap();
+        assert_eq!(air.sent(0), 1);
+
+        // too old
+        a.save_message(
+            &header,
+            Message::Location(PeerLocation {
+                last_updated_at: NOW + 2,
+                ..location
+            }),
+        );
+        a.transmit(&elapsed_ms, NOW + 2 + MAX_RELAY_AGE_S + 1, 2, 3)
+            .unwrap();
+        assert_eq!(air.sent(0), 1);
+
+        // but our own location always goes out
+        a.transmit(&elapsed_ms, NOW + MAX_RELAY_AGE_S + 1, 3, 2)
+            .unwrap();
+        assert_eq!(air.sent(0), 2);
+    }
+
+    #[test]
+    fn test_check_replay() {
+        let mut data = NetworkData {
+            num_peers: 5,
+            ..Default::default()
+        };
+
+        assert_eq!(data.check_replay(2, NOW, 500, NOW), Ok(()));
+
+        // the exact same packet again
+        assert_eq!(
+            data.check_replay(2, NOW, 500, NOW),
+            Err(RejectReason::Replayed)
+        );
+
+        // an older packet from the same peer that arrived late
+        assert_eq!(
+            data.check_replay(2, NOW, 400, NOW),
+            Err(RejectReason::Replayed)
+        );
+        assert_eq!(data.check_replay(2, NOW, 600, NOW), Ok(()));
+
+        // they rebooted. tx_ms started over, but tx_time moved forward
+        assert_eq!(data.check_replay(2, NOW + 1, 10, NOW + 1), Ok(()));
+
+        // every peer is tracked on its own
+        assert_eq!(data.check_replay(3, NOW, 10, NOW + 1), Ok(()));
+
+        // too old and too far in the future. even from a peer we haven't heard from yet
+        assert_eq!(
+            data.check_replay(4, NOW - MAX_PACKET_AGE_S - 1, 10, NOW),
+            Err(RejectReason::Stale)
+        );
+        assert_eq!(
+            data.check_replay(4, NOW + MAX_PACKET_AGE_S + 1, 10, NOW),
+            Err(RejectReason::Stale)
+        );
+
+        // not in the group
+        assert_eq!(
+            data.check_replay(5, NOW, 10, NOW),
+            Err(RejectReason::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_check_beacon_replay() {
+        let mut data = NetworkData {
+            num_peers: 5,
+            ..Default::default()
+        };
+
+        // they have been up for an hour
+        let uptime = 3_600_000;
+
+        assert_eq!(data.check_beacon_replay(2, uptime, 1_000), Ok(()));
+        assert_eq!(data.check_beacon_replay(2, uptime + 30_000, 31_000), Ok(()));
+
+        // the same beacons again
+        assert_eq!(
+            data.check_beacon_replay(2, uptime + 30_000, 32_000),
+            Err(RejectReason::Replayed)
+        );
+        assert_eq!(
+            data.check_beacon_replay(2, uptime, 61_000),
+            Err(RejectReason::Replayed)
+        );
+
+        // they rebooted 10 seconds ago
+        assert_eq!(data.check_beacon_replay(2, 10_000, 91_000), Ok(()));
+        assert_eq!(data.check_beacon_replay(2, 40_000, 121_000), Ok(()));
+
+        // a recording from before the reboot can't be replayed until they have been quiet as long as it was up for
+        assert_eq!(
+            data.check_beacon_replay(2, uptime + 60_000, 151_000),
+            Ok(())
+        );
+        assert_eq!(
+            data.check_beacon_replay(2, uptime, 181_000),
+            Err(RejectReason::Replayed)
+        );
+        assert_eq!(
+            data.check_beacon_replay(2, uptime, 151_000 + uptime),
+            Ok(())
+        );
+
+        assert_eq!(
+            data.check_beacon_replay(5, 10_000, 1_000),
+            Err(RejectReason::Malformed)
+        );
+    }
+
+    #[test]
+    fn test_replayed_packets() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        b.use_receive_interrupt();
+
+        let mut queue = RxQueue::new();
+        let (mut producer, mut consumer) = queue.split();
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.read_received(elapsed_ms.now(), &mut producer).unwrap();
+
+        let mut packet = consumer.dequeue().unwrap();
+
+        // someone recorded it. handling it decrypts it in place, so copy it first
+        let recorded = |packet: &RxPacket| RxPacket {
+            data: packet.data,
+            len: packet.len,
+            info: packet.info,
+            received_at: packet.received_at,
+        };
+
+        let mut replayed = recorded(&packet);
+        let mut late = recorded(&packet);
+
+        assert!(b.handle_received(&mut packet, Some(NOW)).is_some());
+        assert!(b.handle_received(&mut replayed, Some(NOW)).is_none());
+        assert!(b
+            .handle_received(&mut late, Some(NOW + MAX_PACKET_AGE_S + 1))
+            .is_none());
+
+        assert_eq!(b.data.stats.received, 3);
+        assert_eq!(b.data.stats.accepted, 1);
+        assert_eq!(b.data.stats.replayed, 1);
+        assert_eq!(b.data.stats.stale, 1);
+    }
+
+    #[test]
+    fn test_peer_ids_are_bounds_checked() {
+        let air = MockAir::new();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+
+        let header = Header {
+            flags: 0,
+            kind: MessageKind::Location as u8,
+            tx_peer_id: 3,
+            tx_time: NOW,
+            tx_ms: 0,
+            tx_counter: 0,
+        };
+
+        // a peer from a bigger group
+        a.save_message(
+            &header,
+            Message::Location(PeerLocation {
+                peer_id: MAX_PEERS + 1,
+                last_updated_at: NOW,
+                hue: 0,
+                sat: 255,
+                lat: 0.0,
+                lon: 0.0,
+                hops: 0,
+            }),
+        );
+
+        assert!(a
+            .data
+            .peer_locations
+            .iter()
+            .flatten()
+            .all(|(location, _)| location.peer_id != MAX_PEERS + 1));
+
+        assert_eq!(
+            a.data.check_replay(5, NOW, 0, NOW),
+            Err(RejectReason::Malformed)
+        );
+        assert_eq!(a.data.check_replay(4, NOW, 0, NOW), Ok(()));
+    }
+
+    #[test]
+    fn test_bad_group() {
+        let air = MockAir::new();
+
+        let new = |num_peers, my_peer_id| {
+            Network::new(
+                air.radio(),
+                [1; 32],
+                true,
+                num_peers,
+                my_peer_id,
+                0,
+                255,
+                42,
+            )
+            .err()
+        };
+
+        assert_eq!(new(0, None), Some(ConfigError::NumPeers));
+        assert_eq!(new(MAX_PEERS + 1, None), Some(ConfigError::NumPeers));
+        assert_eq!(new(5, Some(5)), Some(ConfigError::PeerId));
+        assert_eq!(new(MAX_PEERS, Some(MAX_PEERS - 1)), None);
+    }
+
+    #[test]
+    fn test_beacons() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        // a has never had a fix or the time
+        let mut a = new_node(&air, [1; 32], Some(2));
+        let mut b = mock_node(&air, [1; 32], 3);
+        let mut c = mock_node(&air, [1; 32], 4);
+
+        a.set_battery(BatteryStatus::Low);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        c.listen_for_time(&elapsed_ms).unwrap();
+
+        a.beacon(&elapsed_ms, Some(0)).unwrap();
+        assert_eq!(air.sent(0), 1);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        c.listen_for_time(&elapsed_ms).unwrap();
+
+        let expected = Beacon {
+            battery: BatteryStatus::Low,
+            position_age_s: None,
+        };
+
+        // with or without the time
+        assert_eq!(b.data.beacons[2], Some(expected));
+        assert_eq!(c.data.beacons[2], Some(expected));
+        assert!(b.data.links[2].is_some());
+
+        // only one per period
+        a.beacon(&elapsed_ms, Some(0)).unwrap();
+        a.beacon(&elapsed_ms, None).unwrap();
+        assert_eq!(air.sent(0), 1);
+
+        // a had a fix a while ago
+        let position = mock_position();
+        a.save_my_location(&elapsed_ms, NOW - MIN_LOST_AFTER_S - 1, &position);
+        elapsed_ms.increment_by(90_000);
+
+        // now a knows the time from b. it sends its old location, and then says that it's old
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        elapsed_ms.increment_by(10);
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        assert_eq!(air.sent(0), 3);
+
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        assert_eq!(air.sent(0), 3);
+
+        assert_eq!(b.data.beacons[2].unwrap().position_age_s, Some(90));
+
+        // a fresh location doesn't need a beacon
+        a.save_my_location(&elapsed_ms, NOW, &position);
+        a.transmit(&elapsed_ms, NOW, 1, 2).unwrap();
+        a.transmit(&elapsed_ms, NOW, 1, 2).unwrap();
+        assert_eq!(air.sent(0), 4);
+    }
+
+    #[test]
+    fn test_sos() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+        let mut c = mock_node(&air, [1; 32], 4);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        a.start_sos(NOW);
+        assert!(a.sos_active());
+        // we don't point at ourselves
+        assert!(a.data.active_sos(NOW).is_none());
+
+        // the sos goes before our location
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        let sos = b.data.active_sos(NOW).unwrap();
+        assert_eq!(sos.peer_id, 2);
+        assert_eq!(sos.hops, 0);
+        assert!(sos.position.is_some());
+        assert!(b.data.peer_location(2).is_none());
+
+        // b relays it first in its own turn. even though that turn is about someone else
+        elapsed_ms.increment_by(10);
+        b.transmit(&elapsed_ms, NOW, 15, 0).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        // c already heard it from a directly
+        assert_eq!(c.data.active_sos(NOW).unwrap().hops, 0);
+        assert_eq!(c.data.stats.accepted, 2);
+
+        // a is fine now. the cancel replaces the sos, even in the same second
+        a.cancel_sos(NOW);
+        assert!(!a.sos_active());
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        elapsed_ms.increment_by(10);
+        a.transmit(&elapsed_ms, NOW, 1, 3).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert!(b.data.active_sos(NOW).is_none());
+
+        // old cancels stop being relayed
+        elapsed_ms.increment_by(10);
+        b.transmit(&elapsed_ms, NOW + MAX_RELAY_AGE_S + 2, 16, 1)
+            .unwrap();
+        let sent = air.sent(1);
+        elapsed_ms.increment_by(10);
+        b.transmit(&elapsed_ms, NOW + MAX_RELAY_AGE_S + 2, 16, 1)
+            .unwrap();
+        assert_eq!(air.sent(1), sent);
+    }
+
+    #[test]
+    fn test_sos_max_age() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+        let mut c = mock_node(&air, [1; 32], 4);
+
+        // c can only hear about a through b
+        air.disconnect(0, 2);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        a.start_sos(NOW);
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.active_sos(NOW).unwrap().last_updated_at, NOW);
+
+        // a has been quiet for too long. b stops relaying it
+        let later = NOW + MAX_SOS_RELAY_AGE_S + 1;
+
+        elapsed_ms.increment_by(10);
+        b.transmit(&elapsed_ms, later, 16, 1).unwrap();
+        c.try_receive(&elapsed_ms, later).unwrap();
+
+        assert!(c.data.active_sos(later).is_none());
+
+        // b doesn't show it anymore either. it would be stuck in the sos pattern until a reboot
+        assert!(b.data.active_sos(later - 1).is_some());
+        assert!(b.data.active_sos(later).is_none());
+
+        // a still needs help. even without a fix, sending it again makes it new enough to relay
+        b.try_receive(&elapsed_ms, later).unwrap();
+        elapsed_ms.increment_by(10);
+        a.transmit(&elapsed_ms, later, 17, 2).unwrap();
+        b.try_receive(&elapsed_ms, later).unwrap();
+
+        assert_eq!(b.data.active_sos(later).unwrap().last_updated_at, later);
+
+        elapsed_ms.increment_by(10);
+        b.transmit(&elapsed_ms, later, 18, 1).unwrap();
+        c.try_receive(&elapsed_ms, later).unwrap();
+
+        let sos = c.data.active_sos(later).unwrap();
+        assert_eq!(sos.peer_id, 2);
+        assert_eq!(sos.hops, 1);
+    }
+
+    #[test]
+    fn test_status() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+        let mut c = mock_node(&air, [1; 32], 4);
+
+        // c only hears about a through b
+        air.disconnect(0, 2);
+
+        a.set_my_status(NOW, Status::NeedWater);
+        assert_eq!(a.data.peer_status(2), Some(Status::NeedWater));
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        // location first, then status
+        for _ in 0..2 {
+            elapsed_ms.increment_by(10);
+            a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+            b.try_receive(&elapsed_ms, NOW).unwrap();
+        }
+
+        assert_eq!(air.sent(0), 2);
+        assert_eq!(b.data.peer_status(2), Some(Status::NeedWater));
+
+        // b relays it to c during its turn about a
+        c.try_receive(&elapsed_ms, NOW).unwrap();
+
+        for _ in 0..2 {
+            elapsed_ms.increment_by(10);
+            b.transmit(&elapsed_ms, NOW, 17, 2).unwrap();
+            c.try_receive(&elapsed_ms, NOW).unwrap();
+        }
+
+        assert_eq!(c.data.peer_status(2), Some(Status::NeedWater));
+
+        // clearing it in the same second still replaces it
+        a.set_my_status(NOW, Status::Clear);
+        assert_eq!(a.data.peer_status(2), None);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        elapsed_ms.increment_by(10);
+        a.transmit(&elapsed_ms, NOW, 1, 2).unwrap();
+        elapsed_ms.increment_by(10);
+        a.transmit(&elapsed_ms, NOW, 1, 2).unwrap();
+
+        for _ in 0..2 {
+            b.try_receive(&elapsed_ms, NOW).unwrap();
+        }
+
+        assert_eq!(b.data.peer_status(2), None);
+    }
+}
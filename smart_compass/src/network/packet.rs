@@ -0,0 +1,365 @@
+//! The bytes that actually go over the air.
+//!
+//! | header (clear) | body (optionally encrypted) | mac |
+//!
+//! The header starts with the protocol version. The high nibble is the major version and the low nibble is the minor.
+//! Packets from firmware with a different major version are ignored. A different minor version has the same header,
+//! so those packets are opened and any message kinds we don't know are skipped. This lets a group update one compass
+//! at a time.
+//!
+//! The mac covers the header and the body as sent, so a tampered header or ciphertext is rejected before decrypting.
+use super::crypto::{NetworkKeys, MAC_LEN};
+
+/// Bump this whenever the header or any of the existing bodies change. Older firmware can't read any of our packets
+pub const PROTOCOL_MAJOR: u8 = 3;
+/// Bump this when a message kind is added. Older firmware skips the kinds it doesn't know
+pub const PROTOCOL_MINOR: u8 = 1;
+pub const PROTOCOL_VERSION: u8 = (PROTOCOL_MAJOR << 4) | PROTOCOL_MINOR;
+
+/// The SX127x FIFO holds at most 255 bytes
+pub const MAX_PACKET_LEN: usize = 255;
+pub const HEADER_LEN: usize = PREFIX_LEN + NETWORK_HASH_WIRE_LEN + NONCE_LEN;
+/// version + flags + kind
+const PREFIX_LEN: usize = 3;
+/// Only the start of the network hash is sent. It just needs to skip other groups' packets quickly.
+/// The mac is what actually keeps them out
+const NETWORK_HASH_WIRE_LEN: usize = 4;
+/// tx_peer_id + tx_time + tx_ms + tx_counter
+const NONCE_LEN: usize = 1 + 4 + 4 + 4;
+pub const MAX_BODY_LEN: usize = MAX_PACKET_LEN - HEADER_LEN - MAC_LEN;
+
+/// The body is encrypted
+pub const FLAG_ENCRYPTED: u8 = 0b0000_0001;
+
+/// Why a received packet was dropped
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RejectReason {
+    /// the packet was too short or failed to decode
+    Malformed,
+    /// the mac did not verify. the packet was corrupted or forged
+    BadMac,
+    WrongNetwork,
+    /// the packet was sent too long ago (or too far in the future)
+    Stale,
+    /// we already received this packet (or a newer one) from this peer
+    Replayed,
+    /// the packet is from firmware that speaks a different major protocol version
+    UnknownVersion,
+    /// the packet is from newer firmware that has a message kind we don't know about
+    UnknownKind,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Header {
+    pub flags: u8,
+    /// what type of message is in the body. see `MessageKind`
+    pub kind: u8,
+    pub tx_peer_id: u8,
+    /// GPS epoch seconds when this was transmitted
+    pub tx_time: u32,
+    /// milliseconds since the transmitting peer booted
+    pub tx_ms: u32,
+    /// starts at a random number every boot and goes up by one for every packet. Two compasses that are still joining
+    /// share a peer id and can transmit in the same ms. This keeps their nonces apart
+    pub tx_counter: u32,
+}
+
+impl Header {
+    fn write(&self, keys: &NetworkKeys, buf: &mut [u8]) {
+        buf[0] = PROTOCOL_VERSION;
+        buf[1] = self.flags;
+        buf[2] = self.kind;
+        buf[PREFIX_LEN..PREFIX_LEN + NETWORK_HASH_WIRE_LEN]
+            .copy_from_slice(&keys.network_hash[..NETWORK_HASH_WIRE_LEN]);
+        buf[PREFIX_LEN + NETWORK_HASH_WIRE_LEN..HEADER_LEN].copy_from_slice(&self.nonce());
+    }
+
+    /// The caller must check the version and network hash first
+    fn read(buf: &[u8]) -> Self {
+        let nonce = &buf[PREFIX_LEN + NETWORK_HASH_WIRE_LEN..HEADER_LEN];
+
+        let mut tx_time = [0u8; 4];
+        tx_time.copy_from_slice(&nonce[1..5]);
+
+        let mut tx_ms = [0u8; 4];
+        tx_ms.copy_from_slice(&nonce[5..9]);
+
+        let mut tx_counter = [0u8; 4];
+        tx_counter.copy_from_slice(&nonce[9..13]);
+
+        Self {
+            flags: buf[1],
+            kind: buf[2],
+            tx_peer_id: nonce[0],
+            tx_time: u32::from_le_bytes(tx_time),
+            tx_ms: u32::from_le_bytes(tx_ms),
+            tx_counter: u32::from_le_bytes(tx_counter),
+        }
+    }
+
+    /// A peer never sends two packets with the same tx_counter, and the counter starts somewhere random every boot, so
+    /// this is safe to use as a nonce
+    fn nonce(&self) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+
+        nonce[0] = self.tx_peer_id;
+        nonce[1..5].copy_from_slice(&self.tx_time.to_le_bytes());
+        nonce[5..9].copy_from_slice(&self.tx_ms.to_le_bytes());
+        nonce[9..13].copy_from_slice(&self.tx_counter.to_le_bytes());
+
+        nonce
+    }
+}
+
+/// Write the header, encrypt the body (if the header's flags say to), and append the mac.
+///
+/// The body must already be written at `buf[HEADER_LEN..HEADER_LEN + body_len]`.
+/// Returns the number of bytes to transmit.
+pub fn seal(keys: &NetworkKeys, header: &Header, buf: &mut [u8], body_len: usize) -> usize {
+    let mac_start = HEADER_LEN + body_len;
+
+    header.write(keys, buf);
+
+    if header.flags & FLAG_ENCRYPTED != 0 {
+        keys.apply_keystream(&header.nonce(), &mut buf[HEADER_LEN..mac_start]);
+    }
+
+    let mac = keys.sign(&buf[..mac_start]);
+    buf[mac_start..mac_start + MAC_LEN].copy_from_slice(&mac);
+
+    mac_start + MAC_LEN
+}
+
+/// Check the network hash and mac and decrypt the body in place.
+pub fn open<'a>(
+    keys: &NetworkKeys,
+    packet: &'a mut [u8],
+) -> Result<(Header, &'a mut [u8]), RejectReason> {
+    if packet.is_empty() {
+        return Err(RejectReason::Malformed);
+    }
+
+    if packet[0] >> 4 != PROTOCOL_MAJOR {
+        // the header might not even be the same length. don't try to read any more
+        return Err(RejectReason::UnknownVersion);
+    }
+
+    if packet.len() < HEADER_LEN + MAC_LEN || packet.len() > MAX_PACKET_LEN {
+        return Err(RejectReason::Malformed);
+    }
+
+    if packet[PREFIX_LEN..PREFIX_LEN + NETWORK_HASH_WIRE_LEN]
+        != keys.network_hash[..NETWORK_HASH_WIRE_LEN]
+    {
+        // this packet is for a different network
+        return Err(RejectReason::WrongNetwork);
+    }
+
+    let header = Header::read(packet);
+
+    let mac_start = packet.len() - MAC_LEN;
+
+    let (signed, mac) = packet.split_at_mut(mac_start);
+
+    if !keys.verify(signed, mac) {
+        return Err(RejectReason::BadMac);
+    }
+
+    let body = &mut signed[HEADER_LEN..];
+
+    if header.flags & FLAG_ENCRYPTED != 0 {
+        keys.apply_keystream(&header.nonce(), body);
+    }
+
+    Ok((header, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{mock_node, NOW};
+    use super::super::MockAir;
+    use super::*;
+    use crate::timers::ElapsedMs;
+
+    fn sealed_packet(keys: &NetworkKeys, flags: u8, body: &[u8]) -> ([u8; MAX_PACKET_LEN], usize) {
+        sealed_packet_with_counter(keys, flags, body, 7)
+    }
+
+    fn sealed_packet_with_counter(
+        keys: &NetworkKeys,
+        flags: u8,
+        body: &[u8],
+        tx_counter: u32,
+    ) -> ([u8; MAX_PACKET_LEN], usize) {
+        let header = Header {
+            flags,
+            kind: 1,
+            tx_peer_id: 3,
+            tx_time: 1_000,
+            tx_ms: 250,
+            tx_counter,
+        };
+
+        let mut buf = [0u8; MAX_PACKET_LEN];
+        buf[HEADER_LEN..HEADER_LEN + body.len()].copy_from_slice(body);
+
+        let n = seal(keys, &header, &mut buf, body.len());
+
+        (buf, n)
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        let (mut buf, n) = sealed_packet(&keys, FLAG_ENCRYPTED, b"meet at the car");
+
+        assert_eq!(n, HEADER_LEN + 15 + MAC_LEN);
+        // the body is not in the clear
+        assert_ne!(&buf[HEADER_LEN..HEADER_LEN + 15], b"meet at the car");
+
+        let (header, body) = open(&keys, &mut buf[..n]).unwrap();
+
+        assert_eq!(header.kind, 1);
+        assert_eq!(header.tx_peer_id, 3);
+        assert_eq!(header.tx_time, 1_000);
+        assert_eq!(header.tx_ms, 250);
+        assert_eq!(header.tx_counter, 7);
+        assert_eq!(body, b"meet at the car");
+    }
+
+    #[test]
+    fn test_counter_changes_keystream() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        // two joining compasses in the same ms. everything else in the header is the same
+        let (a, n) = sealed_packet_with_counter(&keys, FLAG_ENCRYPTED, b"meet at the car", 1);
+        let (b, _) = sealed_packet_with_counter(&keys, FLAG_ENCRYPTED, b"meet at the car", 2);
+
+        assert_ne!(&a[HEADER_LEN..n], &b[HEADER_LEN..n]);
+    }
+
+    #[test]
+    fn test_plaintext_round_trip() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        let (mut buf, n) = sealed_packet(&keys, 0, b"meet at the car");
+
+        assert_eq!(&buf[HEADER_LEN..HEADER_LEN + 15], b"meet at the car");
+
+        let (_, body) = open(&keys, &mut buf[..n]).unwrap();
+
+        assert_eq!(body, b"meet at the car");
+    }
+
+    #[test]
+    fn test_tampered_ciphertext() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        let (mut buf, n) = sealed_packet(&keys, FLAG_ENCRYPTED, b"meet at the car");
+
+        buf[HEADER_LEN] ^= 1;
+
+        assert_eq!(open(&keys, &mut buf[..n]).err(), Some(RejectReason::BadMac));
+    }
+
+    #[test]
+    fn test_tampered_header() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        let (mut buf, n) = sealed_packet(&keys, FLAG_ENCRYPTED, b"meet at the car");
+
+        // pretend to be a different peer
+        buf[PREFIX_LEN + NETWORK_HASH_WIRE_LEN] = 4;
+
+        assert_eq!(open(&keys, &mut buf[..n]).err(), Some(RejectReason::BadMac));
+    }
+
+    #[test]
+    fn test_wrong_network() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+        let other_keys = NetworkKeys::new(&[8u8; 32]);
+
+        let (mut buf, n) = sealed_packet(&keys, FLAG_ENCRYPTED, b"meet at the car");
+
+        assert_eq!(
+            open(&other_keys, &mut buf[..n]).err(),
+            Some(RejectReason::WrongNetwork)
+        );
+    }
+
+    /// Change the version and sign it again like that firmware would have
+    fn resign_as(keys: &NetworkKeys, buf: &mut [u8], n: usize, version: u8) {
+        buf[0] = version;
+
+        let mac = keys.sign(&buf[..n - MAC_LEN]);
+        buf[n - MAC_LEN..n].copy_from_slice(&mac);
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        let (mut buf, n) = sealed_packet(&keys, FLAG_ENCRYPTED, b"meet at the car");
+
+        resign_as(&keys, &mut buf, n, (PROTOCOL_MAJOR + 1) << 4);
+
+        assert_eq!(
+            open(&keys, &mut buf[..n]).err(),
+            Some(RejectReason::UnknownVersion)
+        );
+    }
+
+    #[test]
+    fn test_newer_minor_version() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        let (mut buf, n) = sealed_packet(&keys, FLAG_ENCRYPTED, b"meet at the car");
+
+        resign_as(&keys, &mut buf, n, PROTOCOL_VERSION + 1);
+
+        let (header, body) = open(&keys, &mut buf[..n]).unwrap();
+
+        assert_eq!(header.tx_peer_id, 3);
+        assert_eq!(body, b"meet at the car");
+    }
+
+    #[test]
+    fn test_too_short() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        let mut buf = [0u8; HEADER_LEN];
+        buf[0] = PROTOCOL_VERSION;
+
+        assert_eq!(open(&keys, &mut buf).err(), Some(RejectReason::Malformed));
+        assert_eq!(open(&keys, &mut []).err(), Some(RejectReason::Malformed));
+    }
+
+    #[test]
+    fn test_too_long() {
+        let keys = NetworkKeys::new(&[7u8; 32]);
+
+        let (buf, n) = sealed_packet(&keys, 0, b"meet at the car");
+
+        let mut long = [0u8; MAX_PACKET_LEN + 1];
+        long[..n].copy_from_slice(&buf[..n]);
+
+        assert_eq!(open(&keys, &mut long).err(), Some(RejectReason::Malformed));
+    }
+
+    #[test]
+    fn test_other_networks_are_ignored() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [2; 32], 3);
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.stats.wrong_network, 1);
+        assert!(b.data.peer_location(2).is_none());
+    }
+}
@@ -0,0 +1,39 @@
+//! The parts of a radio that `Network` needs.
+//!
+//! The SX127x is the real one. `MockRadio` (behind the `mock` feature) lets us test on the host.
+use super::RadioSettings;
+
+/// What the radio measured while receiving a packet
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RxInfo {
+    pub rssi: i16,
+    pub snr: Option<i16>,
+}
+
+pub trait Radio {
+    type Error;
+
+    /// Start over. Everything `configure` did is forgotten and the radio is left asleep
+    fn reset(&mut self) -> Result<(), Self::Error>;
+
+    /// Change the frequency, spreading factor, etc. `Network` validates `settings` first, but anyone else might not.
+    /// Settings the radio can't do are an error, not a panic
+    fn configure(&mut self, settings: &RadioSettings) -> Result<(), Self::Error>;
+
+    fn start_transmit(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Returns true once the last transmission is finished
+    fn check_transmit(&mut self) -> Result<bool, Self::Error>;
+
+    fn start_receive(&mut self) -> Result<(), Self::Error>;
+
+    /// Returns true if a packet is waiting. `restart` puts the radio back into receive mode after a bad packet
+    fn check_receive(&mut self, restart: bool) -> Result<bool, Self::Error>;
+
+    /// Copy a received packet into `buf`. Returns the number of bytes copied
+    fn get_received(&mut self, info: &mut RxInfo, buf: &mut [u8]) -> Result<usize, Self::Error>;
+
+    fn sleep(&mut self) -> Result<(), Self::Error>;
+
+    fn silicon_version(&mut self) -> Result<u8, Self::Error>;
+}
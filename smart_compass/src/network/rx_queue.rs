@@ -0,0 +1,113 @@
+//! Packets on their way from the radio's interrupt to the idle loop.
+//!
+//! Polling the radio over SPI from the idle loop takes time away from drawing the lights. Instead, the radio's DIO0 pin
+//! goes high when a packet arrives, and its interrupt copies the packet out of the radio's FIFO (`Network::read_received`)
+//! and into this queue. Checking the mac, decoding, and saving all happen later in the idle loop
+//! (`Network::handle_received`).
+//!
+//! The queue is lock-free. The interrupt only ever enqueues and the idle loop only ever dequeues.
+use super::{RxInfo, MAX_PACKET_LEN};
+use heapless::consts::U4;
+use heapless::spsc::{Consumer, Producer, Queue};
+
+/// TODO: how many? packets come in bursts during someone's turn, but the idle loop should keep up
+pub type RxQueueLen = U4;
+
+pub type RxQueue = Queue<RxPacket, RxQueueLen>;
+pub type RxProducer<'a> = Producer<'a, RxPacket, RxQueueLen>;
+pub type RxConsumer<'a> = Consumer<'a, RxPacket, RxQueueLen>;
+
+/// A packet exactly how the radio gave it to us
+pub struct RxPacket {
+    /// the SX127x FIFO can't hold more than this. packet::open rejects anything longer
+    pub data: [u8; MAX_PACKET_LEN],
+    pub len: usize,
+    pub info: RxInfo,
+    /// `ElapsedMs::now` when the radio said it was done receiving
+    pub received_at: u32,
+}
+
+impl RxPacket {
+    pub fn new(received_at: u32) -> Self {
+        Self {
+            data: [0; MAX_PACKET_LEN],
+            len: 0,
+            info: RxInfo::default(),
+            received_at,
+        }
+    }
+}
+
+/// The queue that the boards use. Only call this once! Tests can make their own `RxQueue`
+/// TODO: do the static outside this?
+pub fn rx_queue() -> (RxProducer<'static>, RxConsumer<'static>) {
+    // `heapless::i` is an "unfortunate implementation detail required to construct heapless types in const context"
+    static mut Q: RxQueue = Queue(heapless::i::Queue::new());
+
+    // NOTE(unsafe) this is fine as long as this is only called once. the boards call it from init
+    unsafe { Q.split() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{mock_node, NOW};
+    use super::super::{Message, MockAir};
+    use super::*;
+    use crate::timers::ElapsedMs;
+
+    #[test]
+    fn test_receive_interrupt() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        b.use_receive_interrupt();
+
+        let mut queue = RxQueue::new();
+        let capacity = queue.capacity();
+        let (mut producer, mut consumer) = queue.split();
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+
+        // the idle loop doesn't poll the radio anymore
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        assert_eq!(air.pending(1), 1);
+        assert_eq!(b.data.stats.received, 0);
+
+        // DIO0 went high
+        elapsed_ms.increment_by(10);
+        b.read_received(elapsed_ms.now(), &mut producer).unwrap();
+        assert_eq!(air.pending(1), 0);
+
+        // and later the idle loop gets to it
+        let mut packet = consumer.dequeue().unwrap();
+        assert_eq!(packet.received_at, 10);
+
+        match b.handle_received(&mut packet, Some(NOW)) {
+            Some(Message::Location(location)) => assert_eq!(location.peer_id, 2),
+            _ => panic!("expected a location"),
+        }
+
+        assert!(b.data.peer_location(2).is_some());
+        assert_eq!(b.data.links[2].unwrap().last_heard_ms, 10);
+
+        // the idle loop fell behind
+        for _ in 0..capacity + 2 {
+            elapsed_ms.increment_by(10);
+            a.transmit_message(&elapsed_ms, NOW, &Message::Ping)
+                .unwrap();
+            b.read_received(elapsed_ms.now(), &mut producer).unwrap();
+        }
+
+        assert_eq!(b.data.stats.rx_queue_full, 2);
+
+        while let Some(mut packet) = consumer.dequeue() {
+            b.handle_received(&mut packet, Some(NOW));
+        }
+
+        assert_eq!(b.data.stats.accepted, 1 + capacity as u32);
+    }
+}
@@ -0,0 +1,367 @@
+//! Who gets to talk when.
+//!
+//! Time is split into segments. Every peer gets `num_peers` segments in a row, and in each of them it broadcasts
+//! what it knows about one peer (itself included). After `num_peers * num_peers` segments, everything repeats.
+//! That grows fast. With MAX_PEERS, a cycle is 512 seconds, so each compass only talks about itself that often. Every
+//! other turn is spent relaying, which is how locations get around faster than that.
+//!
+//! Clocks are never perfect, so transmitters stay quiet for a guard interval at the start of their segment and stop a
+//! guard interval before the end of their window. Receivers listen until a guard interval after the window in case the
+//! transmitter's clock is a little slow, and wake up a guard interval before the next segment in case it's a little
+//! fast. The radio sleeps in between.
+//!
+//! Low batteries only broadcast every few cycles. They still listen in everyone else's segments.
+//!
+//! Without the time (no GPS fix and no peers to tell us), none of that works. Compasses send a beacon once per
+//! `BEACON_PERIOD_MS` of their own uptime instead and listen the rest of the time. Nobody agrees on when a period
+//! starts, so beacons can collide. They are tiny and they keep coming, so enough of them get through.
+use crate::battery::BatteryStatus;
+
+/// How long each peer gets to talk
+pub const TIME_SEGMENT_MS: u32 = 2_000;
+/// How long a transmitter waits at the start of its segment and stops before the end of its window
+pub const GUARD_MS: u32 = 250;
+/// How long a transmitter has to get everything out. Transmitters send everything they have as soon as the guard is
+/// over, so this doesn't need to be the whole segment. Receivers sleep through the rest
+/// TODO: tune this. pins make the burst longer
+pub const TRANSMIT_WINDOW_MS: u32 = 1_000;
+/// With a low battery, only broadcast once every this many cycles
+pub const LOW_BATTERY_BROADCAST_EVERY: u64 = 4;
+/// Without the time, send a beacon once this often
+/// TODO: tune this. it's coarse on purpose. beacons only need to say that we are still around
+pub const BEACON_PERIOD_MS: u32 = 30_000;
+
+/// What the radio should be doing right now
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Slot {
+    /// Our turn. Broadcast what we know about `peer_id`
+    Transmit {
+        time_segment_id: usize,
+        peer_id: usize,
+    },
+    /// Someone else's turn
+    Receive,
+    /// No one should be talking. Either it's our turn but we are too close to the edge (or skipping it to save the
+    /// battery), or it's someone else's turn and they are done
+    Sleep,
+}
+
+pub struct Schedule {
+    num_peers: usize,
+    time_segment_ms: u32,
+    guard_ms: u32,
+    transmit_window_ms: u32,
+    /// only broadcast during every nth cycle
+    broadcast_every: u64,
+}
+
+impl Schedule {
+    /// The transmit window is everything but the guard intervals. Receivers listen the whole segment
+    pub fn new(num_peers: usize, time_segment_ms: u32, guard_ms: u32) -> Self {
+        // with_transmit_window checks this too, but the subtraction below would underflow first
+        assert!(
+            guard_ms <= time_segment_ms / 2,
+            "the guard intervals are longer than the time segment"
+        );
+
+        Self::with_transmit_window(
+            num_peers,
+            time_segment_ms,
+            guard_ms,
+            time_segment_ms - guard_ms * 2,
+        )
+    }
+
+    pub fn with_transmit_window(
+        num_peers: usize,
+        time_segment_ms: u32,
+        guard_ms: u32,
+        transmit_window_ms: u32,
+    ) -> Self {
+        assert!(num_peers > 0);
+        assert!(guard_ms * 2 + transmit_window_ms <= time_segment_ms);
+
+        Self {
+            num_peers,
+            time_segment_ms,
+            guard_ms,
+            transmit_window_ms,
+            broadcast_every: 1,
+        }
+    }
+
+    /// The usual segment length, guard interval, and transmit window
+    pub fn for_peers(num_peers: usize) -> Self {
+        Self::with_transmit_window(num_peers, TIME_SEGMENT_MS, GUARD_MS, TRANSMIT_WINDOW_MS)
+    }
+
+    /// A low battery broadcasts less often
+    pub fn set_battery(&mut self, status: BatteryStatus) {
+        self.broadcast_every = match status {
+            BatteryStatus::Ok => 1,
+            BatteryStatus::Low => LOW_BATTERY_BROADCAST_EVERY,
+        };
+    }
+
+    pub fn num_time_segments(&self) -> usize {
+        self.num_peers * self.num_peers
+    }
+
+    /// How long a transmitter has to get its packets out
+    pub fn transmit_window_ms(&self) -> u32 {
+        self.transmit_window_ms
+    }
+
+    /// How long every peer gets one turn to talk about every peer
+    pub fn cycle_ms(&self) -> u64 {
+        self.time_segment_ms as u64 * self.num_time_segments() as u64
+    }
+
+    /// `ms` is milliseconds past `epoch_seconds`
+    pub fn slot(&self, my_peer_id: usize, epoch_seconds: u32, ms: u32) -> Slot {
+        let now_ms = epoch_seconds as u64 * 1000 + ms as u64;
+
+        let time_segment_id =
+            ((now_ms / self.time_segment_ms as u64) % self.num_time_segments() as u64) as usize;
+        let segment_offset_ms = (now_ms % self.time_segment_ms as u64) as u32;
+
+        let broadcasting_peer_id = time_segment_id / self.num_peers;
+        let broadcasted_peer_id = time_segment_id % self.num_peers;
+
+        if broadcasting_peer_id != my_peer_id {
+            // joining compasses claim during the first guard. the transmitter's burst might run late by a guard, or
+            // start early enough to land at the end of the segment before
+            if segment_offset_ms < self.guard_ms * 2 + self.transmit_window_ms
+                || segment_offset_ms >= self.time_segment_ms - self.guard_ms
+            {
+                return Slot::Receive;
+            }
+
+            return Slot::Sleep;
+        }
+
+        if (now_ms / self.cycle_ms()) % self.broadcast_every != 0 {
+            // saving the battery
+            return Slot::Sleep;
+        }
+
+        if segment_offset_ms < self.guard_ms
+            || segment_offset_ms >= self.guard_ms + self.transmit_window_ms
+        {
+            return Slot::Sleep;
+        }
+
+        Slot::Transmit {
+            time_segment_id,
+            peer_id: broadcasted_peer_id,
+        }
+    }
+
+    /// Use this instead of `slot` when we don't know the time. `now` is `ElapsedMs::now`. Returns the id of the
+    /// current beacon period once we are allowed to beacon in it. Send at most one beacon per id!
+    ///
+    /// Each peer starts at a different offset into the period so that compasses that booted together don't collide
+    /// every time
+    pub fn beacon_slot(&self, my_peer_id: usize, now: u32) -> Option<u32> {
+        let beacon_id = now / BEACON_PERIOD_MS;
+        let offset_ms = now % BEACON_PERIOD_MS;
+
+        let my_offset_ms = BEACON_PERIOD_MS / self.num_peers as u32 * my_peer_id as u32;
+
+        if beacon_id as u64 % self.broadcast_every != 0 {
+            // saving the battery
+            return None;
+        }
+
+        if offset_ms < my_offset_ms {
+            return None;
+        }
+
+        Some(beacon_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::packet::MAX_PACKET_LEN;
+    use super::super::settings::RadioSettings;
+    use super::super::{MAX_RELAY_AGE_S, MAX_RELAY_HOPS};
+    use super::*;
+    use crate::MAX_PEERS;
+
+    #[test]
+    fn test_full_group_cycle() {
+        let schedule = Schedule::for_peers(MAX_PEERS);
+
+        assert_eq!(schedule.cycle_ms(), 512_000);
+
+        // a location can take a whole cycle per hop. it has to still be young enough to relay after all of them
+        assert!(schedule.cycle_ms() * MAX_RELAY_HOPS as u64 <= MAX_RELAY_AGE_S as u64 * 1000);
+    }
+
+    #[test]
+    fn test_segment_boundaries() {
+        let schedule = Schedule::new(3, 2_000, 250);
+
+        // segment 0 belongs to peer 0 and is about peer 0
+        assert_eq!(schedule.slot(0, 0, 0), Slot::Sleep);
+        assert_eq!(schedule.slot(0, 0, 249), Slot::Sleep);
+        assert_eq!(
+            schedule.slot(0, 0, 250),
+            Slot::Transmit {
+                time_segment_id: 0,
+                peer_id: 0
+            }
+        );
+        assert_eq!(
+            schedule.slot(0, 1, 749),
+            Slot::Transmit {
+                time_segment_id: 0,
+                peer_id: 0
+            }
+        );
+        assert_eq!(schedule.slot(0, 1, 750), Slot::Sleep);
+        assert_eq!(schedule.slot(1, 0, 0), Slot::Receive);
+        assert_eq!(schedule.slot(1, 1, 999), Slot::Receive);
+
+        // segment 1 is still peer 0's, but it is about peer 1
+        assert_eq!(
+            schedule.slot(0, 2, 500),
+            Slot::Transmit {
+                time_segment_id: 1,
+                peer_id: 1
+            }
+        );
+
+        // segment 3 is peer 1's turn
+        assert_eq!(schedule.slot(0, 6, 500), Slot::Receive);
+        assert_eq!(
+            schedule.slot(1, 6, 500),
+            Slot::Transmit {
+                time_segment_id: 3,
+                peer_id: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_wraps_around() {
+        let schedule = Schedule::new(3, 2_000, 250);
+
+        assert_eq!(schedule.num_time_segments(), 9);
+
+        // 9 segments * 2 seconds later, we are back at the start
+        assert_eq!(schedule.slot(0, 18, 500), schedule.slot(0, 0, 500));
+        assert_eq!(schedule.slot(2, 17, 500), schedule.slot(2, 35, 500));
+    }
+
+    #[test]
+    fn test_only_one_transmitter() {
+        let schedule = Schedule::for_peers(12);
+
+        for epoch_seconds in 1_600_000_000..1_600_000_100 {
+            for ms in (0..1000).step_by(50) {
+                let transmitters = (0..12)
+                    .filter(|peer_id| {
+                        matches!(
+                            schedule.slot(*peer_id, epoch_seconds, ms),
+                            Slot::Transmit { .. }
+                        )
+                    })
+                    .count();
+
+                assert!(transmitters <= 1);
+
+                // whenever someone is transmitting, everyone else is listening
+                if transmitters == 1 {
+                    let receivers = (0..12)
+                        .filter(|peer_id| {
+                            schedule.slot(*peer_id, epoch_seconds, ms) == Slot::Receive
+                        })
+                        .count();
+
+                    assert_eq!(receivers, 11);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_receivers_sleep_after_the_window() {
+        let schedule = Schedule::with_transmit_window(3, 2_000, 250, 1_000);
+
+        // peer 0 transmits from 250 to 1250. peer 1 listens a little longer in case peer 0's clock is late
+        assert_eq!(schedule.slot(1, 0, 0), Slot::Receive);
+        assert_eq!(schedule.slot(1, 1, 499), Slot::Receive);
+        assert_eq!(schedule.slot(1, 1, 500), Slot::Sleep);
+        assert_eq!(schedule.slot(1, 1, 749), Slot::Sleep);
+        // peer 1's clock might be behind
+        assert_eq!(schedule.slot(1, 1, 750), Slot::Receive);
+
+        assert_eq!(
+            schedule.slot(0, 1, 249),
+            Slot::Transmit {
+                time_segment_id: 0,
+                peer_id: 0
+            }
+        );
+        assert_eq!(schedule.slot(0, 1, 250), Slot::Sleep);
+    }
+
+    #[test]
+    fn test_low_battery() {
+        let mut schedule = Schedule::new(3, 2_000, 250);
+        schedule.set_battery(BatteryStatus::Low);
+
+        let cycle_s = (schedule.cycle_ms() / 1000) as u32;
+
+        let transmits = (0..LOW_BATTERY_BROADCAST_EVERY as u32)
+            .filter(|cycle| {
+                matches!(
+                    schedule.slot(0, cycle * cycle_s, 500),
+                    Slot::Transmit { .. }
+                )
+            })
+            .count();
+
+        assert_eq!(transmits, 1);
+
+        // but we still listen to everyone else
+        for cycle in 0..LOW_BATTERY_BROADCAST_EVERY as u32 {
+            assert_eq!(schedule.slot(1, cycle * cycle_s, 500), Slot::Receive);
+        }
+
+        schedule.set_battery(BatteryStatus::Ok);
+        assert_ne!(schedule.slot(0, cycle_s, 500), Slot::Sleep);
+    }
+
+    #[test]
+    fn test_beacon_slot() {
+        let mut schedule = Schedule::for_peers(3);
+
+        assert_eq!(schedule.beacon_slot(0, 0), Some(0));
+        assert_eq!(schedule.beacon_slot(1, 0), None);
+        assert_eq!(schedule.beacon_slot(1, BEACON_PERIOD_MS / 3), Some(0));
+        assert_eq!(schedule.beacon_slot(2, BEACON_PERIOD_MS - 1), Some(0));
+        assert_eq!(schedule.beacon_slot(2, BEACON_PERIOD_MS), None);
+        assert_eq!(schedule.beacon_slot(0, BEACON_PERIOD_MS), Some(1));
+
+        schedule.set_battery(BatteryStatus::Low);
+
+        let beacons = (0..LOW_BATTERY_BROADCAST_EVERY as u32)
+            .filter(|period| schedule.beacon_slot(0, period * BEACON_PERIOD_MS).is_some())
+            .count();
+
+        assert_eq!(beacons, 1);
+    }
+
+    #[test]
+    fn test_max_packet_fits_in_window() {
+        let schedule = Schedule::for_peers(crate::MAX_PEERS);
+
+        // the defaults have to work with any group size
+        assert!(
+            RadioSettings::default().airtime_ms(MAX_PACKET_LEN) < schedule.transmit_window_ms()
+        );
+    }
+}
@@ -0,0 +1,431 @@
+//! LoRa settings. Everyone in a group has to use the same ones or they won't hear each other.
+//!
+//! Faster settings (lower spreading factor, wider bandwidth) leave the air free sooner. Slower settings reach farther.
+//! Crowded places might want a different frequency than everyone else at the event.
+//!
+//! Everything is checked against the region's rules before it gets anywhere near the radio.
+//!
+//! In the US, 47 CFR 15.247 only allows this much power from frequency hoppers (15.247(a)(1)) and from "digitally
+//! modulated" systems with a 6dB bandwidth of at least 500kHz (15.247(a)(2)). We stay on one channel, so we are the
+//! second kind. That means 500kHz. The 400ms dwell time limit in 15.247(a)(1)(i) is for hoppers and doesn't apply.
+//! A narrower channel that doesn't hop falls under 15.249 instead, which allows about -1dBm. That's less than the
+//! RFM95 can do.
+//! TODO: double check the EU rules before shipping anything
+use super::airtime::airtime_ms;
+
+/// The LoRaWAN sync word. We don't want to wake up every gateway in range
+pub const LORAWAN_SYNC_WORD: u8 = 0x34;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Region {
+    /// 902-928MHz. FCC part 15
+    Us915,
+    /// 863-870MHz. ETSI EN 300 220
+    Eu868,
+}
+
+impl Region {
+    /// The lowest and highest frequencies that we can use
+    pub fn band_hz(self) -> (u32, u32) {
+        match self {
+            Region::Us915 => (902_000_000, 928_000_000),
+            Region::Eu868 => (863_000_000, 870_000_000),
+        }
+    }
+
+    /// The FCC allows 30dBm (15.247(b)(3)), but the RFM95 can't do more than 20dBm
+    pub fn max_tx_power_dbm(self) -> i8 {
+        match self {
+            Region::Us915 => 20,
+            Region::Eu868 => 14,
+        }
+    }
+
+    pub fn default_frequency_hz(self) -> u32 {
+        match self {
+            Region::Us915 => 915_000_000,
+            Region::Eu868 => 868_100_000,
+        }
+    }
+
+    /// The narrowest channel that the region lets us use. Narrower reaches farther
+    pub fn default_bandwidth_hz(self) -> u32 {
+        match self {
+            Region::Us915 => 500_000,
+            Region::Eu868 => 125_000,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SettingsError {
+    /// the channel doesn't fit inside the region's band
+    Frequency,
+    /// SF7 to SF12. SF6 needs implicit headers, and we don't use those
+    SpreadingFactor,
+    /// 125 or 250kHz in EU868. US915 has to use 500kHz since we don't hop
+    Bandwidth,
+    /// 4/5 to 4/8
+    CodingRate,
+    /// more than the region allows (or less than the radio can do)
+    TxPower,
+    /// the LoRaWAN sync word
+    SyncWord,
+    /// the longest packet doesn't fit in our turn of the schedule
+    TooSlow,
+    /// a line in the settings file that we don't understand
+    Parse,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RadioSettings {
+    pub region: Region,
+    pub frequency_hz: u32,
+    /// 7 to 12. Every step up reaches a little farther but takes about twice as long
+    pub spreading_factor: u8,
+    pub bandwidth_hz: u32,
+    /// The 5 in 4/5. Up to 8. Higher recovers from more interference but takes longer
+    pub coding_rate: u8,
+    pub tx_power_dbm: i8,
+    /// Radios only hear packets with the same sync word
+    pub sync_word: u8,
+}
+
+impl RadioSettings {
+    /// Fast, short range, and legal everywhere in the region
+    pub fn for_region(region: Region) -> Self {
+        Self {
+            region,
+            frequency_hz: region.default_frequency_hz(),
+            spreading_factor: 7,
+            bandwidth_hz: region.default_bandwidth_hz(),
+            coding_rate: 5,
+            tx_power_dbm: 14,
+            sync_word: 0x12,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        let (band_low_hz, band_high_hz) = self.region.band_hz();
+
+        // the whole channel has to be in the band. not just the center
+        let half_bandwidth_hz = self.bandwidth_hz / 2;
+
+        if self.frequency_hz < band_low_hz + half_bandwidth_hz
+            || self.frequency_hz > band_high_hz - half_bandwidth_hz
+        {
+            return Err(SettingsError::Frequency);
+        }
+
+        if self.spreading_factor < 7 || self.spreading_factor > 12 {
+            return Err(SettingsError::SpreadingFactor);
+        }
+
+        match (self.region, self.bandwidth_hz) {
+            (Region::Us915, 500_000) | (Region::Eu868, 125_000) | (Region::Eu868, 250_000) => {}
+            _ => return Err(SettingsError::Bandwidth),
+        }
+
+        if self.coding_rate < 5 || self.coding_rate > 8 {
+            return Err(SettingsError::CodingRate);
+        }
+
+        if self.tx_power_dbm < 2 || self.tx_power_dbm > self.region.max_tx_power_dbm() {
+            return Err(SettingsError::TxPower);
+        }
+
+        if self.sync_word == LORAWAN_SYNC_WORD {
+            return Err(SettingsError::SyncWord);
+        }
+
+        Ok(())
+    }
+
+    /// The most we can transmit in an hour, in 0.1%. None if the region doesn't limit it
+    /// TODO: EU868 also allows more with listen before talk
+    pub fn max_duty_cycle_permille(&self) -> Option<u32> {
+        if self.region != Region::Eu868 {
+            // 15.247 only limits our power
+            return None;
+        }
+
+        let half_bandwidth_hz = self.bandwidth_hz / 2;
+        let low_hz = self.frequency_hz - half_bandwidth_hz;
+        let high_hz = self.frequency_hz + half_bandwidth_hz;
+
+        // ETSI EN 300 220 sub-bands. anything outside of these gets the strictest limit
+        let permille = if low_hz >= 868_000_000 && high_hz <= 868_600_000 {
+            10
+        } else if low_hz >= 869_400_000 && high_hz <= 869_650_000 {
+            100
+        } else {
+            1
+        };
+
+        Some(permille)
+    }
+
+    /// How long a packet with this many bytes is on the air
+    pub fn airtime_ms(&self, payload_len: usize) -> u32 {
+        airtime_ms(
+            payload_len,
+            self.spreading_factor,
+            self.bandwidth_hz,
+            self.coding_rate,
+        )
+    }
+
+    /// Change one setting from a "key = value" line. The region moves the frequency and bandwidth to its defaults, but
+    /// only if they are still the old region's defaults. That way the order of the lines in a file doesn't matter
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), SettingsError> {
+        let value = value.trim();
+
+        match key.trim() {
+            "region" => {
+                let old_region = self.region;
+
+                self.region = match value {
+                    "US915" => Region::Us915,
+                    "EU868" => Region::Eu868,
+                    _ => return Err(SettingsError::Parse),
+                };
+
+                if self.frequency_hz == old_region.default_frequency_hz() {
+                    self.frequency_hz = self.region.default_frequency_hz();
+                }
+
+                if self.bandwidth_hz == old_region.default_bandwidth_hz() {
+                    self.bandwidth_hz = self.region.default_bandwidth_hz();
+                }
+            }
+            "frequency_hz" => self.frequency_hz = parse(value)?,
+            "spreading_factor" => self.spreading_factor = parse(value)?,
+            "bandwidth_hz" => self.bandwidth_hz = parse(value)?,
+            "coding_rate" => self.coding_rate = parse(value)?,
+            "tx_power_dbm" => self.tx_power_dbm = parse(value)?,
+            "sync_word" => {
+                self.sync_word = u8::from_str_radix(value.trim_start_matches("0x"), 16)
+                    .map_err(|_| SettingsError::Parse)?
+            }
+            _ => return Err(SettingsError::Parse),
+        }
+
+        Ok(())
+    }
+
+    /// Read "key = value" lines on top of the defaults. Blank lines and lines starting with '#' are skipped
+    pub fn parse(text: &str) -> Result<Self, SettingsError> {
+        let mut settings = Self::default();
+
+        for line in text.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+
+            let key = parts.next().ok_or(SettingsError::Parse)?;
+            let value = parts.next().ok_or(SettingsError::Parse)?;
+
+            settings.set(key, value)?;
+        }
+
+        settings.validate()?;
+
+        Ok(settings)
+    }
+}
+
+impl Default for RadioSettings {
+    fn default() -> Self {
+        Self::for_region(Region::Us915)
+    }
+}
+
+fn parse<T: core::str::FromStr>(value: &str) -> Result<T, SettingsError> {
+    value.parse().map_err(|_| SettingsError::Parse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::test_util::{mock_node, NOW};
+    use super::super::MockAir;
+    use super::*;
+    use crate::timers::ElapsedMs;
+
+    #[test]
+    fn test_defaults_are_legal() {
+        assert_eq!(RadioSettings::default().validate(), Ok(()));
+        assert_eq!(RadioSettings::for_region(Region::Eu868).validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_regions() {
+        let us = RadioSettings::default();
+
+        // the channel hangs off the edge of the band
+        let settings = RadioSettings {
+            frequency_hz: 927_950_000,
+            ..us
+        };
+        assert_eq!(settings.validate(), Err(SettingsError::Frequency));
+
+        // a US frequency in the EU
+        let settings = RadioSettings {
+            region: Region::Eu868,
+            ..us
+        };
+        assert_eq!(settings.validate(), Err(SettingsError::Frequency));
+
+        let eu = RadioSettings::for_region(Region::Eu868);
+
+        let settings = RadioSettings {
+            tx_power_dbm: 20,
+            ..eu
+        };
+        assert_eq!(settings.validate(), Err(SettingsError::TxPower));
+
+        let settings = RadioSettings {
+            bandwidth_hz: 500_000,
+            ..eu
+        };
+        assert_eq!(settings.validate(), Err(SettingsError::Bandwidth));
+    }
+
+    #[test]
+    fn test_us_bandwidth() {
+        // we don't hop, so a narrow US channel would be stuck at 15.249's power
+        let settings = RadioSettings {
+            bandwidth_hz: 125_000,
+            ..RadioSettings::default()
+        };
+        assert_eq!(settings.validate(), Err(SettingsError::Bandwidth));
+
+        // there is no dwell time limit without hopping. even the slowest packets are fine
+        let settings = RadioSettings {
+            spreading_factor: 12,
+            ..RadioSettings::default()
+        };
+        assert_eq!(settings.validate(), Ok(()));
+
+        // switching regions switches the bandwidth too
+        let mut settings = RadioSettings::default();
+        settings.set("region", "EU868").unwrap();
+        assert_eq!(settings.bandwidth_hz, 125_000);
+        assert_eq!(settings.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_region_line_order() {
+        let region_first = "region = EU868\nfrequency_hz = 869525000\nbandwidth_hz = 250000";
+        let region_last = "frequency_hz = 869525000\nbandwidth_hz = 250000\nregion = EU868";
+
+        let settings = RadioSettings::parse(region_first).unwrap();
+
+        assert_eq!(settings.frequency_hz, 869_525_000);
+        assert_eq!(settings.bandwidth_hz, 250_000);
+        assert_eq!(RadioSettings::parse(region_last), Ok(settings));
+
+        // whatever the file doesn't say still comes from the region
+        let settings = RadioSettings::parse("frequency_hz = 869525000\nregion = EU868").unwrap();
+
+        assert_eq!(settings.bandwidth_hz, 125_000);
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        assert_eq!(RadioSettings::default().max_duty_cycle_permille(), None);
+
+        let eu = RadioSettings::for_region(Region::Eu868);
+        assert_eq!(eu.max_duty_cycle_permille(), Some(10));
+
+        let settings = RadioSettings {
+            frequency_hz: 869_525_000,
+            ..eu
+        };
+        assert_eq!(settings.max_duty_cycle_permille(), Some(100));
+
+        let settings = RadioSettings {
+            frequency_hz: 866_000_000,
+            ..eu
+        };
+        assert_eq!(settings.max_duty_cycle_permille(), Some(1));
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = "# a quieter channel\nregion = EU868\nfrequency_hz = 869525000\n\nspreading_factor=9\nsync_word = 0x2B\n";
+
+        let settings = RadioSettings::parse(text).unwrap();
+
+        assert_eq!(settings.region, Region::Eu868);
+        assert_eq!(settings.frequency_hz, 869_525_000);
+        assert_eq!(settings.spreading_factor, 9);
+        assert_eq!(settings.sync_word, 0x2B);
+        assert_eq!(settings.tx_power_dbm, 14);
+
+        assert_eq!(
+            RadioSettings::parse("volume = 11"),
+            Err(SettingsError::Parse)
+        );
+        assert_eq!(
+            RadioSettings::parse("sync_word = 0x34"),
+            Err(SettingsError::SyncWord)
+        );
+    }
+
+    #[test]
+    fn test_radio_settings() {
+        let air = MockAir::new();
+        let elapsed_ms = ElapsedMs::default();
+
+        let mut a = mock_node(&air, [1; 32], 2);
+        let mut b = mock_node(&air, [1; 32], 3);
+
+        let quieter_channel = RadioSettings {
+            frequency_hz: 920_000_000,
+            ..RadioSettings::default()
+        };
+
+        a.set_radio_settings(quieter_channel).unwrap();
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        a.transmit(&elapsed_ms, NOW, 0, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.stats.received, 0);
+
+        // now they are on the same channel again
+        b.set_radio_settings(quieter_channel).unwrap();
+
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+        a.transmit(&elapsed_ms, NOW, 1, 2).unwrap();
+        b.try_receive(&elapsed_ms, NOW).unwrap();
+
+        assert_eq!(b.data.stats.accepted, 1);
+
+        // bad settings never make it to the radio
+        let too_loud = RadioSettings {
+            tx_power_dbm: 30,
+            ..quieter_channel
+        };
+        assert_eq!(
+            a.set_radio_settings(too_loud),
+            Err(SettingsError::TxPower.into())
+        );
+
+        // legal in the EU, but a full packet takes seconds. that doesn't fit in our turn
+        let too_slow = RadioSettings {
+            spreading_factor: 12,
+            ..RadioSettings::for_region(Region::Eu868)
+        };
+        assert_eq!(
+            a.set_radio_settings(too_slow),
+            Err(SettingsError::TooSlow.into())
+        );
+
+        assert_eq!(a.radio_settings(), &quieter_channel);
+    }
+}
@@ -0,0 +1,158 @@
+//! The real radio. An RFM95 (or any other SX127x) on SPI.
+//!
+//! `Radio` is deliberately not imported here. Its methods have the same names as the `radio` crate's traits
+use super::{NetworkError, RadioSettings, RxInfo, SettingsError};
+use radio_sx127x::device::lora::{Bandwidth, CodingRate, SpreadingFactor};
+use radio_sx127x::device::regs;
+use radio_sx127x::device::PaConfig;
+use radio_sx127x::prelude::*;
+
+pub type MyRadio<Spi, SpiError, CsPin, BusyPin, ReadyPin, ResetPin, PinError, Delay> = Sx127x<
+    SpiWrapper<Spi, SpiError, CsPin, BusyPin, ReadyPin, ResetPin, PinError, Delay>,
+    SpiError,
+    PinError,
+>;
+
+/// Connect to an RFM95 (or any other SX127x) over SPI. It starts out with the default `RadioSettings`.
+/// The pins are gone if this fails. There's no trying again without rebooting
+pub fn new_sx127x<Spi, SpiError, CsPin, BusyPin, ReadyPin, ResetPin, PinError, Delay>(
+    spi: Spi,
+    cs: CsPin,
+    busy: BusyPin,
+    ready: ReadyPin,
+    reset: ResetPin,
+    delay: Delay,
+) -> Result<
+    MyRadio<Spi, SpiError, CsPin, BusyPin, ReadyPin, ResetPin, PinError, Delay>,
+    NetworkError<radio_sx127x::Error<SpiError, PinError>>,
+>
+where
+    Spi: embedded_hal::blocking::spi::Transfer<u8, Error = SpiError>
+        + embedded_hal::blocking::spi::Write<u8, Error = SpiError>,
+    CsPin: embedded_hal::digital::v2::OutputPin<Error = PinError>,
+    BusyPin: embedded_hal::digital::v2::InputPin<Error = PinError>,
+    ReadyPin: embedded_hal::digital::v2::InputPin<Error = PinError>,
+    ResetPin: embedded_hal::digital::v2::OutputPin<Error = PinError>,
+    Delay: embedded_hal::blocking::delay::DelayMs<u32>,
+{
+    // `Network` starts out with the defaults too. `Network::set_radio_settings` changes them
+    let settings = RadioSettings::default();
+
+    let config = sx127x_config(&settings)?;
+
+    let mut radio =
+        Sx127x::spi(spi, cs, busy, ready, reset, delay, &config).map_err(NetworkError::Init)?;
+
+    // radio_sx127x doesn't know about sync words
+    radio
+        .write_reg(regs::LoRa::SYNCWORD, settings.sync_word)
+        .map_err(NetworkError::Init)?;
+
+    Ok(radio)
+}
+
+/// Convert our settings into radio_sx127x's. Anything the SX127x can't do at all is an error here. Whether it's legal
+/// is up to `RadioSettings::validate`
+fn sx127x_config(settings: &RadioSettings) -> Result<Config, SettingsError> {
+    let bw = match settings.bandwidth_hz {
+        125_000 => Bandwidth::Bw125kHz,
+        250_000 => Bandwidth::Bw250kHz,
+        500_000 => Bandwidth::Bw500kHz,
+        _ => return Err(SettingsError::Bandwidth),
+    };
+
+    let sf = match settings.spreading_factor {
+        7 => SpreadingFactor::Sf7,
+        8 => SpreadingFactor::Sf8,
+        9 => SpreadingFactor::Sf9,
+        10 => SpreadingFactor::Sf10,
+        11 => SpreadingFactor::Sf11,
+        12 => SpreadingFactor::Sf12,
+        _ => return Err(SettingsError::SpreadingFactor),
+    };
+
+    let cr = match settings.coding_rate {
+        5 => CodingRate::Cr4_5,
+        6 => CodingRate::Cr4_6,
+        7 => CodingRate::Cr4_7,
+        8 => CodingRate::Cr4_8,
+        _ => return Err(SettingsError::CodingRate),
+    };
+
+    Ok(Config {
+        channel: Channel::LoRa(LoRaChannel {
+            freq: settings.frequency_hz,
+            bw,
+            sf,
+            cr,
+        }),
+        pa_config: PaConfig {
+            power: settings.tx_power_dbm,
+            ..Default::default()
+        },
+        ..Default::default()
+    })
+}
+
+impl<Spi, SpiError, CsPin, BusyPin, ReadyPin, ResetPin, PinError, Delay> super::Radio
+    for MyRadio<Spi, SpiError, CsPin, BusyPin, ReadyPin, ResetPin, PinError, Delay>
+where
+    Spi: embedded_hal::blocking::spi::Transfer<u8, Error = SpiError>
+        + embedded_hal::blocking::spi::Write<u8, Error = SpiError>,
+    CsPin: embedded_hal::digital::v2::OutputPin<Error = PinError>,
+    BusyPin: embedded_hal::digital::v2::InputPin<Error = PinError>,
+    ReadyPin: embedded_hal::digital::v2::InputPin<Error = PinError>,
+    ResetPin: embedded_hal::digital::v2::OutputPin<Error = PinError>,
+    Delay: embedded_hal::blocking::delay::DelayMs<u32>,
+{
+    type Error = radio_sx127x::Error<SpiError, PinError>;
+
+    fn reset(&mut self) -> Result<(), Self::Error> {
+        Sx127x::reset(self)
+    }
+
+    fn configure(&mut self, settings: &RadioSettings) -> Result<(), Self::Error> {
+        // `Radio` is public, so this can be called with settings that were never validated
+        let config = sx127x_config(settings)
+            .map_err(|_| radio_sx127x::Error::<SpiError, PinError>::InvalidConfiguration)?;
+
+        Sx127x::configure(self, &config)?;
+
+        self.write_reg(regs::LoRa::SYNCWORD, settings.sync_word)
+    }
+
+    fn start_transmit(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.start_transmit(data)
+    }
+
+    fn check_transmit(&mut self) -> Result<bool, Self::Error> {
+        self.check_transmit()
+    }
+
+    fn start_receive(&mut self) -> Result<(), Self::Error> {
+        self.start_receive()
+    }
+
+    fn check_receive(&mut self, restart: bool) -> Result<bool, Self::Error> {
+        self.check_receive(restart)
+    }
+
+    fn get_received(&mut self, info: &mut RxInfo, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut packet_info = PacketInfo::default();
+
+        let n = self.get_received(&mut packet_info, buf)?;
+
+        info.rssi = packet_info.rssi;
+        info.snr = packet_info.snr;
+
+        Ok(n)
+    }
+
+    fn sleep(&mut self) -> Result<(), Self::Error> {
+        self.set_state(State::Sleep)
+    }
+
+    fn silicon_version(&mut self) -> Result<u8, Self::Error> {
+        Sx127x::silicon_version(self)
+    }
+}
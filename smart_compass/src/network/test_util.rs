@@ -0,0 +1,74 @@
+//! Compasses for the network tests. Every test module builds them the same way so that the tests only have to say
+//! what is different about theirs.
+use super::packet::{self, HEADER_LEN};
+use super::{
+    Header, Message, MockAir, MockRadio, Network, NetworkKeys, NetworkSecret, Radio, MAX_PACKET_LEN,
+};
+use crate::timers::ElapsedMs;
+use yanp::parse::{GpsPosition, LatitudeDirection, LongitudeDirection};
+
+/// GPS time when the tests start
+pub const NOW: u32 = 1_600_000_000;
+
+/// Where every mock compass had its fix
+pub fn mock_position() -> GpsPosition {
+    GpsPosition {
+        lat: 37.7749,
+        lat_dir: LatitudeDirection::North,
+        lon: -122.4194,
+        lon_dir: LongitudeDirection::West,
+    }
+}
+
+/// A compass in a group of 5 that has never had a fix. None if it still has to join
+pub fn new_node(
+    air: &MockAir,
+    network_secret: NetworkSecret,
+    my_peer_id: Option<usize>,
+) -> Network<MockRadio> {
+    Network::new(
+        air.radio(),
+        network_secret,
+        true,
+        5,
+        my_peer_id,
+        0,
+        255,
+        // anything different for every node
+        my_peer_id.map_or(42, |x| x as u32 * 1_000_000),
+    )
+    .unwrap()
+}
+
+/// A compass in a group of 5 that got a fix at NOW
+pub fn mock_node(
+    air: &MockAir,
+    network_secret: NetworkSecret,
+    my_peer_id: usize,
+) -> Network<MockRadio> {
+    let mut network = new_node(air, network_secret, Some(my_peer_id));
+
+    network.save_my_location(&ElapsedMs::default(), NOW, &mock_position());
+
+    network
+}
+
+/// Send a message from a radio that isn't a compass. This is how tests say things that a real compass wouldn't
+pub fn inject(
+    radio: &mut MockRadio,
+    network_secret: &NetworkSecret,
+    header: &Header,
+    message: &Message,
+) {
+    let mut buf = [0u8; MAX_PACKET_LEN];
+
+    let body_len = message.encode(&mut buf[HEADER_LEN..]).unwrap();
+    let n = packet::seal(
+        &NetworkKeys::new(network_secret),
+        header,
+        &mut buf,
+        body_len,
+    );
+
+    radio.start_transmit(&buf[..n]).unwrap();
+}
@@ -0,0 +1,173 @@
+//! Compact binary encoding for message bodies.
+//!
+//! Every byte costs airtime, so this is much tighter than CBOR.
+//! Integers are little endian. Coordinates are micro-degrees in an i32 (about 11cm of precision).
+
+/// The buffer was too short (or a value didn't fit in its bytes)
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WireError;
+
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    /// The number of bytes written
+    pub fn bytes_written(&self) -> usize {
+        self.len
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> Result<(), WireError> {
+        let end = self.len + bytes.len();
+
+        if end > self.buf.len() {
+            return Err(WireError);
+        }
+
+        self.buf[self.len..end].copy_from_slice(bytes);
+        self.len = end;
+
+        Ok(())
+    }
+
+    pub fn u8(&mut self, x: u8) -> Result<(), WireError> {
+        self.bytes(&[x])
+    }
+
+    pub fn u16(&mut self, x: u16) -> Result<(), WireError> {
+        self.bytes(&x.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, x: u32) -> Result<(), WireError> {
+        self.bytes(&x.to_le_bytes())
+    }
+
+    pub fn i32(&mut self, x: i32) -> Result<(), WireError> {
+        self.bytes(&x.to_le_bytes())
+    }
+
+    pub fn degrees(&mut self, x: f32) -> Result<(), WireError> {
+        self.i32(degrees_to_micro(x))
+    }
+}
+
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], WireError> {
+        let end = self.pos + len;
+
+        if end > self.buf.len() {
+            return Err(WireError);
+        }
+
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, WireError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, WireError> {
+        let mut x = [0u8; 2];
+        x.copy_from_slice(self.bytes(2)?);
+        Ok(u16::from_le_bytes(x))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, WireError> {
+        let mut x = [0u8; 4];
+        x.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(x))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, WireError> {
+        let mut x = [0u8; 4];
+        x.copy_from_slice(self.bytes(4)?);
+        Ok(i32::from_le_bytes(x))
+    }
+
+    pub fn degrees(&mut self) -> Result<f32, WireError> {
+        Ok(micro_to_degrees(self.i32()?))
+    }
+}
+
+pub fn degrees_to_micro(degrees: f32) -> i32 {
+    (degrees * 1_000_000.0) as i32
+}
+
+pub fn micro_to_degrees(micro: i32) -> f32 {
+    micro as f32 / 1_000_000.0
+}
+
+/// Pack hue and saturation into one byte. Hue keeps its top 5 bits and saturation keeps its top 3.
+/// 32 hues is still plenty to tell our friends apart
+pub fn pack_hue_sat(hue: u8, sat: u8) -> u8 {
+    (hue & 0b1111_1000) | (sat >> 5)
+}
+
+/// The inverse of pack_hue_sat. Full saturation stays at full saturation
+pub fn unpack_hue_sat(packed: u8) -> (u8, u8) {
+    let hue = packed & 0b1111_1000;
+    let sat = ((packed & 0b0000_0111) as u16 * 255 / 7) as u8;
+
+    (hue, sat)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut buf = [0u8; 16];
+
+        let mut writer = Writer::new(&mut buf);
+        writer.u8(1).unwrap();
+        writer.u16(0x0203).unwrap();
+        writer.u32(0x0405_0607).unwrap();
+        writer.i32(-8).unwrap();
+        writer.degrees(-122.4194).unwrap();
+        assert_eq!(writer.bytes_written(), 15);
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.u8(), Ok(1));
+        assert_eq!(reader.u16(), Ok(0x0203));
+        assert_eq!(reader.u32(), Ok(0x0405_0607));
+        assert_eq!(reader.i32(), Ok(-8));
+        assert!((reader.degrees().unwrap() - -122.4194).abs() < 0.00001);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let mut buf = [0u8; 3];
+
+        let mut writer = Writer::new(&mut buf);
+        assert_eq!(writer.u32(1), Err(WireError));
+        assert_eq!(writer.bytes_written(), 0);
+
+        let mut reader = Reader::new(&buf);
+        assert_eq!(reader.u32(), Err(WireError));
+    }
+
+    #[test]
+    fn test_hue_sat() {
+        assert_eq!(unpack_hue_sat(pack_hue_sat(0, 0)), (0, 0));
+        assert_eq!(unpack_hue_sat(pack_hue_sat(255, 255)), (248, 255));
+        assert_eq!(unpack_hue_sat(pack_hue_sat(100, 128)), (96, 145));
+    }
+}
@@ -1,5 +1,9 @@
 pub use embedded_sdmmc;
 
+use crate::config::{ConfigError, DeviceConfig, GROUP_FILE, MAX_GROUP_FILE_LEN};
+use core::fmt::Write;
+use embedded_sdmmc::{BlockDevice, Controller, Error, Mode, TimeSource, VolumeIdx};
+
 /// TODO: use the RTC?
 pub struct DummyTimeSource;
 
@@ -8,3 +12,155 @@ impl embedded_sdmmc::TimeSource for DummyTimeSource {
         embedded_sdmmc::Timestamp::from_fat(0, 0)
     }
 }
+
+/// Where we remember the peer id that we joined the group with. The group file is the same for everyone, so this
+/// can't go in there
+pub const PEER_ID_FILE: &str = "PEER_ID.TXT";
+
+/// A file didn't fit in the buffer we had for it. Part of a file is worse than none
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TooLong;
+
+/// Read a whole file into `buf`. None if there is no file. Some(Err) if it doesn't fit
+pub fn read_file<D, T>(
+    controller: &mut Controller<D, T>,
+    name: &str,
+    buf: &mut [u8],
+) -> Result<Option<Result<usize, TooLong>>, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    D::Error: core::fmt::Debug,
+{
+    let mut volume = controller.get_volume(VolumeIdx(0))?;
+    let dir = controller.open_root_dir(&volume)?;
+
+    let mut file = match controller.open_file_in_dir(&mut volume, &dir, name, Mode::ReadOnly) {
+        Ok(x) => x,
+        Err(Error::FileNotFound) => {
+            controller.close_dir(&volume, dir);
+            return Ok(None);
+        }
+        Err(err) => {
+            controller.close_dir(&volume, dir);
+            return Err(err);
+        }
+    };
+
+    // don't even start on a file that we can't read all of
+    let read =
+        if file.length() as usize > buf.len() {
+            Ok(Err(TooLong))
+        } else {
+            // this keeps going until the buffer is full or the file ends
+            controller.read(&volume, &mut file, buf).map(|n| {
+                if file.eof() {
+                    Ok(n)
+                } else {
+                    Err(TooLong)
+                }
+            })
+        };
+
+    controller.close_file(&volume, file)?;
+    controller.close_dir(&volume, dir);
+
+    read.map(Some)
+}
+
+/// Read the peer id that we saved after joining. None if we haven't joined yet
+pub fn load_peer_id<D, T>(
+    controller: &mut Controller<D, T>,
+) -> Result<Option<usize>, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    D::Error: core::fmt::Debug,
+{
+    // "255\n" is as long as it gets
+    let mut buf = [0u8; 8];
+
+    // a garbled file is the same as no file. we will just join again
+    let n = match read_file(controller, PEER_ID_FILE, &mut buf)? {
+        Some(Ok(x)) => x,
+        Some(Err(TooLong)) | None => return Ok(None),
+    };
+
+    let peer_id = core::str::from_utf8(&buf[..n])
+        .ok()
+        .and_then(|x| x.trim().parse().ok());
+
+    Ok(peer_id)
+}
+
+/// Read the group file (see `config`). None if there is no file. Some(Err) if the file has bad (or illegal) settings
+///
+/// If the group file doesn't pick a peer id, the one we saved after joining is used
+pub fn load_config<D, T>(
+    controller: &mut Controller<D, T>,
+) -> Result<Option<Result<DeviceConfig, ConfigError>>, Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    D::Error: core::fmt::Debug,
+{
+    let mut buf = [0u8; MAX_GROUP_FILE_LEN];
+
+    let n = match read_file(controller, GROUP_FILE, &mut buf)? {
+        Some(Ok(x)) => x,
+        Some(Err(TooLong)) => return Ok(Some(Err(ConfigError::TooLong))),
+        None => return Ok(None),
+    };
+
+    let mut config = match core::str::from_utf8(&buf[..n])
+        .map_err(|_| ConfigError::Parse)
+        .and_then(DeviceConfig::parse)
+    {
+        Ok(x) => x,
+        Err(err) => return Ok(Some(Err(err))),
+    };
+
+    if config.peer_id.is_none() {
+        // an id from a bigger group is no good. we will just join again
+        config.peer_id = load_peer_id(controller)?.filter(|x| *x < config.num_peers);
+    }
+
+    Ok(Some(Ok(config)))
+}
+
+/// Remember the peer id that we joined the group with
+pub fn save_peer_id<D, T>(
+    controller: &mut Controller<D, T>,
+    peer_id: usize,
+) -> Result<(), Error<D::Error>>
+where
+    D: BlockDevice,
+    T: TimeSource,
+    D::Error: core::fmt::Debug,
+{
+    let mut buf = heapless::String::<heapless::consts::U8>::new();
+    writeln!(buf, "{}", peer_id).map_err(|_| Error::ConversionError)?;
+
+    let mut volume = controller.get_volume(VolumeIdx(0))?;
+    let dir = controller.open_root_dir(&volume)?;
+
+    let mut file = match controller.open_file_in_dir(
+        &mut volume,
+        &dir,
+        PEER_ID_FILE,
+        Mode::ReadWriteCreateOrTruncate,
+    ) {
+        Ok(x) => x,
+        Err(err) => {
+            controller.close_dir(&volume, dir);
+            return Err(err);
+        }
+    };
+
+    let written = controller.write(&mut volume, &mut file, buf.as_bytes());
+
+    controller.close_file(&volume, file)?;
+    controller.close_dir(&volume, dir);
+
+    written.map(|_| ())
+}
@@ -31,7 +31,7 @@ impl ElapsedMs {
     #[cfg(feature = "thumbv6")]
     pub fn increment_by(&self, by: u32) {
         let x = self.0.load(Ordering::Relaxed);
-        self.0.store(x + by, Ordering::Relaxed)
+        self.0.store(x.wrapping_add(by), Ordering::Relaxed)
     }
 
     /// Increment the time by a configurable amount.
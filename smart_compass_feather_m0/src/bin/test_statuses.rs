@@ -0,0 +1,245 @@
+#![no_main]
+#![no_std]
+#![feature(alloc_error_handler)]
+#![feature(asm)]
+
+// panic handler
+use panic_halt as _;
+
+pub extern crate feather_m0 as hal;
+
+use hal::prelude::*;
+use usb_device::prelude::*;
+
+use alloc_cortex_m::CortexMHeap;
+use core::alloc::Layout;
+use hal::clock::GenericClockController;
+use heapless::consts::*;
+use heapless::spsc::{Consumer, Producer, Queue};
+use numtoa::NumToA;
+use rtic::app;
+use smart_compass::network::{NetworkData, PeerStatus, Status};
+use smart_compass::timers;
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+// TODO: do this without allocating
+#[global_allocator]
+static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
+
+/// how many fake peers pick statuses
+static NUM_PEERS: usize = 4;
+
+/// one of the fake peers picks their next status this often
+static STATUS_EVERY_MS: u32 = 3_000;
+
+/// Quick and dirty way to log messages
+pub enum LogMessage {
+    /// a peer picked a new status (or cleared it)
+    PeerStatus(usize, Status),
+}
+
+#[app(device = hal::pac, peripherals = true)]
+const APP: () = {
+    struct Resources {
+        elapsed_ms: timers::ElapsedMs,
+        elapsed_ms_timer: hal::timer::TimerCounter4,
+        every_200_millis: timers::EveryNMillis,
+        every_status: timers::EveryNMillis,
+        red_led: hal::gpio::Pa17<hal::gpio::Output<hal::gpio::OpenDrain>>,
+        usb_device: usb_device::device::UsbDevice<'static, hal::UsbBus>,
+        usb_queue_tx: Producer<'static, LogMessage, U8, u8>,
+        usb_queue_rx: Consumer<'static, LogMessage, U8, u8>,
+        usb_serial: usbd_serial::SerialPort<'static, hal::UsbBus>,
+    }
+
+    /// Increment ELAPSED_MS every millisecond
+    /// The `wait()` call is important because it checks and resets the counter ready for the next period.
+    #[task(binds = TC4, priority = 3, resources = [&elapsed_ms, elapsed_ms_timer])]
+    fn tc4(c: tc4::Context) {
+        if c.resources.elapsed_ms_timer.wait().is_ok() {
+            c.resources.elapsed_ms.increment();
+        }
+    }
+
+    /// Send log messages over USB
+    #[task(binds = USB, priority = 1, resources = [&elapsed_ms, usb_device, usb_serial, usb_queue_rx])]
+    fn usb(c: usb::Context) {
+        let elapsed_ms = c.resources.elapsed_ms;
+        let usb_device = c.resources.usb_device;
+        let usb_serial = c.resources.usb_serial;
+        let usb_queue_rx = c.resources.usb_queue_rx;
+
+        let mut num_buf = [0u8; 32];
+
+        // TODO: receive commands from serial. picking a status from a computer would be nice for testing
+        usb_device.poll(&mut [usb_serial]);
+
+        while let Some(msg) = usb_queue_rx.dequeue() {
+            let now = elapsed_ms.now();
+
+            if let Ok(_) = usb_serial.write(now.numtoa(10, &mut num_buf)) {
+                usb_serial.write(b" - ").ok();
+
+                match msg {
+                    LogMessage::PeerStatus(peer_id, status) => {
+                        usb_serial.write(b"peer ").ok();
+                        usb_serial.write(peer_id.numtoa(10, &mut num_buf)).ok();
+                        usb_serial.write(b" is ").ok();
+                        usb_serial.write(status.name().as_bytes()).ok();
+                    }
+                }
+
+                usb_serial.write(b"\n").ok();
+            }
+        }
+    }
+
+    /// setup the hardware
+    #[init]
+    fn init(c: init::Context) -> init::LateResources {
+        // Initialize the allocator BEFORE you use it
+        let start = cortex_m_rt::heap_start() as usize;
+        let size = 1024; // in bytes
+        unsafe { ALLOCATOR.init(start, size) }
+
+        let mut device = c.device;
+
+        let mut clocks = GenericClockController::with_internal_32kosc(
+            device.GCLK,
+            &mut device.PM,
+            &mut device.SYSCTRL,
+            &mut device.NVMCTRL,
+        );
+        let gclk0 = clocks.gclk0();
+        let mut pins = hal::Pins::new(device.PORT);
+
+        // 1ms timer for ELAPSED_MS
+        // TODO: which timer should we use?
+        let mut elapsed_ms_timer = hal::timer::TimerCounter::tc4_(
+            &clocks.tc4_tc5(&gclk0).unwrap(),
+            device.TC4,
+            &mut device.PM,
+        );
+        elapsed_ms_timer.start(1.ms());
+        elapsed_ms_timer.enable_interrupt();
+
+        let elapsed_ms = timers::ElapsedMs::default();
+
+        // setup USB serial for debug logging
+        // TODO: put these usb things int resources instead of in statics
+        let usb_allocator = unsafe {
+            static mut USB_ALLOCATOR: Option<usb_device::bus::UsbBusAllocator<hal::UsbBus>> = None;
+
+            USB_ALLOCATOR = Some(hal::usb_allocator(
+                device.USB,
+                &mut clocks,
+                &mut device.PM,
+                pins.usb_dm,
+                pins.usb_dp,
+                &mut pins.port,
+            ));
+            USB_ALLOCATOR.as_ref().unwrap()
+        };
+
+        let usb_serial = SerialPort::new(&usb_allocator);
+        let usb_device = UsbDeviceBuilder::new(&usb_allocator, UsbVidPid(0x16c0, 0x27dd))
+            .manufacturer("StittHappens")
+            .product("Smart Compass")
+            .serial_number("TEST")
+            .device_class(USB_CLASS_CDC)
+            .build();
+
+        let usb_queue = unsafe {
+            static mut USB_QUEUE: Option<Queue<LogMessage, U8, u8>> = None;
+
+            USB_QUEUE = Some(Queue::u8());
+            USB_QUEUE.as_mut().unwrap()
+        };
+
+        let (usb_queue_tx, usb_queue_rx) = usb_queue.split();
+
+        // onboard LED
+        let red_led = pins.d13.into_open_drain_output(&mut pins.port);
+
+        let every_200_millis = timers::EveryNMillis::new(&elapsed_ms, 200);
+        let every_status = timers::EveryNMillis::new(&elapsed_ms, STATUS_EVERY_MS);
+
+        init::LateResources {
+            every_200_millis,
+            every_status,
+            red_led,
+            elapsed_ms,
+            elapsed_ms_timer,
+            usb_device,
+            usb_queue_tx,
+            usb_queue_rx,
+            usb_serial,
+        }
+    }
+
+    /// Do the thing
+    #[idle(resources = [
+        &elapsed_ms,
+        every_200_millis,
+        every_status,
+        red_led,
+        usb_queue_tx,
+    ])]
+    fn idle(c: idle::Context) -> ! {
+        let every_200_millis = c.resources.every_200_millis;
+        let every_status = c.resources.every_status;
+        let elapsed_ms = c.resources.elapsed_ms;
+        let red_led = c.resources.red_led;
+        let usb_queue_tx = c.resources.usb_queue_tx;
+
+        // TODO: there's no radio on this board yet. fake peers save their statuses the same way received ones are
+        let mut network_data = NetworkData {
+            num_peers: NUM_PEERS,
+            ..Default::default()
+        };
+
+        let mut next_peer_id = 0;
+
+        loop {
+            // this is useful to know if the program has crashed
+            if every_200_millis.ready(elapsed_ms).is_ok() {
+                red_led.toggle();
+            }
+
+            if let Ok(now) = every_status.ready(elapsed_ms) {
+                let peer_id = next_peer_id;
+                next_peer_id = (next_peer_id + 1) % NUM_PEERS;
+
+                let status = network_data
+                    .peer_status(peer_id)
+                    .unwrap_or(Status::Clear)
+                    .next();
+
+                // TODO: get the time from the gps instead
+                let fake_epoch_seconds = now / 1000;
+
+                let saved = network_data.save_status(PeerStatus {
+                    peer_id,
+                    last_updated_at: fake_epoch_seconds,
+                    status,
+                    hops: 0,
+                });
+
+                if saved {
+                    // the queue is only full if nothing is reading the serial port. dropping logs is fine then
+                    usb_queue_tx
+                        .enqueue(LogMessage::PeerStatus(peer_id, status))
+                        .ok();
+
+                    rtic::pend(hal::pac::Interrupt::USB);
+                }
+            }
+        }
+    }
+};
+
+/// Out of memory!
+#[alloc_error_handler]
+fn oom(_: Layout) -> ! {
+    loop {}
+}
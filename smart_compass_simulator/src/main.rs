@@ -0,0 +1,114 @@
+//! Run a group of virtual compasses on the host and see how long it takes them to find each other.
+//!
+//! cargo run -p smart_compass_simulator -- --nodes 3 --loss 0.2 --skew-ms 500 --seconds 600 --seed 1
+mod rng;
+mod sim;
+
+use sim::{Scenario, Topology};
+use std::env;
+use std::process::exit;
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: smart_compass_simulator [--nodes N] [--topology mesh|chain] [--boot-interval-s S] [--without-gps N] [--loss 0.0-1.0] [--skew-ms MS] [--seconds S] [--seed X]"
+    );
+    eprintln!("at most {} nodes", Scenario::max_nodes());
+    exit(1);
+}
+
+fn parse_args() -> Scenario {
+    let mut scenario = Scenario {
+        nodes: Scenario::max_nodes(),
+        topology: Topology::Mesh,
+        boot_interval_s: 0,
+        without_gps: 0,
+        loss: 0.1,
+        max_skew_ms: 250,
+        max_seconds: 600,
+        seed: 1,
+    };
+
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        let value = args.next().unwrap_or_else(|| usage());
+
+        match arg.as_str() {
+            "--nodes" => scenario.nodes = value.parse().unwrap_or_else(|_| usage()),
+            "--topology" => {
+                scenario.topology = match value.as_str() {
+                    "mesh" => Topology::Mesh,
+                    "chain" => Topology::Chain,
+                    _ => usage(),
+                }
+            }
+            "--boot-interval-s" => {
+                scenario.boot_interval_s = value.parse().unwrap_or_else(|_| usage())
+            }
+            "--without-gps" => scenario.without_gps = value.parse().unwrap_or_else(|_| usage()),
+            "--loss" => scenario.loss = value.parse().unwrap_or_else(|_| usage()),
+            "--skew-ms" => scenario.max_skew_ms = value.parse().unwrap_or_else(|_| usage()),
+            "--seconds" => scenario.max_seconds = value.parse().unwrap_or_else(|_| usage()),
+            "--seed" => scenario.seed = value.parse().unwrap_or_else(|_| usage()),
+            _ => usage(),
+        }
+    }
+
+    if scenario.nodes < 2
+        || scenario.nodes > Scenario::max_nodes()
+        || scenario.without_gps >= scenario.nodes
+    {
+        usage();
+    }
+
+    scenario
+}
+
+fn main() {
+    let scenario = parse_args();
+
+    println!(
+        "{} nodes in a {:?}, {:.0}% loss, up to {}ms of clock skew, seed {}",
+        scenario.nodes,
+        scenario.topology,
+        scenario.loss * 100.0,
+        scenario.max_skew_ms,
+        scenario.seed
+    );
+
+    let report = sim::run(&scenario);
+
+    for (i, (peer_id, joined_at_ms)) in report.joined_at_ms.iter().enumerate() {
+        let joined = match (peer_id, joined_at_ms) {
+            (Some(peer_id), Some(ms)) => format!(
+                "joined as peer {} after {:.1}s",
+                peer_id,
+                *ms as f32 / 1000.0
+            ),
+            _ => "never joined".to_string(),
+        };
+
+        let converged = match report.converged_at_ms[i] {
+            Some(ms) => format!("found everyone after {:.1}s", ms as f32 / 1000.0),
+            None => "never found everyone".to_string(),
+        };
+
+        let clock = match report.time_error_ms[i] {
+            Some(ms) => format!("clock off by {}ms", ms),
+            None => "no clock".to_string(),
+        };
+
+        println!(
+            "node {}: {}, {}, {}. sent {}, accepted {}, rejected {}",
+            i, joined, converged, clock, report.sent[i], report.accepted[i], report.rejected[i]
+        );
+    }
+
+    match report.all_converged_at_ms {
+        Some(ms) => println!("converged after {:.1}s", ms as f32 / 1000.0),
+        None => {
+            println!("did not converge in {}s", scenario.max_seconds);
+            exit(2);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+//! A tiny xorshift random number generator.
+//!
+//! The same seed always gives the same run. That makes a bad run easy to replay while debugging
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift gets stuck on 0
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+
+    /// A float in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// True with probability `p`
+    pub fn chance(&mut self, p: f32) -> bool {
+        self.next_f32() < p
+    }
+
+    /// An integer in [-max, max]
+    pub fn plus_or_minus(&mut self, max: i64) -> i64 {
+        if max <= 0 {
+            return 0;
+        }
+
+        (self.next_u64() % (2 * max as u64 + 1)) as i64 - max
+    }
+}
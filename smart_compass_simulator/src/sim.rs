@@ -0,0 +1,447 @@
+//! A group of virtual compasses sharing one mock radio channel.
+use crate::rng::Rng;
+use smart_compass::network::{MockAir, MockRadio, Network, RxInfo, Schedule, Slot};
+use smart_compass::timers::ElapsedMs;
+use smart_compass::MAX_PEERS;
+use yanp::parse::{GpsPosition, LatitudeDirection, LongitudeDirection};
+
+/// How far the simulation moves forward each step
+pub const TICK_MS: u64 = 10;
+
+/// Somewhere in the desert
+const START_EPOCH_SECONDS: u64 = 1_598_000_000;
+const START_LAT: f32 = 40.7864;
+const START_LON: f32 = -119.2065;
+
+/// About 100m
+const TRACK_RADIUS_DEGREES: f32 = 0.001;
+
+/// Who can hear who
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Topology {
+    /// everyone is in range of everyone
+    Mesh,
+    /// each node only hears the nodes right before and after it. like hiking single file through the hills
+    Chain,
+}
+
+impl Topology {
+    fn in_range(self, from: usize, to: usize) -> bool {
+        match self {
+            Self::Mesh => true,
+            Self::Chain => from + 1 == to || to + 1 == from,
+        }
+    }
+}
+
+pub struct Scenario {
+    pub nodes: usize,
+    pub topology: Topology,
+    /// node i turns on at i * this. nodes that can't hear everyone need to join one at a time
+    pub boot_interval_s: u64,
+    /// the last this many nodes never get a GPS fix. they have to get the time from their peers
+    pub without_gps: usize,
+    /// chance that any one packet doesn't make it to any one receiver
+    pub loss: f32,
+    /// each node's clock is off by up to this much
+    pub max_skew_ms: i64,
+    pub max_seconds: u64,
+    pub seed: u64,
+}
+
+impl Scenario {
+    pub fn max_nodes() -> usize {
+        MAX_PEERS
+    }
+}
+
+/// One virtual compass. They all start out fresh and have to join the group
+struct Node {
+    /// when (true ms) this node turns on
+    boot_at_ms: u64,
+    network: Network<MockRadio>,
+    /// picks the nonce for joining
+    nonce: u32,
+    /// nodes without a fix have no location and no time of their own
+    has_gps: bool,
+    elapsed_ms: ElapsedMs,
+    /// how far this node's clock is from the true time
+    skew_ms: i64,
+    /// where on the track this node starts
+    phase: f32,
+    last_gps_second: Option<u32>,
+    /// when (true ms) this node got a peer id
+    joined_at_ms: Option<u64>,
+    /// the first time (true ms) that this node knew where everyone else is
+    converged_at_ms: Option<u64>,
+}
+
+impl Node {
+    fn epoch_ms(&self, true_ms: u64) -> u64 {
+        ((START_EPOCH_SECONDS * 1000 + true_ms) as i64 + self.skew_ms) as u64
+    }
+
+    /// Walk in a big circle. One lap every 10 minutes
+    fn position(&self, epoch_seconds: u32) -> GpsPosition {
+        let angle = self.phase + epoch_seconds as f32 / 600.0 * 2.0 * core::f32::consts::PI;
+
+        let lat = START_LAT + TRACK_RADIUS_DEGREES * angle.sin();
+        let lon = START_LON + TRACK_RADIUS_DEGREES * angle.cos();
+
+        GpsPosition {
+            lat,
+            lat_dir: if lat >= 0.0 {
+                LatitudeDirection::North
+            } else {
+                LatitudeDirection::South
+            },
+            lon,
+            lon_dir: if lon >= 0.0 {
+                LongitudeDirection::East
+            } else {
+                LongitudeDirection::West
+            },
+        }
+    }
+
+    /// The same thing the boards do in their idle loops
+    fn step(&mut self, schedule: &Schedule, true_ms: u64) {
+        if true_ms < self.boot_at_ms {
+            // still off. the radio starts out asleep
+            return;
+        }
+
+        self.elapsed_ms.increment_by(TICK_MS as u32);
+
+        let gps_seconds = (self.epoch_ms(true_ms) / 1000) as u32;
+
+        // the GPS updates once a second
+        if self.has_gps && self.last_gps_second != Some(gps_seconds) {
+            self.last_gps_second = Some(gps_seconds);
+
+            let position = self.position(gps_seconds);
+
+            self.network
+                .save_my_location(&self.elapsed_ms, gps_seconds, &position);
+
+            self.network
+                .data
+                .clock
+                .gps_time(gps_seconds, self.elapsed_ms.now());
+        }
+
+        let (epoch_seconds, ms) = match self.network.data.clock.now(self.elapsed_ms.now()) {
+            Some(x) => x,
+            None => {
+                // a peer might tell us. until then, let everyone know we are here
+                let beacon_id =
+                    self.network.data.my_peer_id.and_then(|my_peer_id| {
+                        schedule.beacon_slot(my_peer_id, self.elapsed_ms.now())
+                    });
+
+                // mock radios don't fail unless they are wedged
+                self.network.beacon(&self.elapsed_ms, beacon_id).unwrap();
+                return;
+            }
+        };
+
+        let my_peer_id = match self.network.data.my_peer_id {
+            Some(x) => x,
+            None => {
+                if self.network.joining.is_none() {
+                    self.network.start_join(self.nonce, epoch_seconds, ms);
+                }
+
+                if self
+                    .network
+                    .join(&self.elapsed_ms, epoch_seconds, ms)
+                    .unwrap()
+                    .is_some()
+                {
+                    self.joined_at_ms = Some(true_ms);
+                }
+
+                return;
+            }
+        };
+
+        match schedule.slot(my_peer_id, epoch_seconds, ms) {
+            Slot::Transmit {
+                time_segment_id,
+                peer_id,
+            } => self
+                .network
+                .transmit(&self.elapsed_ms, epoch_seconds, time_segment_id, peer_id),
+            Slot::Receive => self.network.try_receive(&self.elapsed_ms, epoch_seconds),
+            Slot::Sleep => self.network.sleep(),
+        }
+        .unwrap();
+    }
+
+    /// How far this node's clock is from the true time. None if it doesn't know the time
+    fn time_error_ms(&self, true_ms: u64) -> Option<i64> {
+        let now_ms = self.network.data.clock.now_ms(self.elapsed_ms.now())?;
+
+        Some(now_ms as i64 - (START_EPOCH_SECONDS * 1000 + true_ms) as i64)
+    }
+
+    fn knows_everyone(&self, peer_ids: &[usize]) -> bool {
+        self.network.data.my_peer_id.is_some()
+            && peer_ids
+                .iter()
+                .all(|peer_id| self.network.data.peer_locations[*peer_id].is_some())
+    }
+}
+
+pub struct Report {
+    /// the peer id that each node joined with and when. None if it never joined
+    pub joined_at_ms: Vec<(Option<usize>, Option<u64>)>,
+    /// when each node first knew where everyone else is. None if it never did
+    pub converged_at_ms: Vec<Option<u64>>,
+    /// when every node knew where everyone else is
+    pub all_converged_at_ms: Option<u64>,
+    /// how far each node's clock was from the true time at the end
+    pub time_error_ms: Vec<Option<i64>>,
+    pub sent: Vec<u32>,
+    pub accepted: Vec<u32>,
+    pub rejected: Vec<u32>,
+}
+
+pub fn run(scenario: &Scenario) -> Report {
+    assert!(scenario.nodes <= Scenario::max_nodes());
+    assert!(scenario.without_gps < scenario.nodes);
+
+    let mut rng = Rng::new(scenario.seed);
+
+    // the group is only as big as it needs to be. smaller groups get more turns to talk
+    let num_peers = scenario.nodes;
+    let schedule = Schedule::for_peers(num_peers);
+    let air = MockAir::new();
+    let network_secret = [42; 32];
+
+    let mut nodes: Vec<Node> = (0..scenario.nodes)
+        .map(|i| {
+            // the hue doesn't matter here. spread them out anyways
+            let hue = (i * 256 / scenario.nodes) as u8;
+            // real compasses get both of these from sensor noise too
+            let nonce = rng.next_u64() as u32;
+
+            Node {
+                boot_at_ms: i as u64 * scenario.boot_interval_s * 1000,
+                network: Network::new(
+                    air.radio(),
+                    network_secret,
+                    true,
+                    num_peers,
+                    None,
+                    hue,
+                    255,
+                    nonce,
+                )
+                .unwrap(),
+                nonce,
+                has_gps: i < scenario.nodes - scenario.without_gps,
+                elapsed_ms: ElapsedMs::default(),
+                skew_ms: rng.plus_or_minus(scenario.max_skew_ms),
+                phase: rng.next_f32() * 2.0 * core::f32::consts::PI,
+                last_gps_second: None,
+                joined_at_ms: None,
+                converged_at_ms: None,
+            }
+        })
+        .collect();
+
+    let good_signal = RxInfo {
+        rssi: -80,
+        snr: Some(5),
+    };
+
+    let mut all_converged_at_ms = None;
+    let mut true_ms = 0;
+
+    while true_ms < scenario.max_seconds * 1000 {
+        true_ms += TICK_MS;
+
+        // nodes transmit at most once per tick, so flipping the links every tick drops individual packets
+        for from in 0..nodes.len() {
+            for to in 0..nodes.len() {
+                if from == to {
+                    continue;
+                }
+
+                if !scenario.topology.in_range(from, to) || rng.chance(scenario.loss) {
+                    air.disconnect(from, to);
+                } else {
+                    air.connect(from, to, good_signal);
+                }
+            }
+        }
+
+        for node in nodes.iter_mut() {
+            node.step(&schedule, true_ms);
+        }
+
+        // no one can know where everyone is until everyone has joined with their own id
+        let mut peer_ids: Vec<usize> = nodes
+            .iter()
+            .filter_map(|node| node.network.data.my_peer_id)
+            .collect();
+
+        peer_ids.sort_unstable();
+        peer_ids.dedup();
+
+        if peer_ids.len() < nodes.len() {
+            continue;
+        }
+
+        // nodes without a fix don't have a location to share
+        let peer_ids: Vec<usize> = nodes
+            .iter()
+            .filter(|node| node.has_gps)
+            .filter_map(|node| node.network.data.my_peer_id)
+            .collect();
+
+        for node in nodes.iter_mut() {
+            if node.converged_at_ms.is_none() && node.knows_everyone(&peer_ids) {
+                node.converged_at_ms = Some(true_ms);
+            }
+        }
+
+        if nodes.iter().all(|node| node.converged_at_ms.is_some()) {
+            all_converged_at_ms = Some(true_ms);
+            break;
+        }
+    }
+
+    let stats = |f: &dyn Fn(&Node) -> u32| nodes.iter().map(f).collect::<Vec<_>>();
+
+    Report {
+        joined_at_ms: nodes
+            .iter()
+            .map(|node| (node.network.data.my_peer_id, node.joined_at_ms))
+            .collect(),
+        converged_at_ms: nodes.iter().map(|node| node.converged_at_ms).collect(),
+        all_converged_at_ms,
+        time_error_ms: nodes
+            .iter()
+            .map(|node| node.time_error_ms(true_ms))
+            .collect(),
+        sent: (0..nodes.len()).map(|i| air.sent(i)).collect(),
+        accepted: stats(&|node| node.network.data.stats.accepted),
+        rejected: stats(&|node| {
+            let stats = &node.network.data.stats;
+            stats.received - stats.accepted
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_unique_peer_ids(report: &Report) {
+        let mut peer_ids: Vec<usize> = report
+            .joined_at_ms
+            .iter()
+            .map(|(peer_id, _)| peer_id.unwrap())
+            .collect();
+
+        peer_ids.sort_unstable();
+        peer_ids.dedup();
+
+        assert_eq!(peer_ids.len(), report.joined_at_ms.len());
+    }
+
+    #[test]
+    fn test_converges_without_loss() {
+        let report = run(&Scenario {
+            nodes: Scenario::max_nodes(),
+            topology: Topology::Mesh,
+            boot_interval_s: 0,
+            without_gps: 0,
+            loss: 0.0,
+            max_skew_ms: 0,
+            max_seconds: 1_500,
+            seed: 1,
+        });
+
+        assert!(report.all_converged_at_ms.is_some());
+        assert!(report.rejected.iter().all(|x| *x == 0));
+        assert_unique_peer_ids(&report);
+    }
+
+    #[test]
+    fn test_converges_with_loss_and_skew() {
+        let report = run(&Scenario {
+            nodes: Scenario::max_nodes(),
+            topology: Topology::Mesh,
+            boot_interval_s: 0,
+            without_gps: 0,
+            loss: 0.3,
+            max_skew_ms: 500,
+            max_seconds: 1_500,
+            seed: 2,
+        });
+
+        assert!(report.all_converged_at_ms.is_some());
+        assert_unique_peer_ids(&report);
+    }
+
+    #[test]
+    fn test_converges_over_a_chain() {
+        // the ends are 4 hops apart. they only find each other through relays
+        let report = run(&Scenario {
+            nodes: 5,
+            topology: Topology::Chain,
+            boot_interval_s: 90,
+            without_gps: 0,
+            loss: 0.1,
+            max_skew_ms: 250,
+            max_seconds: 1_500,
+            seed: 4,
+        });
+
+        assert!(report.all_converged_at_ms.is_some());
+        assert_unique_peer_ids(&report);
+    }
+
+    #[test]
+    fn test_time_from_peers() {
+        let report = run(&Scenario {
+            nodes: 4,
+            topology: Topology::Mesh,
+            boot_interval_s: 0,
+            without_gps: 2,
+            loss: 0.1,
+            max_skew_ms: 0,
+            max_seconds: 600,
+            seed: 5,
+        });
+
+        assert!(report.all_converged_at_ms.is_some());
+        assert_unique_peer_ids(&report);
+
+        // the ones without a GPS add a packet's airtime to their peers' time. the mock radio is instant, so they end up
+        // a little ahead
+        for time_error_ms in report.time_error_ms {
+            assert!(time_error_ms.unwrap().abs() < 100);
+        }
+    }
+
+    #[test]
+    fn test_total_loss_never_converges() {
+        let report = run(&Scenario {
+            nodes: 2,
+            topology: Topology::Mesh,
+            boot_interval_s: 0,
+            without_gps: 0,
+            loss: 1.0,
+            max_skew_ms: 0,
+            max_seconds: 60,
+            seed: 3,
+        });
+
+        assert_eq!(report.all_converged_at_ms, None);
+        assert!(report.accepted.iter().all(|x| *x == 0));
+    }
+}
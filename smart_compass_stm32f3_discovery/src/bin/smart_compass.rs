@@ -10,13 +10,17 @@ pub use stm32f3_discovery::prelude::*;
 use alloc_cortex_m::CortexMHeap;
 use asm_delay::AsmDelay;
 use core::alloc::Layout;
+use core::sync::atomic::{AtomicU32, Ordering};
 use cortex_m_semihosting::hprintln;
 use rtic::app;
 use shared_bus_rtic::SharedBus;
-use smart_compass::{battery, lights, location, network, storage, timers, MAX_PEERS};
+use smart_compass::{
+    battery, button, config, lights, location, network, storage, timers, MAX_PINS,
+};
 use stm32f3_discovery::accelerometer::{Orientation, RawAccelerometer};
 use stm32f3_discovery::compass::Compass;
 use stm32f3_discovery::cortex_m::asm::delay;
+use stm32f3_discovery::cortex_m::peripheral::SCB;
 use stm32f3_discovery::cortex_m_rt;
 use stm32f3_discovery::hal;
 use stm32f3_discovery::leds::Leds as CompassLeds;
@@ -28,8 +32,24 @@ static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
 
 static mut ELAPSED_MS: Option<timers::ElapsedMs> = None;
 
+/// Radio errors in the DIO0 interrupt. Printing blocks (or faults without a debugger), so idle prints them instead
+static RX_INTERRUPT_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// hprintln, but only with the "debug" feature. Use this for anything that happens often. Every print stops the chip
+/// until the debugger answers, and that is enough to miss our turn
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "debug") {
+            hprintln!($($arg)*).unwrap();
+        }
+    };
+}
+
 type MyBattery = battery::Battery<hal::gpio::gpioc::PC8<hal::gpio::Input<hal::gpio::PullDown>>>;
 
+/// the blue "USER" button. the board already has a pull down on it
+type MyUserButton = button::Button<hal::gpio::gpioa::PA0<hal::gpio::Input<hal::gpio::Floating>>>;
+
 /// TODO: what should we name this
 // TODO: less specific type than AF5
 pub type MySpi1 = hal::spi::Spi<
@@ -82,14 +102,16 @@ type SdController<Spi> = storage::embedded_sdmmc::Controller<
 
 /// TODO: what should we name this
 type MyNetwork<Spi> = network::Network<
-    Spi,
-    hal::spi::Error,
-    hal::gpio::PXx<hal::gpio::Output<hal::gpio::PushPull>>,
-    hal::gpio::PXx<hal::gpio::Input<hal::gpio::PullDown>>,
-    hal::gpio::PXx<hal::gpio::Input<hal::gpio::PullDown>>,
-    hal::gpio::PXx<hal::gpio::Output<hal::gpio::OpenDrain>>,
-    (),
-    asm_delay::AsmDelay,
+    network::MyRadio<
+        Spi,
+        hal::spi::Error,
+        hal::gpio::PXx<hal::gpio::Output<hal::gpio::PushPull>>,
+        hal::gpio::PXx<hal::gpio::Input<hal::gpio::PullDown>>,
+        hal::gpio::PXx<hal::gpio::Input<hal::gpio::PullDown>>,
+        hal::gpio::PXx<hal::gpio::Output<hal::gpio::OpenDrain>>,
+        (),
+        asm_delay::AsmDelay,
+    >,
 >;
 
 /// keep everything on the same bus inside one struct
@@ -101,27 +123,40 @@ pub struct SharedSPIResources {
 }
 
 // static globals
-// TODO: what type?
-const NUM_TIME_SEGMENTS: usize = MAX_PEERS * MAX_PEERS;
-const TIME_SEGMENT_S: usize = 2;
 // /// the number of ms to offset our network timer. this is time to send+receive+process+draw
 // static NETWORK_OFFSET: u16 = 125 + 225;
 const DEFAULT_BRIGHTNESS: u8 = 128;
 const FRAMES_PER_SECOND: u8 = 30;
+/// Radio errors in a row before we reset the radio
+const MAX_RADIO_ERRORS: u8 = 3;
+/// Hold the user button this long to ask for help (or to say we are fine again). Shorter presses change our status
+const SOS_HOLD_MS: u32 = 3_000;
+/// Press the user button twice this quickly to drop a pin. A single press waits this long before changing our status
+const DOUBLE_PRESS_MS: u32 = 400;
+/// How long to show the error before restarting when init can't finish
+const INIT_RETRY_MS: u32 = 10_000;
+/// How often to look for a good group file after the first one didn't work
+const CONFIG_RETRY_MS: u32 = 5_000;
 
 #[app(device = stm32f3_discovery::hal::stm32, peripherals = true)]
 const APP: () = {
     struct Resources {
         battery: MyBattery,
+        /// something went wrong reading the group file. idle shows it on the lights and keeps the radio off
+        config_error: Option<lights::ErrorPattern>,
         // TODO: put compass in a shared_resources helper if theres more than one i2c
         compass: Compass,
         compass_lights: CompassLeds,
         elapsed_ms: timers::ElapsedMs,
         elapsed_ms_timer: hal::timer::Timer<hal::stm32::TIM7>,
+        exti: hal::stm32::EXTI,
         lights: MyLights,
         gps: MyGps,
         gps_queue: MyGpsQueue,
+        rx_queue_rx: network::RxConsumer<'static>,
+        rx_queue_tx: network::RxProducer<'static>,
         shared_spi_resources: SharedSPIResources,
+        user_button: MyUserButton,
     }
 
     #[task(binds = TIM7, resources = [elapsed_ms, elapsed_ms_timer, gps_queue])]
@@ -137,6 +172,32 @@ const APP: () = {
         }
     }
 
+    /// the GPS's PPS pin (PC7) went high. a new second just started
+    #[task(binds = EXTI9_5, resources = [elapsed_ms, exti, gps_queue])]
+    fn exti9_5(c: exti9_5::Context) {
+        c.resources.gps_queue.pps_edge(c.resources.elapsed_ms.now());
+
+        // clear the pending bit so that we don't fire again right away
+        c.resources.exti.pr1.write(|w| w.pr7().set_bit());
+    }
+
+    /// the radio's DIO0 pin (PC2) went high. a packet arrived (or a transmission finished)
+    #[task(binds = EXTI2_TSC, resources = [elapsed_ms, exti, rx_queue_tx, shared_spi_resources])]
+    fn exti2_tsc(c: exti2_tsc::Context) {
+        // copy the packet out now. idle decodes it when it isn't busy drawing
+        if c.resources
+            .shared_spi_resources
+            .network
+            .read_received(c.resources.elapsed_ms.now(), c.resources.rx_queue_tx)
+            .is_err()
+        {
+            // the next call from idle will find this too
+            RX_INTERRUPT_ERRORS.fetch_add(1, Ordering::Relaxed);
+        }
+
+        c.resources.exti.pr1.write(|w| w.pr2().set_bit());
+    }
+
     /// setup the hardware
     #[init]
     fn init(c: init::Context) -> init::LateResources {
@@ -151,6 +212,9 @@ const APP: () = {
         // Device specific peripherals
         let device = c.device;
 
+        // SYSCFG picks which port's pins go to the EXTI lines. it needs a clock before we can configure it
+        device.RCC.apb2enr.modify(|_, w| w.syscfgen().set_bit());
+
         let mut reset_and_clock_control = device.RCC.constrain();
 
         // setup ITM output
@@ -190,7 +254,7 @@ const APP: () = {
 
         // TODO: shared-bus for the i2c?
         // new lsm303 driver uses continuous mode, so no need wait for interrupts on DRDY
-        let my_compass = Compass::new(
+        let mut my_compass = Compass::new(
             gpiob.pb6,
             gpiob.pb7,
             &mut gpiob.moder,
@@ -201,6 +265,14 @@ const APP: () = {
         )
         .unwrap();
 
+        // a different starting point for our packet counter every boot. it keeps our nonces from ever repeating
+        let nonce_seed = {
+            let accel = my_compass.accel_raw().unwrap();
+            let mag = my_compass.mag_raw().unwrap();
+
+            sensor_noise((accel.x, accel.y, accel.z), (mag.x, mag.y, mag.z))
+        };
+
         let my_compass_lights = CompassLeds::new(
             gpioe.pe8,
             gpioe.pe9,
@@ -247,11 +319,42 @@ const APP: () = {
 
         let time_source = storage::DummyTimeSource;
 
-        let my_sd_card: SdController<_> = storage::embedded_sdmmc::Controller::new(
+        let mut my_sd_card: SdController<_> = storage::embedded_sdmmc::Controller::new(
             storage::embedded_sdmmc::SdMmcSpi::new(sd_spi, sdcard_cs),
             time_source,
         );
 
+        // the lights aren't set up yet. idle shows this once they are
+        let mut config_error = None;
+
+        // the group's secret, our colors, and the radio settings. everyone in the group needs the same file
+        // the defaults don't have a secret. they only fill in the network until idle finds a good file
+        let my_config = match my_sd_card.device().init() {
+            Ok(()) => match storage::load_config(&mut my_sd_card) {
+                Ok(Some(Ok(x))) => x,
+                Ok(Some(Err(err))) => {
+                    hprintln!("Bad group file: {:?}", err).unwrap();
+                    config_error = Some(lights::ErrorPattern::Config);
+                    config::DeviceConfig::default()
+                }
+                Ok(None) => {
+                    hprintln!("No group file").unwrap();
+                    config_error = Some(lights::ErrorPattern::Config);
+                    config::DeviceConfig::default()
+                }
+                Err(err) => {
+                    hprintln!("SD card failed: {:?}", err).unwrap();
+                    config_error = Some(lights::ErrorPattern::SdCard);
+                    config::DeviceConfig::default()
+                }
+            },
+            Err(err) => {
+                hprintln!("No SD card: {:?}", err).unwrap();
+                config_error = Some(lights::ErrorPattern::SdCard);
+                config::DeviceConfig::default()
+            }
+        };
+
         // setup the radio
         let radio_spi = shared_spi_manager.acquire();
 
@@ -280,25 +383,7 @@ const APP: () = {
             .downgrade()
             .downgrade();
 
-        // TODO: get this from the SD card
-        // TODO: 32
-        let network_hash = [0u8; 16];
-        let my_peer_id = 0;
-        let my_hue = 0;
-        let my_saturation = 0;
-
-        let my_network: MyNetwork<_> = network::Network::new(
-            radio_spi,
-            rfm95_cs,
-            rfm95_busy,
-            rfm95_ready,
-            rfm95_reset,
-            delay,
-            network_hash,
-            my_peer_id,
-            my_hue,
-            my_saturation,
-        );
+        let (rx_queue_tx, rx_queue_rx) = network::rx_queue();
 
         // TODO: setup orientation sensor
 
@@ -327,6 +412,29 @@ const APP: () = {
 
         let (my_gps, my_gps_queue) = location::UltimateGps::new(gps_tx, gps_rx, gps_enable_pin);
 
+        // the PPS pin goes high at the start of every second (while the GPS has a fix)
+        // TODO: what pin should we use? this one was random
+        let _gps_pps_pin = gpioc
+            .pc7
+            .into_pull_down_input(&mut gpioc.moder, &mut gpioc.pupdr);
+
+        // interrupt on the rising edge of PC7
+        // TODO: the hal doesn't have helpers for this yet
+        device
+            .SYSCFG
+            .exticr2
+            .modify(|_, w| unsafe { w.exti7().bits(0b010) });
+        device.EXTI.imr1.modify(|_, w| w.mr7().set_bit());
+        device.EXTI.rtsr1.modify(|_, w| w.tr7().set_bit());
+
+        // interrupt on the rising edge of the radio's DIO0 (PC2)
+        device
+            .SYSCFG
+            .exticr1
+            .modify(|_, w| unsafe { w.exti2().bits(0b010) });
+        device.EXTI.imr1.modify(|_, w| w.mr2().set_bit());
+        device.EXTI.rtsr1.modify(|_, w| w.tr2().set_bit());
+
         // create lights
         // TODO: is spi a good interface for this? whats the best way to run ws2812s?
         // TODO: what pin shuold we use? this one was random
@@ -344,7 +452,7 @@ const APP: () = {
             &mut reset_and_clock_control.apb1,
         );
 
-        let my_lights: MyLights = lights::Lights::new(
+        let mut my_lights: MyLights = lights::Lights::new(
             Ws2812::new(lights_spi),
             DEFAULT_BRIGHTNESS,
             &elapsed_ms,
@@ -362,6 +470,70 @@ const APP: () = {
             60_000,
         );
 
+        let user_button = button::Button::new(
+            gpioa
+                .pa0
+                .into_floating_input(&mut gpioa.moder, &mut gpioa.pupdr),
+            SOS_HOLD_MS,
+            DOUBLE_PRESS_MS,
+        );
+
+        // the radio goes last. if it doesn't start, the lights show why
+        // TODO: put this in the group file too?
+        let encrypt_locations = true;
+
+        let radio = match network::new_sx127x(
+            radio_spi,
+            rfm95_cs,
+            rfm95_busy,
+            rfm95_ready,
+            rfm95_reset,
+            delay,
+        ) {
+            Ok(x) => x,
+            Err(err) => {
+                hprintln!("Radio failed to start: {:?}", err).unwrap();
+
+                // new_sx127x took the pins, so starting over is the only way to try again
+                show_error_then_reset(&mut my_lights, &elapsed_ms, lights::ErrorPattern::Radio);
+            }
+        };
+
+        let mut my_network: MyNetwork<_> = match network::Network::new(
+            radio,
+            my_config.network_secret,
+            encrypt_locations,
+            my_config.num_peers,
+            // None means we haven't joined the group yet. idle will find us a free id
+            my_config.peer_id,
+            my_config.hue,
+            my_config.saturation,
+            nonce_seed,
+        ) {
+            Ok(x) => x,
+            Err(err) => {
+                // load_config already checked these, so this is a bug. the defaults are fine too
+                hprintln!("Network doesn't like the group: {:?}", err).unwrap();
+
+                show_error_then_reset(&mut my_lights, &elapsed_ms, lights::ErrorPattern::Config);
+            }
+        };
+
+        // without a group file, the radio stays quiet
+        if config_error.is_none() {
+            // this also checks that the longest packet fits inside our part of a time segment
+            if let Err(err) = my_network.set_radio_settings(my_config.radio_settings) {
+                hprintln!(
+                    "Radio settings don't work for us: {:?}. Using the defaults",
+                    err
+                )
+                .unwrap();
+            }
+
+            // the DIO0 interrupt reads the packets. idle doesn't have to poll for them
+            my_network.use_receive_interrupt();
+        }
+
         let shared_spi_resources = SharedSPIResources {
             network: my_network,
             sd_card: my_sd_card,
@@ -369,34 +541,46 @@ const APP: () = {
 
         init::LateResources {
             battery,
+            config_error,
             compass: my_compass,
             compass_lights: my_compass_lights,
             gps: my_gps,
             gps_queue: my_gps_queue,
             lights: my_lights,
+            rx_queue_rx,
+            rx_queue_tx,
             shared_spi_resources,
+            user_button,
             elapsed_ms,
             elapsed_ms_timer,
+            exti: device.EXTI,
         }
     }
 
     // `shared` cannot be accessed from this context
     // TODO: more of this should probably be done with interrupts
+    // shared_spi_resources has to be locked. the DIO0 interrupt uses the radio too
     #[idle(resources = [
         battery,
+        config_error,
         compass,
         compass_lights,
         gps,
         lights,
+        rx_queue_rx,
         shared_spi_resources,
+        user_button,
     ])]
     fn idle(c: idle::Context) -> ! {
         let my_battery = c.resources.battery;
+        let config_error = *c.resources.config_error;
         let my_compass = c.resources.compass;
         let my_compass_lights = c.resources.compass_lights;
         let my_gps = c.resources.gps;
         let my_lights = c.resources.lights;
-        let shared_spi_resources = c.resources.shared_spi_resources;
+        let rx_queue_rx = c.resources.rx_queue_rx;
+        let my_user_button = c.resources.user_button;
+        let mut shared_spi_resources = c.resources.shared_spi_resources;
 
         let elapsed_ms = ELAPSED_MS.as_ref().unwrap();
 
@@ -408,10 +592,29 @@ const APP: () = {
         my_lights.draw_black(elapsed_ms);
         elapsed_ms.block(1500);
 
-        // TODO: read this from the SD
-        let my_peer_id = 0;
+        // without a group file we would be in a group without a secret. wait for a good card instead
+        if let Some(error) = config_error {
+            let mut config_retry_every = timers::EveryNMillis::new(elapsed_ms, CONFIG_RETRY_MS);
 
-        shared_spi_resources.network.data.my_peer_id = my_peer_id;
+            loop {
+                my_lights.draw_error(elapsed_ms, error);
+
+                if config_retry_every.ready(elapsed_ms).is_err() {
+                    continue;
+                }
+
+                let loaded = shared_spi_resources.lock(|shared| {
+                    shared.sd_card.device().init().is_ok()
+                        && matches!(storage::load_config(&mut shared.sd_card), Ok(Some(Ok(_))))
+                });
+
+                if loaded {
+                    // init sets everything up with the new file
+                    hprintln!("Found a good group file. Restarting").unwrap();
+                    SCB::sys_reset();
+                }
+            }
+        }
 
         // configure gps
         // get the version (PMTK_Q_RELEASE)
@@ -426,11 +629,28 @@ const APP: () = {
         // PMTK_SET_NMEA_UPDATE_10HZ - 100
         my_gps.send_command(b"PMTK220,1000");
 
-        hprintln!(
-            "Radio silicon version: 0x{:X}",
-            shared_spi_resources.network.silicon_version()
-        )
-        .unwrap();
+        let num_peers = shared_spi_resources.lock(|shared| {
+            match shared.network.silicon_version() {
+                Ok(version) => hprintln!("Radio silicon version: 0x{:X}", version).unwrap(),
+                Err(err) => hprintln!("Radio silicon version unknown: {:?}", err).unwrap(),
+            }
+
+            shared.network.data.num_peers
+        });
+
+        let mut schedule = network::Schedule::for_peers(num_peers);
+
+        // how often to print how well we hear everyone
+        let mut link_stats_every = timers::EveryNMillis::new(elapsed_ms, 10_000);
+
+        // false once resetting the radio stops helping. then we only show an error until a reset works
+        let mut radio_ok = true;
+        let mut radio_errors = 0;
+        let mut radio_reset_every = timers::EveryNMillis::new(elapsed_ms, 10_000);
+
+        // each pin we drop gets the next id. after MAX_PINS, the oldest ones get replaced
+        // TODO: save this on the SD card? after a restart our new pins replace the old ones
+        let mut next_pin_id: u8 = 0;
 
         // delay for 1 second (TODO: use a helper for calculating 1 second in cycles)
         delay(72_000_000);
@@ -441,94 +661,405 @@ const APP: () = {
                 (true, battery::BatteryStatus::Low) => {
                     hprintln!("Battery low").unwrap();
                     my_lights.brightness = DEFAULT_BRIGHTNESS / 2;
+                    schedule.set_battery(battery::BatteryStatus::Low);
+                    shared_spi_resources
+                        .lock(|shared| shared.network.set_battery(battery::BatteryStatus::Low));
                 }
                 (true, battery::BatteryStatus::Ok) => {
                     hprintln!("Battery ok").unwrap();
                     my_lights.brightness = DEFAULT_BRIGHTNESS;
+                    schedule.set_battery(battery::BatteryStatus::Ok);
+                    shared_spi_resources
+                        .lock(|shared| shared.network.set_battery(battery::BatteryStatus::Ok));
                 }
             }
 
+            if let Ok(now) = link_stats_every.ready(elapsed_ms) {
+                shared_spi_resources.lock(|shared| {
+                    let network_data = &mut shared.network.data;
+
+                    debug!(
+                        "Radio on for the last hour: {}ms transmitting, {}ms receiving. {} packets over the duty cycle, {} dropped by a full queue",
+                        network_data.radio_time.transmit_ms(now),
+                        network_data.radio_time.receive_ms(now),
+                        network_data.stats.duty_cycle_limited,
+                        network_data.stats.rx_queue_full
+                    );
+
+                    for (peer_id, link) in network_data.links.iter().enumerate() {
+                        if let Some(link) = link {
+                            debug!(
+                                "Peer {}: rssi {}, snr {:?}, {} packets, last heard {}ms ago{}",
+                                peer_id,
+                                link.rssi,
+                                link.snr,
+                                link.received,
+                                now.wrapping_sub(link.last_heard_ms),
+                                if link.is_weak() { " (weak)" } else { "" }
+                            );
+
+                            if let Some(beacon) = network_data.beacons[peer_id] {
+                                debug!(
+                                    "Peer {} beaconed: battery {:?}, last fix {:?}s ago",
+                                    peer_id,
+                                    beacon.battery,
+                                    beacon.position_age_s
+                                );
+                            }
+                        }
+                    }
+
+                    // statuses are relayed. we might not hear these peers ourselves
+                    for peer_id in 0..network_data.num_peers {
+                        if let Some(status) = network_data.peer_status(peer_id) {
+                            debug!("Peer {} is {}", peer_id, status.name());
+                        }
+                    }
+                });
+            }
+
+            if let Some(press) = my_user_button.check(elapsed_ms) {
+                shared_spi_resources.lock(|shared| {
+                    let network = &mut shared.network;
+
+                    // TODO: queue it until we know the time instead of dropping it
+                    let epoch_seconds = match network.data.clock.now(elapsed_ms.now()) {
+                        Some((epoch_seconds, _)) => epoch_seconds,
+                        None => {
+                            hprintln!("Can't tell anyone anything without the time").unwrap();
+                            return;
+                        }
+                    };
+
+                    match press {
+                        button::Press::Long if network.sos_active() => {
+                            hprintln!("SOS canceled").unwrap();
+                            network.cancel_sos(epoch_seconds);
+                        }
+                        button::Press::Long => {
+                            hprintln!("SOS!").unwrap();
+                            network.start_sos(epoch_seconds);
+                        }
+                        button::Press::Short => {
+                            let status = network
+                                .data
+                                .my_peer_id
+                                .and_then(|my_peer_id| network.data.peer_status(my_peer_id))
+                                .unwrap_or(network::Status::Clear)
+                                .next();
+
+                            hprintln!("Status: {}", status.name()).unwrap();
+                            network.set_my_status(epoch_seconds, status);
+                        }
+                        button::Press::Double => match &my_gps.data.position {
+                            Some(position) => {
+                                hprintln!("Pin {} dropped", next_pin_id).unwrap();
+
+                                let hue = network.data.my_hue;
+                                let sat = network.data.my_saturation;
+
+                                network.save_my_pin(next_pin_id, epoch_seconds, position, hue, sat);
+
+                                next_pin_id = (next_pin_id + 1) % MAX_PINS as u8;
+                            }
+                            None => hprintln!("Can't drop a pin without a fix").unwrap(),
+                        },
+                    }
+                });
+            }
+
+            if !radio_ok {
+                my_lights.draw_error(elapsed_ms, lights::ErrorPattern::Radio);
+
+                if radio_reset_every.ready(elapsed_ms).is_ok() {
+                    match shared_spi_resources.lock(|shared| shared.network.reset_radio()) {
+                        Ok(()) => {
+                            hprintln!("Radio is back").unwrap();
+                            radio_ok = true;
+                        }
+                        Err(err) => hprintln!("Radio is still broken: {:?}", err).unwrap(),
+                    }
+                }
+
+                continue;
+            }
+
             let accel = my_compass.accel_raw().unwrap();
             let mag = my_compass.mag_raw().unwrap();
 
             // TODO: should we use hprintln or iprintln?
             // iprintln!(stim, "Accel:{:?}; Mag:{:?}", accel, mag);
-            hprintln!("Accel:{:?}; Mag:{:?}", accel, mag).unwrap();
+            debug!("Accel:{:?}; Mag:{:?}", accel, mag);
 
             // TODO: get the actual orientation from a sensor
             // TODO: should this be a global? should it happen on interrupt?
             let orientation = &Orientation::Unknown;
 
-            {
-                let gps_data = &my_gps.data;
-                let network_data = &shared_spi_resources.network.data;
+            draw_lights(
+                my_lights,
+                &mut shared_spi_resources,
+                elapsed_ms,
+                &my_gps.data,
+                orientation,
+            );
+
+            let rx_interrupt_errors = RX_INTERRUPT_ERRORS.swap(0, Ordering::Relaxed);
 
-                my_lights.draw(elapsed_ms, Some(gps_data), Some(network_data), orientation);
+            if rx_interrupt_errors > 0 {
+                hprintln!("Radio errors in DIO0 interrupt: {}", rx_interrupt_errors).unwrap();
             }
 
-            if my_gps.receive() {
-                hprintln!("GPS received a sentence").unwrap();
+            // decode whatever the DIO0 interrupt pulled out of the radio. one packet per lock keeps the interrupt waiting
+            // as little as possible
+            while let Some(mut packet) = rx_queue_rx.dequeue() {
+                shared_spi_resources.lock(|shared| {
+                    let network = &mut shared.network;
 
-                let gps_data = &my_gps.data;
+                    let now_epoch_seconds = network
+                        .data
+                        .clock
+                        .now(elapsed_ms.now())
+                        .map(|(epoch_seconds, _)| epoch_seconds);
 
-                if let Some(last_updated_at) = gps_data.epoch_seconds {
-                    if let Some(position) = &gps_data.position {
-                        shared_spi_resources
-                            .network
-                            .save_my_location(last_updated_at, position);
+                    if let Some(message) = network.handle_received(&mut packet, now_epoch_seconds) {
+                        debug!("Received {:?}", message.kind());
                     }
-                }
+                });
             }
 
-            let gps_data = &my_gps.data;
-            let network_data = &shared_spi_resources.network.data;
-
-            my_lights.draw(elapsed_ms, Some(gps_data), Some(network_data), orientation);
-
-            if my_gps.has_fix() {
-                hprintln!("GPS has fix").unwrap();
+            if my_gps.receive() {
+                debug!("GPS received a sentence");
 
-                if let Some(epoch_seconds) = gps_data.epoch_seconds {
-                    // TODO: the seconds being a float is really annoying. i don't want to bring floats into this
+                let gps_data = &my_gps.data;
+                let gps_clock = my_gps.clock;
+                let has_fix = my_gps.has_fix();
+
+                shared_spi_resources.lock(|shared| {
+                    if let Some(last_updated_at) = gps_data.epoch_seconds {
+                        if let Some(position) = &gps_data.position {
+                            shared
+                                .network
+                                .save_my_location(elapsed_ms, last_updated_at, position);
+                        }
+                    }
 
-                    let time_segment_id =
-                        (epoch_seconds as usize / TIME_SEGMENT_S) % NUM_TIME_SEGMENTS;
+                    if has_fix {
+                        debug!("GPS has fix");
+
+                        let now = elapsed_ms.now();
+                        let clock = &mut shared.network.data.clock;
+
+                        // the PPS pin tells us exactly when the second started. without it, the sentences only give
+                        // us whole seconds
+                        let synced = if let Some(epoch_ms) = gps_clock.now_ms(now) {
+                            clock.gps_time_ms(epoch_ms, now);
+                            true
+                        } else if let Some(epoch_seconds) = gps_data.epoch_seconds {
+                            clock.gps_time(epoch_seconds, now);
+                            true
+                        } else {
+                            false
+                        };
+
+                        if synced {
+                            debug!(
+                                "Clock: {:?}, offset {:?}ms, drift {}ppm",
+                                clock.source(),
+                                clock.offset_ms(),
+                                clock.drift_ppm()
+                            );
+                        }
+                    } else {
+                        debug!("GPS does not have a fix");
+                    }
+                });
+            }
 
-                    let broadcasting_peer_id = time_segment_id / MAX_PEERS;
-                    let broadcasted_peer_id = time_segment_id % MAX_PEERS;
+            let gps_data = &my_gps.data;
 
-                    // radio transmit or receive depending on the time_segment_id
-                    // TODO: spend 50% the time with the radio asleep?
-                    if broadcasting_peer_id == my_peer_id {
-                        // my turn to broadcast
-                        shared_spi_resources.network.transmit(
-                            &elapsed_ms,
+            draw_lights(
+                my_lights,
+                &mut shared_spi_resources,
+                elapsed_ms,
+                gps_data,
+                orientation,
+            );
+
+            let now = elapsed_ms.now();
+
+            radio_ok = shared_spi_resources.lock(|shared| {
+                let network = &mut shared.network;
+
+                // GPS time if we have a fix. otherwise, the time from our peers
+                let radio_result = if let Some((epoch_seconds, ms)) = network.data.clock.now(now) {
+                    let my_peer_id = match network.data.my_peer_id {
+                        Some(x) => x,
+                        None => {
+                            if network.joining.is_none() {
+                                let nonce = sensor_noise(
+                                    (accel.x, accel.y, accel.z),
+                                    (mag.x, mag.y, mag.z),
+                                ) ^ now;
+
+                                hprintln!("Joining the group").unwrap();
+
+                                network.start_join(nonce, epoch_seconds, ms);
+                            }
+
+                            let joined = network.join(&elapsed_ms, epoch_seconds, ms);
+
+                            if let Ok(Some(my_peer_id)) = joined {
+                                hprintln!("Joined as peer {}", my_peer_id).unwrap();
+
+                                // without a card, we just join again next time
+                                if let Err(err) =
+                                    storage::save_peer_id(&mut shared.sd_card, my_peer_id)
+                                {
+                                    hprintln!("Couldn't save our peer id: {:?}", err).unwrap();
+                                }
+                            }
+
+                            return check_radio(network, joined.map(|_| ()), &mut radio_errors);
+                        }
+                    };
+
+                    // radio transmit or receive depending on the time segment
+                    match schedule.slot(my_peer_id, epoch_seconds, ms) {
+                        network::Slot::Transmit {
                             time_segment_id,
-                            broadcasted_peer_id,
-                        );
-                    } else {
-                        // listen for someone else
-                        shared_spi_resources.network.try_receive();
+                            peer_id,
+                        } => {
+                            // my turn to broadcast
+                            network.transmit(&elapsed_ms, epoch_seconds, time_segment_id, peer_id)
+                        }
+                        network::Slot::Receive => {
+                            // listen for someone else. the DIO0 interrupt reads what they send
+                            network.try_receive(&elapsed_ms, epoch_seconds)
+                        }
+                        network::Slot::Sleep => {
+                            // no one should be talking. save some power
+                            network.sleep()
+                        }
                     }
                 } else {
-                    hprintln!("GPS does not have the time").unwrap();
-                    // TODO: should we bother with the radio? maybe put it to sleep?
-                    shared_spi_resources.network.sleep();
-                }
-            } else {
-                hprintln!("GPS does not have a fix").unwrap();
-                // wait on the radio receiving
-                // TODO: although maybe that should be in an interrupt?
-            }
+                    debug!("Waiting for the time");
+
+                    // a peer might tell us. until then, let everyone know we are here
+                    let beacon_id = network
+                        .data
+                        .my_peer_id
+                        .and_then(|my_peer_id| schedule.beacon_slot(my_peer_id, now));
+
+                    network.beacon(&elapsed_ms, beacon_id)
+                };
+
+                check_radio(network, radio_result, &mut radio_errors)
+            });
 
             // draw again because the using radio can take a while
-            my_lights.draw(elapsed_ms, Some(gps_data), Some(network_data), orientation);
+            draw_lights(
+                my_lights,
+                &mut shared_spi_resources,
+                elapsed_ms,
+                gps_data,
+                orientation,
+            );
 
             // TODO: fastLED.delay equivalent to improve brightness at low levels? make sure it doesn't block the radios!
         }
     }
 };
 
+/// Only hold the lock while filling the buffer. Sending it to the lights is slow and the DIO0 interrupt needs the radio
+fn draw_lights(
+    my_lights: &mut MyLights,
+    shared_spi_resources: &mut impl rtic::Mutex<T = SharedSPIResources>,
+    elapsed_ms: &timers::ElapsedMs,
+    gps_data: &location::GpsData,
+    orientation: &Orientation,
+) {
+    let start = shared_spi_resources.lock(|shared| {
+        my_lights.buffer(
+            elapsed_ms,
+            Some(gps_data),
+            Some(&shared.network.data),
+            orientation,
+        )
+    });
+
+    if let Some(start) = start {
+        my_lights.draw_buffer(elapsed_ms, start);
+    }
+}
+
+/// For errors during init. Interrupts are still off, so count the ms for the lights ourselves. Then start over
+fn show_error_then_reset(
+    my_lights: &mut MyLights,
+    elapsed_ms: &timers::ElapsedMs,
+    pattern: lights::ErrorPattern,
+) -> ! {
+    for _ in 0..INIT_RETRY_MS {
+        my_lights.draw_error(elapsed_ms, pattern);
+
+        // about 1ms at 72MHz. `delay` went to the radio
+        stm32f3_discovery::cortex_m::asm::delay(72_000);
+        elapsed_ms.increment();
+    }
+
+    SCB::sys_reset();
+}
+
+/// The sensors are noisy enough to tell compasses that booted together apart. This is the only randomness we have
+fn sensor_noise(accel: (i16, i16, i16), mag: (i16, i16, i16)) -> u32 {
+    (accel.0 as u32) << 16
+        ^ (accel.1 as u32) << 8
+        ^ accel.2 as u32
+        ^ (mag.0 as u32) << 20
+        ^ (mag.1 as u32) << 10
+        ^ mag.2 as u32
+}
+
+/// Most radio errors are noise on the SPI bus and go away if we try again. If they keep happening, reset the radio.
+/// Returns false if the reset didn't work either
+fn check_radio<R: network::Radio>(
+    network: &mut network::Network<R>,
+    result: Result<(), network::NetworkError<R::Error>>,
+    radio_errors: &mut u8,
+) -> bool
+where
+    R::Error: core::fmt::Debug,
+{
+    let err = match result {
+        Ok(()) => {
+            *radio_errors = 0;
+            return true;
+        }
+        Err(err) => err,
+    };
+
+    hprintln!("Radio error: {:?}", err).unwrap();
+
+    *radio_errors += 1;
+
+    if *radio_errors < MAX_RADIO_ERRORS {
+        // try again on the next loop
+        return true;
+    }
+
+    *radio_errors = 0;
+
+    match network.reset_radio() {
+        Ok(()) => {
+            hprintln!("Radio reset").unwrap();
+            true
+        }
+        Err(err) => {
+            hprintln!("Radio reset failed: {:?}", err).unwrap();
+            false
+        }
+    }
+}
+
 #[alloc_error_handler]
 fn oom(_: Layout) -> ! {
     loop {}